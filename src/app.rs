@@ -2,21 +2,61 @@
 //!
 //! This module contains the core application state for chat sessions,
 //! including message history, tool definitions, and session persistence.
-//! It implements the streaming chat handler for real-time response display.
+//! It implements the streaming chat handler for real-time response display,
+//! and the [`ToolExecutor`] running tool calls for chat mode and `chatto run`.
 
+use std::cell::RefCell;
+use std::io;
 use std::io::Write;
-use std::{fs, io, path::Path};
 
+use chrono::{DateTime, Utc};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::ollama::{post_ollama_chat, OllamaChatRequest, StreamingChatHandler};
+use crate::approval::{ApprovalMode, ApprovalPolicy, ApprovalSubject};
+use crate::backend::ChatBackend;
+use crate::checkpoint::Checkpoint;
+use crate::custom_tools::register_custom_tools;
+use crate::interrupt;
+use crate::mcp::register_servers;
+use crate::ollama::{OllamaChatRequest, StreamingChatHandler, ToolCall};
+use crate::process::ProcessManager;
+use crate::session::{new_session_id, SessionStore};
+use crate::shell::ShellEnvironment;
+use crate::tool_parser::{parse_tool_calls, ToolParsingStrategy};
+use crate::tools::{ToolContext, ToolRegistry};
 use crate::{
     ollama::{OllamaChatMessage, OllamaChatResponse, OllamaChatResponseStreamingState},
     ApplicationConfig,
 };
 
+/// Configuration for automatic context compaction
+///
+/// Loaded from the `compaction` config section. When the estimated context
+/// crosses `threshold` of the model's context window, the conversation is
+/// compacted before the next request is sent.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct CompactionConfig {
+    /// Whether to compact automatically before sending
+    pub auto: bool,
+    /// Fraction of the context window (0.0 - 1.0) that triggers compaction
+    pub threshold: f64,
+    /// Number of most recent turns kept verbatim when compacting
+    pub keep_turns: usize,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self {
+            auto: true,
+            threshold: 0.8,
+            keep_turns: 2,
+        }
+    }
+}
+
 /// Application state for a chat session
 ///
 /// Maintains the conversation history, tool definitions, and session metadata.
@@ -31,6 +71,33 @@ pub struct ApplicationState {
     pub tools: Vec<Value>,
     /// Conversation history
     pub messages: Vec<OllamaChatMessage>,
+    /// When the session was created
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
+    /// When the session was last saved
+    #[serde(default)]
+    pub updated_at: Option<DateTime<Utc>>,
+    /// Id of the session this one was forked from
+    #[serde(default)]
+    pub parent_session: Option<String>,
+    /// Index of the last message shared with the parent session
+    #[serde(default)]
+    pub forked_at: Option<usize>,
+    /// Snapshots of files taken before tool writes, oldest first
+    #[serde(default)]
+    pub checkpoints: Vec<Checkpoint>,
+    /// Number of messages when the session was last saved
+    #[serde(default)]
+    pub message_count: usize,
+    /// Estimated token count when the session was last saved
+    #[serde(default)]
+    pub token_estimate: usize,
+    /// Tool calls the user allowed for the rest of this chat (not persisted)
+    #[serde(skip)]
+    pub session_approvals: Vec<String>,
+    /// Format the responses have to follow (not persisted)
+    #[serde(skip)]
+    pub format: Option<Value>,
 }
 
 /// Converts ApplicationState into an OllamaChatRequest for API submission.
@@ -47,6 +114,7 @@ impl From<ApplicationState> for OllamaChatRequest {
             options: None,
             stream: false,
             think: false,
+            format: value.format,
         }
     }
 }
@@ -125,54 +193,219 @@ impl ApplicationState {
     /// New ApplicationState with empty message history
     pub fn new_from_config(app_config: &ApplicationConfig) -> Self {
         Self {
-            session_id: String::default(),
+            session_id: new_session_id(),
             model: app_config.model.clone(),
             messages: Vec::new(),
             tools: Vec::new(),
+            created_at: Some(Utc::now()),
+            updated_at: None,
+            parent_session: None,
+            forked_at: None,
+            checkpoints: Vec::new(),
+            message_count: 0,
+            token_estimate: 0,
+            session_approvals: Vec::new(),
+            format: None,
         }
     }
 
-    /// Loads a session from disk or creates a new one if it doesn't exist
+    /// Loads a session from the session store or creates a new one if it doesn't exist
     ///
     /// # Arguments
     /// * `session_name` - Name of the session to load
-    /// * `app_config` - Application configuration for new session creation
+    /// * `app_config` - Application configuration for the session store and new session creation
     ///
     /// # Returns
     /// Loaded or newly created ApplicationState
     ///
     /// # Errors
-    /// Returns error if session file exists but cannot be read or parsed
+    /// Returns error if the session name is invalid or the session file exists
+    /// but cannot be read or parsed
     pub fn load_session(
         session_name: &str,
         app_config: &ApplicationConfig,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let filename = format!(".chatto-{}.session.yaml", session_name);
-        if Path::new(&filename).exists() {
-            let content = fs::read_to_string(&filename)?;
-            let app_state: ApplicationState = serde_yaml::from_str(&content)?;
-            println!("Loaded session: {}", session_name);
-            Ok(app_state)
-        } else {
-            println!("Starting new session: {}", session_name);
-            Ok(Self::new_from_config(app_config))
+        match SessionStore::new(&app_config.sessions).load(session_name)? {
+            Some(app_state) => {
+                eprintln!("Loaded session: {}", session_name);
+                Ok(app_state)
+            }
+            None => {
+                eprintln!("Starting new session: {}", session_name);
+                Ok(Self::new_from_config(app_config))
+            }
         }
     }
 
-    /// Saves the current session to disk
+    /// Saves the current session to the session store
+    ///
+    /// Updates the session's timestamps, message count and token estimate
+    /// before writing it.
     ///
     /// # Arguments
-    /// * `session_name` - Name for the session file
+    /// * `session_name` - Name for the session
+    /// * `app_config` - Application configuration for the session store
     ///
     /// # Errors
-    /// Returns error if file cannot be written
-    pub fn save_session(&self, session_name: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let filename = format!(".chatto-{}.session.yaml", session_name);
-        let yaml_content = serde_yaml::to_string(&self)?;
-        fs::write(&filename, yaml_content)?;
+    /// Returns error if the session name is invalid or the file cannot be written
+    pub fn save_session(
+        &mut self,
+        session_name: &str,
+        app_config: &ApplicationConfig,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let now = Utc::now();
+        //Sessions saved before ids were generated get one so they can be forked
+        if self.session_id.is_empty() {
+            self.session_id = new_session_id();
+        }
+        self.created_at.get_or_insert(now);
+        self.updated_at = Some(now);
+        self.refresh_metadata();
+        SessionStore::new(&app_config.sessions).save(session_name, self)?;
         Ok(())
     }
 
+    /// Creates a new session that shares this session's history up to a message
+    ///
+    /// The fork gets a new session id and records this session as its parent.
+    /// Session approvals are not carried over.
+    ///
+    /// # Arguments
+    /// * `at` - Index of the last message to keep, or None to keep all messages
+    ///
+    /// # Returns
+    /// The forked session
+    ///
+    /// # Errors
+    /// Returns error if the index is past the end of the history
+    pub fn fork(&self, at: Option<usize>) -> Result<Self, Box<dyn std::error::Error>> {
+        let at = at.unwrap_or(self.messages.len().saturating_sub(1));
+        if at >= self.messages.len() {
+            return Err(format!(
+                "Message index {} is out of range (0-{})",
+                at,
+                self.messages.len().saturating_sub(1)
+            )
+            .into());
+        }
+        Ok(Self {
+            session_id: new_session_id(),
+            model: self.model.clone(),
+            tools: self.tools.clone(),
+            messages: self.messages[..=at].to_vec(),
+            created_at: Some(Utc::now()),
+            updated_at: None,
+            parent_session: (!self.session_id.is_empty()).then(|| self.session_id.clone()),
+            forked_at: Some(at),
+            checkpoints: self
+                .checkpoints
+                .iter()
+                .filter(|c| c.message_index <= at)
+                .cloned()
+                .collect(),
+            message_count: 0,
+            token_estimate: 0,
+            session_approvals: Vec::new(),
+            format: self.format.clone(),
+        })
+    }
+
+    /// Takes a checkpoint of files a tool call is about to write
+    ///
+    /// The checkpoint is keyed to the last assistant message, which is the
+    /// one whose tool calls are being processed.
+    ///
+    /// # Arguments
+    /// * `tool_name` - Name of the tool about to write
+    /// * `paths` - Paths the tool will write
+    ///
+    /// # Returns
+    /// Id of the new checkpoint
+    ///
+    /// # Errors
+    /// Returns error if an existing file cannot be read
+    pub fn add_checkpoint(&mut self, tool_name: &str, paths: &[String]) -> io::Result<usize> {
+        let message_index = self
+            .messages
+            .iter()
+            .rposition(|m| m.role == "assistant")
+            .unwrap_or_default();
+        let checkpoint = Checkpoint::capture(&self.checkpoints, message_index, tool_name, paths)?;
+        let id = checkpoint.id;
+        self.checkpoints.push(checkpoint);
+        Ok(id)
+    }
+
+    /// Reverts the files changed by the most recent checkpointed tool write
+    ///
+    /// # Returns
+    /// The reverted checkpoint, or None if there are no checkpoints
+    ///
+    /// # Errors
+    /// Returns error if the files cannot be restored, the checkpoint is kept
+    pub fn undo(&mut self) -> Result<Option<Checkpoint>, Box<dyn std::error::Error>> {
+        let Some(checkpoint) = self.checkpoints.last() else {
+            return Ok(None);
+        };
+        checkpoint.restore()?;
+        Ok(self.checkpoints.pop())
+    }
+
+    /// Reverts files to the state they were in before a checkpoint was taken
+    ///
+    /// The checkpoint and every later one are restored newest first and
+    /// removed. With `truncate`, the conversation is also cut back to just
+    /// before the assistant message that requested the checkpointed write.
+    ///
+    /// # Arguments
+    /// * `id` - Id of the checkpoint to restore
+    /// * `truncate` - Whether to truncate the conversation as well
+    ///
+    /// # Returns
+    /// The reverted checkpoints, newest first
+    ///
+    /// # Errors
+    /// Returns error if the checkpoint doesn't exist, a file cannot be
+    /// restored, or the conversation no longer lines up with the checkpoint
+    /// (e.g. after compaction or /trim), in which case only the files are restored
+    pub fn restore_checkpoint(
+        &mut self,
+        id: usize,
+        truncate: bool,
+    ) -> Result<Vec<Checkpoint>, Box<dyn std::error::Error>> {
+        let Some(position) = self.checkpoints.iter().position(|c| c.id == id) else {
+            return Err(format!("Checkpoint #{} not found", id).into());
+        };
+        let mut reverted = Vec::new();
+        while self.checkpoints.len() > position {
+            let checkpoint = self.checkpoints.last().unwrap();
+            checkpoint.restore()?;
+            reverted.push(self.checkpoints.pop().unwrap());
+        }
+        if truncate {
+            let message_index = reverted.last().unwrap().message_index;
+            if self
+                .messages
+                .get(message_index)
+                .is_none_or(|m| m.role != "assistant" || m.tool_calls.is_none())
+            {
+                return Err(format!(
+                    "files restored, but message {} is no longer the tool call that made the change so the conversation was kept",
+                    message_index
+                )
+                .into());
+            }
+            self.messages.truncate(message_index);
+        }
+        Ok(reverted)
+    }
+
+    /// Recomputes the message count and token estimate saved with the session
+    pub fn refresh_metadata(&mut self) {
+        self.message_count = self.messages.len();
+        self.token_estimate = self.get_token_count_estimate();
+    }
+
     /// Trims message history to keep only essential messages
     ///
     /// Keeps system message, last user message, and last assistant message.
@@ -193,26 +426,53 @@ impl ApplicationState {
         self.messages = new_messages;
     }
 
+    /// Checks whether the context has crossed the automatic compaction threshold
+    ///
+    /// # Arguments
+    /// * `config` - Application configuration with the compaction settings
+    ///
+    /// # Returns
+    /// True if automatic compaction is enabled, the estimated token count is
+    /// at or above the threshold of the model's context window and there are
+    /// messages older than the kept turns to summarize
+    pub fn needs_compaction(&self, config: &ApplicationConfig) -> bool {
+        let compaction = &config.compaction;
+        let limit = config.max_context(&self.model) as f64 * compaction.threshold;
+        compaction.auto
+            && self.get_token_count_estimate() as f64 >= limit
+            && self.turn_start(compaction.keep_turns) > 1
+    }
+
     /// Compacts message history by summarizing previous messages
     ///
-    /// Sends all messages (except system) to the model for summarization.
-    /// The summary replaces the message history, keeping context small
-    /// while preserving conversation continuity.
+    /// Sends all messages (except system) before the last `keep_turns` turns
+    /// to the model for summarization. A turn starts at a user message and
+    /// includes the assistant responses and tool results that follow it. The
+    /// summary replaces the summarized messages and the kept turns follow it
+    /// verbatim, keeping context small while preserving conversation continuity.
     ///
     /// # Arguments
     /// * `client` - HTTP client for API requests
     /// * `config` - Application configuration
+    /// * `keep_turns` - Number of most recent turns to keep verbatim
     ///
     /// # Returns
     /// Result indicating success or failure of compaction
     ///
     /// # Errors
-    /// Returns error if summarization request fails
+    /// Returns error if there is nothing to summarize, the summarization
+    /// request fails or the summary is empty
     pub async fn compact(
         &mut self,
         client: &Client,
         config: &ApplicationConfig,
+        keep_turns: usize,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        //Messages from this index on are kept verbatim
+        let keep_from = self.turn_start(keep_turns);
+        if keep_from <= 1 {
+            return Err("Nothing to compact".into());
+        }
         let mut messages: Vec<OllamaChatMessage> = Vec::new();
         messages.push(OllamaChatMessage {
             role: "system".to_string(),
@@ -220,9 +480,11 @@ impl ApplicationState {
             tool_calls: None,
             tool_call_id: None,
             tool_name: None,
+            images: None,
+            model: None,
         });
         let mut content: String = String::new();
-        self.messages.iter().skip(1).for_each(|m| {
+        self.messages[1..keep_from].iter().for_each(|m| {
             if m.role == "tool" {
                 content.push_str(
                     format!(
@@ -259,6 +521,8 @@ impl ApplicationState {
             tool_calls: None,
             tool_call_id: None,
             tool_name: None,
+            images: None,
+            model: None,
         });
         let request: OllamaChatRequest = OllamaChatRequest {
             model: self.model.clone(),
@@ -269,101 +533,206 @@ impl ApplicationState {
                 .and_then(|m| m.options.clone()),
             stream: false,
             think: false,
+            format: None,
         };
-        let (response, _) = post_ollama_chat(
-            client,
-            &config.url,
-            &config.api_key,
-            &request,
-            Option::<&mut ApplicationState>::None,
-        )
-        .await?;
+        let (response, _) = config
+            .backend()
+            .chat(client, &request, Option::<&mut ApplicationState>::None)
+            .await?;
+        if response.is_interrupted() {
+            return Err("Compaction interrupted".into());
+        }
+        let summary = response.message.unwrap_or_default().content;
+        self.replace_with_summary(keep_from, summary)
+    }
+
+    /// Replaces the messages between the system message and `keep_from` with a summary
+    ///
+    /// # Arguments
+    /// * `keep_from` - Index of the first message kept verbatim
+    /// * `summary` - Summary of the replaced messages
+    ///
+    /// # Errors
+    /// Returns error if the summary is empty, in which case the messages are
+    /// left untouched
+    fn replace_with_summary(
+        &mut self,
+        keep_from: usize,
+        summary: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if summary.trim().is_empty() {
+            return Err("The model returned an empty summary, the history was left as is".into());
+        }
+        let kept = self.messages.split_off(keep_from);
         self.messages.truncate(1);
         self.messages.push(OllamaChatMessage {
             role: "user".to_string(),
-            content: response.message.unwrap_or_default().content,
+            content: summary,
             tool_calls: None,
             tool_call_id: None,
             tool_name: None,
+            images: None,
+            model: None,
+        });
+        self.messages.extend(kept);
+        //Keep checkpoints pointing at the same messages, summarized ones point at the summary
+        self.checkpoints.iter_mut().for_each(|c| {
+            c.message_index = c.message_index.saturating_sub(keep_from - 2).max(1);
         });
         Ok(())
     }
 
-    /// Extracts tool calls from conversation using a specialized model
+    /// Finds the index of the message that starts the last `turns` turns
     ///
-    /// Uses 'functiongemma' model to analyze the last user/assistant messages
-    /// and identify potential tool calls based on available tools.
+    /// # Arguments
+    /// * `turns` - Number of turns counted back from the end
+    ///
+    /// # Returns
+    /// Index of the user message starting the earliest of those turns, or the
+    /// length of the history if `turns` is 0. Returns 1 (just after the system
+    /// message) if there are fewer turns than requested.
+    fn turn_start(&self, turns: usize) -> usize {
+        if turns == 0 {
+            return self.messages.len();
+        }
+        self.messages
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter(|(_, m)| m.role == "user")
+            .map(|(i, _)| i)
+            .rev()
+            .nth(turns - 1)
+            .unwrap_or(1)
+    }
+
+    /// Extracts tool calls written into the last response
+    ///
+    /// Used for models without the `tools` capability. The calls found are
+    /// attached to the response so they run like native tool calls.
     ///
     /// # Arguments
     /// * `client` - HTTP client for API requests
     /// * `config` - Application configuration
+    /// * `strategy` - How the calls are extracted
     ///
     /// # Returns
-    /// Result indicating success or failure
+    /// Number of tool calls found
     ///
     /// # Errors
-    /// Returns error if tool extraction request fails
-    pub async fn get_tool_calls(
+    /// Returns error if the last message isn't a response or the helper model
+    /// request fails
+    pub async fn extract_tool_calls(
         &mut self,
         client: &Client,
         config: &ApplicationConfig,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut messages: Vec<OllamaChatMessage> = Vec::new();
-        messages.push(OllamaChatMessage {
-            role: "system".to_string(),
-            content: "Review the given conversation snippet and identify if there are any tool calls you can make based on the user message, or the assistant response. If the assistant gives a code snippet, try to write that to the file. If a shell command is included, try to run that. If the model needs to read contents of a file, do that. If a search is warranted, run grep or find.".to_string(),
-            tool_calls: None,
-            tool_call_id: None,
-            tool_name: None,
-        });
-        let mut content: String = String::new();
-        /*
-        //The last user message
-        if let Some(i) = self.messages.iter().rposition(|m| m.role == "user") {
-            content.push_str(format!("user: {}\n", self.messages.get(i).unwrap().content).as_str());
-        }*/
-        //The last assistant message
-        if let Some(i) = self.messages.iter().rposition(|m| m.role == "assistant") {
-            content.push_str(
-                format!("assistant: {}\n", self.messages.get(i).unwrap().content).as_str(),
-            );
+        strategy: ToolParsingStrategy,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let content = match self.messages.last() {
+            Some(m) if m.role == "assistant" => m.content.clone(),
+            _ => return Err("The last message is not a response from the model".into()),
+        };
+        let calls = match strategy {
+            ToolParsingStrategy::Off => Vec::new(),
+            ToolParsingStrategy::Parser => parse_tool_calls(&content, &self.tools),
+            ToolParsingStrategy::Model => self.get_tool_calls(client, config, &content).await?,
+        };
+        let count = calls.len();
+        if let Some(last) = self.messages.last_mut().filter(|_| count > 0) {
+            last.tool_calls = Some(calls);
         }
-        messages.push(OllamaChatMessage {
-            role: "user".to_string(),
-            content,
-            tool_calls: None,
-            tool_call_id: None,
-            tool_name: None,
-        });
+        Ok(count)
+    }
+
+    /// Asks the configured helper model to make the tool calls in a response
+    ///
+    /// # Arguments
+    /// * `client` - HTTP client for API requests
+    /// * `config` - Application configuration naming the helper model
+    /// * `content` - Text of the response
+    ///
+    /// # Returns
+    /// The tool calls made by the helper model
+    ///
+    /// # Errors
+    /// Returns error if no helper model is configured or the request fails
+    async fn get_tool_calls(
+        &self,
+        client: &Client,
+        config: &ApplicationConfig,
+        content: &str,
+    ) -> Result<Vec<ToolCall>, Box<dyn std::error::Error>> {
+        let model = config
+            .tool_parsing
+            .model
+            .clone()
+            .ok_or("tool_parsing.model must name a helper model for the model strategy")?;
+        let messages = vec![
+            OllamaChatMessage {
+                role: "system".to_string(),
+                content: "Review the given conversation snippet and identify if there are any tool calls you can make based on the user message, or the assistant response. If the assistant gives a code snippet, try to write that to the file. If a shell command is included, try to run that. If the model needs to read contents of a file, do that. If a search is warranted, run grep or find.".to_string(),
+                tool_calls: None,
+                tool_call_id: None,
+                tool_name: None,
+                images: None,
+                model: None,
+            },
+            OllamaChatMessage {
+                role: "user".to_string(),
+                content: format!("assistant: {}\n", content),
+                tool_calls: None,
+                tool_call_id: None,
+                tool_name: None,
+                images: None,
+                model: None,
+            },
+        ];
         let request: OllamaChatRequest = OllamaChatRequest {
-            model: "functiongemma".to_string(),
+            options: config.get_model(&model).and_then(|m| m.options.clone()),
+            model,
             messages,
             tools: Some(self.tools.clone()),
-            options: config
-                .get_model("functiongemma")
-                .and_then(|m| m.options.clone()),
             stream: false,
             think: false,
+            format: None,
         };
-        let (response, _) = post_ollama_chat(
-            client,
-            &config.url,
-            &config.api_key,
-            &request,
-            Option::<&mut ApplicationState>::None,
-        )
-        .await?;
-
-        if let Some(message) = response.message {
-            self.messages.push(OllamaChatMessage {
-                role: "user".to_string(),
-                content: message.content.clone(),
-                tool_calls: message.tool_calls.clone(),
-                tool_call_id: None,
-                tool_name: None,
-            });
+        let (response, _) = config
+            .backend()
+            .chat(client, &request, Option::<&mut ApplicationState>::None)
+            .await?;
+        if response.is_interrupted() {
+            return Err("Tool call extraction interrupted".into());
         }
-        Ok(())
+        Ok(response
+            .message
+            .and_then(|m| m.tool_calls)
+            .unwrap_or_default())
+    }
+
+    /// Creates a chat request for the current conversation
+    ///
+    /// Applies the configured streaming preference and, when the model is
+    /// known, its options and tool/think capabilities.
+    ///
+    /// # Arguments
+    /// * `app_config` - Application configuration
+    ///
+    /// # Returns
+    /// Request ready to be sent to the chat endpoint
+    pub fn create_request(&self, app_config: &ApplicationConfig) -> OllamaChatRequest {
+        let mut request: OllamaChatRequest = self.clone().into();
+        request.stream = app_config.stream;
+        request.think = false;
+        if let Some(model_config) = app_config.get_model(&self.model) {
+            request.think = model_config.capabilities.iter().any(|c| c == "thinking");
+            request.tools = model_config
+                .capabilities
+                .iter()
+                .any(|c| c == "tools")
+                .then_some(self.tools.clone());
+            request.options = model_config.options.clone();
+        }
+        request
     }
 
     /// Adds a user message to the conversation history
@@ -371,12 +740,23 @@ impl ApplicationState {
     /// # Arguments
     /// * `content` - The message content from the user
     pub fn add_user_message(&mut self, content: &str) {
+        self.add_user_message_with_images(content, Vec::new());
+    }
+
+    /// Adds a user message with attached images to the conversation history
+    ///
+    /// # Arguments
+    /// * `content` - The message content from the user
+    /// * `images` - Base64 encoded images for vision models
+    pub fn add_user_message_with_images(&mut self, content: &str, images: Vec<String>) {
         let message = OllamaChatMessage {
             role: "user".to_string(),
             content: content.to_string(),
             tool_calls: None,
             tool_name: None,
             tool_call_id: None,
+            images: (!images.is_empty()).then_some(images),
+            model: None,
         };
         self.messages.push(message);
     }
@@ -406,17 +786,30 @@ impl ApplicationState {
     /// Will convert the response into a chat message and store it in the messages vec
     /// Adds an assistant response to the conversation history
     ///
+    /// The message records the current model as the one that produced it. A
+    /// response interrupted by the user keeps its partial content with an
+    /// interruption note appended, and drops any tool calls so they don't run.
+    ///
     /// # Arguments
     /// * `resp` - The response to add to history
     pub fn add_assistant_response(&mut self, resp: OllamaChatResponse) {
+        let interrupted = resp.is_interrupted();
         if let Some(message) = resp.message {
-            let new_message = OllamaChatMessage {
+            let mut new_message = OllamaChatMessage {
                 role: "assistant".to_string(),
                 content: message.content.clone(),
                 tool_calls: message.tool_calls.clone(),
                 tool_name: None,
                 tool_call_id: None,
+                images: None,
+                model: Some(self.model.clone()),
             };
+            if interrupted {
+                new_message
+                    .content
+                    .push_str("\n\n[Response interrupted by the user]");
+                new_message.tool_calls = None;
+            }
             self.messages.push(new_message);
         }
     }
@@ -434,6 +827,8 @@ impl ApplicationState {
             tool_calls: None,
             tool_name: Some(tool_name.to_string()),
             tool_call_id: Some(tool_call_id.to_string()),
+            images: None,
+            model: None,
         };
         self.messages.push(message);
     }
@@ -445,7 +840,8 @@ impl ApplicationState {
     /// - No assistant response has been received
     /// - Last message is not a tool result
     ///
-    /// Returns false if last message is a tool result (should auto-send to assistant)
+    /// Returns false if last message is a tool result, including results sent
+    /// as user messages to models without tool support (should auto-send to assistant)
     pub fn should_prompt_user(&self) -> bool {
         // If the model hasn't been prompted yet, return true
         // Messages could be system->user->user (compact and rag might put documents/summaries as
@@ -466,7 +862,9 @@ impl ApplicationState {
             return true;
         }
         // If last message is a tool (result) then we can just send those results to the assistant
-        if self.messages.last().unwrap().role == "tool" {
+        // Models without tool support get their results as user messages that keep the call id
+        let last = self.messages.last().unwrap();
+        if last.role == "tool" || last.tool_call_id.is_some() {
             return false;
         }
         // Otherwise prompt user
@@ -498,3 +896,293 @@ impl ApplicationState {
         messages_tokens + tools_tokens
     }
 }
+
+/// Answer to a tool call the approval policy asks about
+pub enum ToolApproval {
+    /// Run the call
+    Approve,
+    /// Run the call, and calls like it for the rest of the chat
+    ApproveForSession,
+    /// Don't run the call, telling the model why
+    Reject(String),
+}
+
+/// Runs the model's tool calls, shared by chat mode and `chatto run`
+///
+/// Holds the tool registry with the built-in, custom and MCP tools, the
+/// approval policy, the shell environment and the background processes.
+/// Processes are killed when the executor is dropped at the end of the chat.
+pub struct ToolExecutor {
+    registry: ToolRegistry,
+    policy: ApprovalPolicy,
+    shell: ShellEnvironment,
+    /// Background processes started by the model
+    pub processes: ProcessManager,
+    /// Images returned by the tool call being executed
+    images: RefCell<Vec<String>>,
+}
+
+impl ToolExecutor {
+    /// Sets up the tools for a chat
+    ///
+    /// # Arguments
+    /// * `app_config` - Application configuration with the approval, custom tool, MCP and shell sections
+    ///
+    /// # Errors
+    /// Returns error if the approval rules, a custom tool or the shell
+    /// environment are invalid
+    pub fn new(app_config: &ApplicationConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let shell = ShellEnvironment::new(&app_config.shell)?;
+        //File tools take paths relative to the current directory, so it has
+        //to be the root the approval rules resolve paths against
+        std::env::set_current_dir(shell.root()).map_err(|e| {
+            format!(
+                "Failed to enter the shell root {}: {}",
+                shell.root().display(),
+                e
+            )
+        })?;
+        let mut registry = ToolRegistry::with_builtin_tools();
+        let mut policy = ApprovalPolicy::new(&app_config.approval, shell.root())?;
+        register_custom_tools(&mut registry, &mut policy, &app_config.tools)?;
+        register_servers(&mut registry, &app_config.mcp_servers, &shell);
+        Ok(Self {
+            registry,
+            policy,
+            shell,
+            processes: ProcessManager::new(),
+            images: RefCell::new(Vec::new()),
+        })
+    }
+
+    /// Definitions of all tools, for the chat request
+    pub fn definitions(&self) -> Vec<Value> {
+        self.registry.definitions()
+    }
+
+    /// Executes or rejects tool calls and adds their results to the conversation
+    ///
+    /// Each call is previewed and checked against the approval policy, `ask`
+    /// decides the calls the policy leaves to the user. Files are checkpointed
+    /// before an approved call writes them. Once Ctrl-C is pressed the
+    /// remaining calls are cancelled. Models without tool support get the
+    /// results as user messages.
+    ///
+    /// # Arguments
+    /// * `app_config` - Application configuration with the output limit and model capabilities
+    /// * `app_state` - Session state holding the session approvals, checkpoints and messages
+    /// * `tool_calls` - The tool calls to process
+    /// * `ask` - Decides a call the policy asks about
+    pub fn execute(
+        &self,
+        app_config: &ApplicationConfig,
+        app_state: &mut ApplicationState,
+        tool_calls: &[ToolCall],
+        mut ask: impl FnMut(&ToolCall) -> ToolApproval,
+    ) {
+        let context = ToolContext {
+            output_limit: &app_config.output_limit,
+            shell: &self.shell,
+            processes: &self.processes,
+            vision: app_config.has_capability(&app_state.model, "vision"),
+            images: &self.images,
+        };
+        let mut messages = Vec::new();
+        for tc in tool_calls {
+            //Once interrupted, the remaining calls are cancelled but still need a result
+            if interrupt::is_interrupted() {
+                messages.push(create_tool_message(
+                    tc,
+                    "TOOL CALL CANCELLED. The user interrupted the tool calls".to_string(),
+                ));
+                continue;
+            }
+            let content = self.execute_one(&context, app_state, tc, &mut ask);
+            let mut message = create_tool_message(tc, content);
+            message.images = context.take_images();
+            messages.push(message);
+        }
+        //If the model is not tool capable then turn these into user messages
+        if !app_config.has_capability(&app_state.model, "tools") {
+            eprintln!("Modifying role to user for tools (Model doesn't understand tools)");
+            messages
+                .iter_mut()
+                .for_each(|m| m.role = "user".to_string());
+        }
+        app_state.messages.extend(messages);
+    }
+
+    /// Executes or rejects a single tool call
+    ///
+    /// # Returns
+    /// The tool result, or why the call was rejected
+    fn execute_one(
+        &self,
+        context: &ToolContext,
+        app_state: &mut ApplicationState,
+        tc: &ToolCall,
+        ask: &mut impl FnMut(&ToolCall) -> ToolApproval,
+    ) -> String {
+        let name = &tc.function.name;
+        let Some(tool) = self.registry.get(name) else {
+            eprintln!("Unknown Tool Call {} Requested!", name);
+            return format!("Unknown tool: {}", name);
+        };
+        //Malformed arguments go straight back to the model as the tool result
+        let subject = match tool
+            .preview(&tc.function.arguments)
+            .and_then(|_| tool.approval_subject(&tc.function.arguments))
+        {
+            Ok(subject) => subject,
+            Err(e) => {
+                eprintln!("❌ {}", e);
+                return e;
+            }
+        };
+        let approval = match self
+            .policy
+            .evaluate(name, &subject, &app_state.session_approvals)
+        {
+            ApprovalMode::Always => {
+                eprintln!(" ✓ Approved by policy");
+                ToolApproval::Approve
+            }
+            ApprovalMode::Never => {
+                eprintln!(" ⛔ Denied by policy");
+                ToolApproval::Reject("denied by approval policy".to_string())
+            }
+            ApprovalMode::Ask => ask(tc),
+        };
+        match approval {
+            ToolApproval::Approve => {}
+            ToolApproval::ApproveForSession => {
+                app_state
+                    .session_approvals
+                    .push(self.policy.session_key(name, &subject));
+            }
+            ToolApproval::Reject(reason) => {
+                return format!("TOOL CALL REJECTED. Feedback/Reason: {}", reason);
+            }
+        }
+        //Snapshot the files before they are written so the change can be undone,
+        //a write that couldn't be undone isn't made
+        if let ApprovalSubject::WritePaths(paths) = &subject {
+            match app_state.add_checkpoint(name, paths) {
+                Ok(id) => eprintln!(" 📌 Checkpoint #{} (/undo to revert)", id),
+                Err(e) => {
+                    eprintln!(" ⛔ No checkpoint taken: {}", e);
+                    return format!(
+                        "Error: the files could not be checkpointed, so nothing was written: {}",
+                        e
+                    );
+                }
+            }
+        }
+        tool.execute(&tc.function.arguments, context)
+            .unwrap_or_else(|e| e)
+    }
+}
+
+/// Creates a tool result message for the given tool call
+///
+/// # Arguments
+/// * `tc` - The tool call this result is for
+/// * `content` - The result content
+///
+/// # Returns
+/// Tool message ready to add to conversation history
+fn create_tool_message(tc: &ToolCall, content: String) -> OllamaChatMessage {
+    OllamaChatMessage {
+        role: "tool".to_string(),
+        content,
+        tool_calls: None,
+        tool_call_id: tc.id.clone(),
+        tool_name: Some(tc.function.name.clone()),
+        images: None,
+        model: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A session with one message per role, content numbered by index
+    fn state(roles: &[&str]) -> ApplicationState {
+        let messages: Vec<Value> = roles
+            .iter()
+            .enumerate()
+            .map(|(i, role)| serde_json::json!({"role": role, "content": i.to_string()}))
+            .collect();
+        serde_json::from_value(serde_json::json!({
+            "session_id": "test",
+            "model": "mock",
+            "tools": [],
+            "messages": messages,
+        }))
+        .unwrap()
+    }
+
+    fn contents(state: &ApplicationState) -> Vec<&str> {
+        state.messages.iter().map(|m| m.content.as_str()).collect()
+    }
+
+    #[test]
+    fn finds_turn_starts() {
+        let state = state(&[
+            "system",
+            "user",
+            "assistant",
+            "tool",
+            "assistant",
+            "user",
+            "assistant",
+            "user",
+        ]);
+        assert_eq!(state.turn_start(0), 8);
+        assert_eq!(state.turn_start(1), 7);
+        assert_eq!(state.turn_start(2), 5);
+        assert_eq!(state.turn_start(3), 1);
+        //Fewer turns than asked for keeps everything after the system message
+        assert_eq!(state.turn_start(10), 1);
+    }
+
+    #[test]
+    fn summary_replaces_turns_before_the_kept_ones() {
+        let mut state = state(&[
+            "system",
+            "user",
+            "assistant",
+            "tool",
+            "assistant",
+            "user",
+            "assistant",
+        ]);
+        let checkpoint = |message_index| Checkpoint {
+            id: message_index,
+            message_index,
+            tool_name: "write_file".into(),
+            created_at: Utc::now(),
+            files: Vec::new(),
+        };
+        state.checkpoints = vec![checkpoint(2), checkpoint(6)];
+        let keep_from = state.turn_start(1);
+        state
+            .replace_with_summary(keep_from, "summary".into())
+            .unwrap();
+        assert_eq!(contents(&state), ["0", "summary", "5", "6"]);
+        assert_eq!(state.messages[1].role, "user");
+        //Checkpoints follow their messages, summarized ones point at the summary
+        let indexes: Vec<usize> = state.checkpoints.iter().map(|c| c.message_index).collect();
+        assert_eq!(indexes, [1, 3]);
+    }
+
+    #[test]
+    fn empty_summaries_leave_the_history_alone() {
+        let mut state = state(&["system", "user", "assistant", "user", "assistant"]);
+        for summary in ["", " \n"] {
+            assert!(state.replace_with_summary(3, summary.into()).is_err());
+            assert_eq!(contents(&state), ["0", "1", "2", "3", "4"]);
+        }
+    }
+}
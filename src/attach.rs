@@ -0,0 +1,362 @@
+//! Files attached to chat messages.
+//!
+//! In chat mode `/attach <path>` queues a file for the next user message and
+//! `@path` attaches a file mentioned in the message itself. Text files are
+//! embedded into the message as fenced blocks. Images are sent base64 encoded
+//! in the message's `images`, which models with the `vision` capability can
+//! see. The `read_file` tool hands images to vision models the same way.
+
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::Path,
+    sync::LazyLock,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use regex::Regex;
+
+/// Largest file that can be attached
+const MAX_ATTACHMENT_SIZE: u64 = 20 * 1024 * 1024;
+
+/// Bytes needed to detect every supported image type
+const HEADER_LEN: usize = 18;
+
+/// Sizes of the known BMP info headers, from the OS/2 `BITMAPCOREHEADER`
+/// to `BITMAPV5HEADER`
+const BMP_INFO_HEADER_SIZES: [u32; 7] = [12, 40, 52, 56, 64, 108, 124];
+
+/// Matches `@path` mentions at the start of a word
+static MENTION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(^|\s)@(\S+)").unwrap());
+
+/// A file attached to a user message
+#[derive(Debug, Clone)]
+pub enum Attachment {
+    /// A text file, embedded into the message content
+    Text { path: String, content: String },
+    /// An image, sent base64 encoded with the message
+    Image { path: String, data: String },
+}
+
+impl Attachment {
+    /// Reads a file to attach
+    ///
+    /// # Arguments
+    /// * `path` - Path of the file
+    ///
+    /// # Returns
+    /// An image attachment for PNG, JPEG, GIF, WebP and BMP files, otherwise
+    /// a text attachment
+    ///
+    /// # Errors
+    /// Returns an error message if the file can't be read, is too large or
+    /// is neither text nor a supported image
+    pub fn load(path: &str) -> Result<Self, String> {
+        if image_mime(Path::new(path)).is_some() {
+            return Ok(Attachment::Image {
+                path: path.to_string(),
+                data: load_image(path)?,
+            });
+        }
+        let bytes = read_limited(path)?;
+        let content = String::from_utf8(bytes)
+            .map_err(|_| format!("Error: {} is neither a text file nor an image", path))?;
+        Ok(Attachment::Text {
+            path: path.to_string(),
+            content,
+        })
+    }
+
+    /// Path the file was attached from
+    pub fn path(&self) -> &str {
+        match self {
+            Attachment::Text { path, .. } | Attachment::Image { path, .. } => path,
+        }
+    }
+
+    /// Whether the attachment is an image
+    pub fn is_image(&self) -> bool {
+        matches!(self, Attachment::Image { .. })
+    }
+}
+
+/// Reads a file, refusing files over the attachment size limit
+fn read_limited(path: &str) -> Result<Vec<u8>, String> {
+    let size = fs::metadata(path)
+        .map_err(|e| format!("Error reading {}: {}", path, e))?
+        .len();
+    if size > MAX_ATTACHMENT_SIZE {
+        return Err(format!(
+            "Error: {} is {} bytes, the limit is {} bytes",
+            path, size, MAX_ATTACHMENT_SIZE
+        ));
+    }
+    fs::read(path).map_err(|e| format!("Error reading {}: {}", path, e))
+}
+
+/// Reads an image and encodes it for the `images` of a message
+///
+/// # Arguments
+/// * `path` - Path of the image
+///
+/// # Returns
+/// The base64 encoded image
+///
+/// # Errors
+/// Returns an error message if the file can't be read or is too large
+pub fn load_image(path: &str) -> Result<String, String> {
+    read_limited(path).map(|bytes| STANDARD.encode(bytes))
+}
+
+/// Detects an image by the first bytes of the file
+///
+/// # Returns
+/// The image's MIME type, or None if the file isn't a supported image or
+/// can't be read
+pub fn image_mime(path: &Path) -> Option<&'static str> {
+    let mut file = File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    let mut header = [0; HEADER_LEN];
+    let read = file.read(&mut header).ok()?;
+    mime_of(&header[..read], len)
+}
+
+/// Detects the MIME type of an image from its first bytes
+///
+/// BMP files only start with `BM`, which plenty of text files do too, so
+/// their header also has to state the file's size and a known info header
+/// size.
+///
+/// # Arguments
+/// * `bytes` - Start of the image data
+/// * `len` - Size of the whole image in bytes
+pub fn mime_of(bytes: &[u8], len: u64) -> Option<&'static str> {
+    match bytes {
+        [0x89, b'P', b'N', b'G', ..] => Some("image/png"),
+        [0xFF, 0xD8, 0xFF, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        [b'B', b'M', s0, s1, s2, s3, _, _, _, _, _, _, _, _, h0, h1, h2, h3, ..]
+            if u64::from(u32::from_le_bytes([*s0, *s1, *s2, *s3])) == len
+                && BMP_INFO_HEADER_SIZES.contains(&u32::from_le_bytes([*h0, *h1, *h2, *h3])) =>
+        {
+            Some("image/bmp")
+        }
+        _ => None,
+    }
+}
+
+/// Detects the MIME type of a base64 encoded image
+///
+/// # Arguments
+/// * `data` - The base64 encoded image
+pub fn base64_mime(data: &str) -> Option<&'static str> {
+    //Every 4 characters encode 3 bytes, less the padding at the end
+    let prefix = &data[..data.len().min(HEADER_LEN.div_ceil(3) * 4)];
+    let padding = data.bytes().rev().take_while(|&b| b == b'=').count();
+    let len = (data.len() / 4 * 3).saturating_sub(padding) as u64;
+    STANDARD
+        .decode(prefix)
+        .ok()
+        .and_then(|bytes| mime_of(&bytes, len))
+}
+
+/// Attaches the files mentioned as `@path` in a message
+///
+/// Mentions of paths that aren't files are left alone, so `@someone` stays
+/// as written. Attached mentions lose their `@`, and trailing punctuation is
+/// ignored when the path doesn't exist with it.
+///
+/// # Arguments
+/// * `input` - The message as typed
+///
+/// # Returns
+/// The message with the mentions replaced by their paths, and the attachments
+pub fn extract_mentions(input: &str) -> (String, Vec<Attachment>) {
+    let mut attachments: Vec<Attachment> = Vec::new();
+    let text = MENTION
+        .replace_all(input, |caps: &regex::Captures| {
+            let mention = &caps[2];
+            let path = [
+                mention,
+                mention.trim_end_matches([',', '.', ';', ':', '!', '?', ')']),
+            ]
+            .into_iter()
+            .find(|p| Path::new(p).is_file());
+            let Some(path) = path else {
+                return caps[0].to_string();
+            };
+            match Attachment::load(path) {
+                Ok(attachment) => {
+                    attachments.push(attachment);
+                    format!("{}{}", &caps[1], mention)
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    caps[0].to_string()
+                }
+            }
+        })
+        .into_owned();
+    (text, attachments)
+}
+
+/// Adds attachments to the content of a user message
+///
+/// # Arguments
+/// * `content` - The message content
+/// * `attachments` - Files to attach
+///
+/// # Returns
+/// The content with the text files appended as fenced blocks, and the
+/// base64 encoded images
+pub fn apply_attachments(content: &str, attachments: Vec<Attachment>) -> (String, Vec<String>) {
+    let mut content = content.to_string();
+    let mut images: Vec<String> = Vec::new();
+    for attachment in attachments {
+        match attachment {
+            Attachment::Text {
+                path,
+                content: text,
+            } => {
+                //The fence has to be longer than any backtick run in the file
+                let longest = text
+                    .split(|c| c != '`')
+                    .map(str::len)
+                    .max()
+                    .unwrap_or_default();
+                let fence = "`".repeat((longest + 1).max(3));
+                content.push_str(&format!(
+                    "\n\nFile `{}`:\n{}\n{}\n{}",
+                    path,
+                    fence,
+                    text.trim_end_matches('\n'),
+                    fence
+                ));
+            }
+            Attachment::Image { path, data } => {
+                content.push_str(&format!("\n\nImage `{}` is attached.", path));
+                images.push(data);
+            }
+        }
+    }
+    (content.trim_start().to_string(), images)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A BMP header stating `len` bytes and an info header of `info` bytes
+    fn bmp(len: u32, info: u32) -> Vec<u8> {
+        let mut bytes = b"BM".to_vec();
+        bytes.extend(len.to_le_bytes());
+        bytes.extend([0; 8]);
+        bytes.extend(info.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn detects_images_by_their_header() {
+        assert_eq!(mime_of(b"\x89PNG\r\n\x1a\n", 100), Some("image/png"));
+        assert_eq!(mime_of(b"\xff\xd8\xff\xe0", 100), Some("image/jpeg"));
+        assert_eq!(mime_of(b"GIF89a", 100), Some("image/gif"));
+        assert_eq!(mime_of(b"RIFF\0\0\0\0WEBPVP8 ", 100), Some("image/webp"));
+        assert_eq!(mime_of(b"RIFF\0\0\0\0WAVEfmt ", 100), None);
+        assert_eq!(mime_of(b"hello", 5), None);
+        assert_eq!(mime_of(b"", 0), None);
+    }
+
+    #[test]
+    fn checks_bmp_headers() {
+        assert_eq!(mime_of(&bmp(1000, 40), 1000), Some("image/bmp"));
+        assert_eq!(mime_of(&bmp(1000, 124), 1000), Some("image/bmp"));
+        //The stated size has to be the file's size
+        assert_eq!(mime_of(&bmp(1000, 40), 999), None);
+        assert_eq!(mime_of(&bmp(1000, 41), 1000), None);
+        //Text starting with BM isn't an image
+        let text = b"BMW owners manual, chapter 1\n";
+        assert_eq!(mime_of(text, text.len() as u64), None);
+        assert_eq!(mime_of(b"BM", 2), None);
+    }
+
+    #[test]
+    fn detects_base64_images() {
+        let mut image = bmp(30, 40);
+        image.resize(30, 0);
+        assert_eq!(base64_mime(&STANDARD.encode(&image)), Some("image/bmp"));
+        image.push(0);
+        assert_eq!(base64_mime(&STANDARD.encode(&image)), None);
+        let png = STANDARD.encode(b"\x89PNG\r\n\x1a\n");
+        assert_eq!(base64_mime(&png), Some("image/png"));
+        assert_eq!(base64_mime("not base64!"), None);
+    }
+
+    #[test]
+    fn attaches_mentioned_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = dir.path().join("notes.md");
+        fs::write(&text, "# Notes\n").unwrap();
+        let text = text.to_str().unwrap();
+        let image = dir.path().join("pic.png");
+        fs::write(&image, b"\x89PNG\r\n\x1a\n").unwrap();
+        let image = image.to_str().unwrap();
+
+        let input = format!("see @{}, and @{} @someone e@mail", text, image);
+        let (message, attachments) = extract_mentions(&input);
+        //Attached mentions lose their @, others are left alone
+        assert_eq!(
+            message,
+            format!("see {}, and {} @someone e@mail", text, image)
+        );
+        assert_eq!(attachments.len(), 2);
+        assert_eq!(attachments[0].path(), text);
+        assert!(!attachments[0].is_image());
+        assert_eq!(attachments[1].path(), image);
+        assert!(attachments[1].is_image());
+    }
+
+    #[test]
+    fn leaves_unreadable_mentions_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("data.bin");
+        fs::write(&binary, [0xff, 0xfe, 0x00]).unwrap();
+        let input = format!("@{} @{}", binary.display(), dir.path().display());
+        let (message, attachments) = extract_mentions(&input);
+        assert_eq!(message, input);
+        assert!(attachments.is_empty());
+    }
+
+    #[test]
+    fn appends_attachments_to_the_content() {
+        let attachments = vec![
+            Attachment::Text {
+                path: "a.md".into(),
+                content: "one\n```rust\nx\n```\n".into(),
+            },
+            Attachment::Image {
+                path: "pic.png".into(),
+                data: "aGk=".into(),
+            },
+            Attachment::Text {
+                path: "b.txt".into(),
+                content: "two".into(),
+            },
+        ];
+        let (content, images) = apply_attachments("look", attachments);
+        assert_eq!(
+            content,
+            "look\n\nFile `a.md`:\n````\none\n```rust\nx\n```\n````\
+             \n\nImage `pic.png` is attached.\
+             \n\nFile `b.txt`:\n```\ntwo\n```"
+        );
+        assert_eq!(images, vec!["aGk=".to_string()]);
+        //Attachments without a message don't start with blank lines
+        let text = Attachment::Text {
+            path: "b.txt".into(),
+            content: "two".into(),
+        };
+        let (content, _) = apply_attachments("", vec![text]);
+        assert_eq!(content, "File `b.txt`:\n```\ntwo\n```");
+    }
+}
@@ -0,0 +1,269 @@
+//! Chat backend abstraction.
+//!
+//! Chatto keeps its conversation state in Ollama's message format. A
+//! `ChatBackend` sends that state to a particular kind of server and
+//! translates the reply back, so sessions and tools work unchanged across
+//! backends.
+//!
+//! ## Backends
+//!
+//! - **ollama**: Ollama's native `/api/chat` API (default)
+//! - **openai**: OpenAI-compatible `/v1/chat/completions` APIs such as
+//!   llama.cpp server, vLLM or LM Studio
+//!
+//! The backend is selected with the `backend` config key alongside `url`,
+//! or the `--backend` command-line argument. Both backends retry transient
+//! failures as configured in the `retry` section.
+
+use reqwest::Client;
+use serde::Deserialize;
+use strum::{Display, EnumString};
+
+use crate::error::{ChatError, RetryConfig};
+use crate::ollama::{
+    list_models, post_ollama_chat, show_model, OllamaChatRequest, OllamaChatResponse,
+    OllamaChatResponseStreamingState, OllamaModel, StreamingChatHandler,
+};
+use crate::openai::{list_openai_models, openai_model, post_openai_chat};
+
+/// Kind of API spoken by the configured endpoint
+#[derive(Debug, Deserialize, Clone, Copy, Default, Display, EnumString, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum BackendKind {
+    /// Ollama's native API
+    #[default]
+    Ollama,
+    /// OpenAI-compatible chat completions API
+    OpenAi,
+}
+
+/// A server that chat requests can be sent to
+//The futures are only awaited on chatto's own runtime, so no Send bound is needed
+#[allow(async_fn_in_trait)]
+pub trait ChatBackend {
+    /// Sends a chat request and handles the (optionally streamed) response
+    ///
+    /// # Arguments
+    /// * `client` - The HTTP client to use for the request
+    /// * `request` - The chat request to send
+    /// * `streaming_chat_handler` - Optional handler for processing streaming response chunks
+    ///
+    /// # Returns
+    /// A tuple containing the complete response and the final streaming state
+    ///
+    /// # Errors
+    /// Returns an error if the request fails (after retrying transient
+    /// failures) or the response can't be parsed
+    async fn chat(
+        &self,
+        client: &Client,
+        request: &OllamaChatRequest,
+        streaming_chat_handler: Option<impl StreamingChatHandler>,
+    ) -> Result<(OllamaChatResponse, OllamaChatResponseStreamingState), ChatError>;
+
+    /// Lists the models available on the server
+    ///
+    /// # Errors
+    /// Returns an error if the request fails or the response can't be parsed
+    async fn list_models(&self, client: &Client) -> Result<Vec<OllamaModel>, ChatError>;
+
+    /// Retrieves information and capabilities for a model
+    ///
+    /// # Errors
+    /// Returns an error if the request fails or the model doesn't exist
+    async fn show_model(&self, client: &Client, model: &str) -> Result<OllamaModel, ChatError>;
+
+    /// Looks up the digest identifying the current version of a model
+    ///
+    /// # Returns
+    /// The digest, None if the server doesn't report one for the model
+    ///
+    /// # Errors
+    /// Returns an error if the request fails
+    async fn model_digest(&self, client: &Client, model: &str)
+        -> Result<Option<String>, ChatError>;
+}
+
+/// Backend for Ollama's native API
+pub struct OllamaBackend {
+    /// Base URL of the Ollama instance
+    pub url: String,
+    /// API key for authentication
+    pub api_key: String,
+    /// Retry policy for transient failures
+    pub retry: RetryConfig,
+}
+
+impl ChatBackend for OllamaBackend {
+    async fn chat(
+        &self,
+        client: &Client,
+        request: &OllamaChatRequest,
+        mut streaming_chat_handler: Option<impl StreamingChatHandler>,
+    ) -> Result<(OllamaChatResponse, OllamaChatResponseStreamingState), ChatError> {
+        //Transient failures happen before anything is streamed, so nothing is shown twice
+        self.retry
+            .run(async || {
+                post_ollama_chat(
+                    client,
+                    &self.url,
+                    &self.api_key,
+                    request,
+                    streaming_chat_handler.as_mut(),
+                )
+                .await
+            })
+            .await
+    }
+
+    async fn list_models(&self, client: &Client) -> Result<Vec<OllamaModel>, ChatError> {
+        self.retry
+            .run(|| list_models(client, &self.url, &self.api_key))
+            .await
+    }
+
+    async fn show_model(&self, client: &Client, model: &str) -> Result<OllamaModel, ChatError> {
+        self.retry
+            .run(|| show_model(client, &self.url, &self.api_key, model))
+            .await
+    }
+
+    async fn model_digest(
+        &self,
+        client: &Client,
+        model: &str,
+    ) -> Result<Option<String>, ChatError> {
+        //Only the model list has digests, where names carry a tag
+        let latest = format!("{}:latest", model);
+        let models = self.list_models(client).await?;
+        Ok(models
+            .into_iter()
+            .find(|m| m.name == model || m.name == latest)
+            .map(|m| m.digest)
+            .filter(|d| !d.is_empty()))
+    }
+}
+
+/// Backend for OpenAI-compatible chat completions APIs
+pub struct OpenAiBackend {
+    /// Base URL of the server (with or without `/v1`)
+    pub url: String,
+    /// API key for authentication
+    pub api_key: String,
+    /// Retry policy for transient failures
+    pub retry: RetryConfig,
+}
+
+impl ChatBackend for OpenAiBackend {
+    async fn chat(
+        &self,
+        client: &Client,
+        request: &OllamaChatRequest,
+        mut streaming_chat_handler: Option<impl StreamingChatHandler>,
+    ) -> Result<(OllamaChatResponse, OllamaChatResponseStreamingState), ChatError> {
+        self.retry
+            .run(async || {
+                post_openai_chat(
+                    client,
+                    &self.url,
+                    &self.api_key,
+                    request,
+                    streaming_chat_handler.as_mut(),
+                )
+                .await
+            })
+            .await
+    }
+
+    async fn list_models(&self, client: &Client) -> Result<Vec<OllamaModel>, ChatError> {
+        self.retry
+            .run(|| list_openai_models(client, &self.url, &self.api_key))
+            .await
+    }
+
+    async fn show_model(&self, _client: &Client, model: &str) -> Result<OllamaModel, ChatError> {
+        Ok(openai_model(model))
+    }
+
+    async fn model_digest(
+        &self,
+        _client: &Client,
+        _model: &str,
+    ) -> Result<Option<String>, ChatError> {
+        //The model information isn't fetched from the server, so there is nothing to invalidate
+        Ok(None)
+    }
+}
+
+/// The configured backend
+///
+/// Dispatches to the backend selected by `BackendKind`.
+pub enum Backend {
+    Ollama(OllamaBackend),
+    OpenAi(OpenAiBackend),
+}
+
+impl Backend {
+    /// Creates the backend for an endpoint
+    ///
+    /// # Arguments
+    /// * `kind` - Kind of API spoken by the endpoint
+    /// * `url` - Base URL of the endpoint
+    /// * `api_key` - API key for authentication
+    /// * `retry` - Retry policy for transient failures
+    pub fn new(kind: BackendKind, url: &str, api_key: &str, retry: &RetryConfig) -> Self {
+        let (url, api_key, retry) = (url.to_string(), api_key.to_string(), retry.clone());
+        match kind {
+            BackendKind::Ollama => Backend::Ollama(OllamaBackend {
+                url,
+                api_key,
+                retry,
+            }),
+            BackendKind::OpenAi => Backend::OpenAi(OpenAiBackend {
+                url,
+                api_key,
+                retry,
+            }),
+        }
+    }
+}
+
+impl ChatBackend for Backend {
+    async fn chat(
+        &self,
+        client: &Client,
+        request: &OllamaChatRequest,
+        streaming_chat_handler: Option<impl StreamingChatHandler>,
+    ) -> Result<(OllamaChatResponse, OllamaChatResponseStreamingState), ChatError> {
+        match self {
+            Backend::Ollama(b) => b.chat(client, request, streaming_chat_handler).await,
+            Backend::OpenAi(b) => b.chat(client, request, streaming_chat_handler).await,
+        }
+    }
+
+    async fn list_models(&self, client: &Client) -> Result<Vec<OllamaModel>, ChatError> {
+        match self {
+            Backend::Ollama(b) => b.list_models(client).await,
+            Backend::OpenAi(b) => b.list_models(client).await,
+        }
+    }
+
+    async fn show_model(&self, client: &Client, model: &str) -> Result<OllamaModel, ChatError> {
+        match self {
+            Backend::Ollama(b) => b.show_model(client, model).await,
+            Backend::OpenAi(b) => b.show_model(client, model).await,
+        }
+    }
+
+    async fn model_digest(
+        &self,
+        client: &Client,
+        model: &str,
+    ) -> Result<Option<String>, ChatError> {
+        match self {
+            Backend::Ollama(b) => b.model_digest(client, model).await,
+            Backend::OpenAi(b) => b.model_digest(client, model).await,
+        }
+    }
+}
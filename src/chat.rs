@@ -14,115 +14,114 @@
 //! - `/save <name>` - Save the session
 //! - `/edit`, `/editor` - Open editor for message composition
 //! - `/tools` - Extract tool calls from conversation
+//! - `/model [name]` - Show the model or switch the conversation to another one
+//! - `/format [schema.json|json|off]` - Require JSON responses matching a schema, validated and retried
+//! - `/attach [path]` - Attach a file to the next message (or list attachments), `@path` attaches inline
 //! - `/reset` - Clear message history (keep system message)
 //! - `/trim` - Trim message history to essentials
-//! - `/compact` - Summarize message history
-//! - `/send` - Force send without user message
+//! - `/compact [turns]` - Summarize message history, keeping the last turns
+//! - `/send` - Force send without user message (also resends after a failed request)
+//! - `/fork <name> [at <index>]` - Continue in a new session sharing history up to a message
+//! - `/rewind <index>` - Continue from a message, keeping the original transcript
+//! - `/checkpoints` - List file checkpoints taken before tool writes
+//! - `/undo` - Revert the files changed by the last tool write
+//! - `/restore <id> [truncate]` - Revert files to a checkpoint, optionally truncating the conversation
+//! - `/ps` - List background processes started by the model
+//! - `/kill <id>|all` - Stop background processes
+//!
+//! ## Interrupting
+//!
+//! Ctrl-C stops a streaming response or a running shell command and returns
+//! to the prompt. Pressing it twice at the prompt saves the session and exits.
 
 use std::{env, fs, io::Write, process::Command};
 
 use reqwest::Client;
-use rustyline::DefaultEditor;
+use rustyline::{error::ReadlineError, DefaultEditor};
+use serde_json::Value;
 use tempfile::NamedTempFile;
 
 use crate::{
-    app::ApplicationState,
-    ollama::{
-        post_ollama_chat, OllamaChatMessage, OllamaChatRequest, OllamaChatResponseStreamingState,
-        ToolCall,
-    },
-    tools::{
-        create_read_file_tool, create_shell_tool, create_write_file_tool, execute_command,
-        read_file_lines, show_write_diff, write_file_content,
-    },
+    app::{ApplicationState, ToolApproval, ToolExecutor},
+    attach::{apply_attachments, extract_mentions, Attachment},
+    backend::ChatBackend,
+    error::ChatError,
+    interrupt,
+    ollama::{OllamaChatMessage, OllamaChatResponseStreamingState, ToolCall},
+    output_format::{retry_prompt, OutputFormat},
+    session::SessionStore,
+    tool_parser::{text_tools_prompt, ToolParsingStrategy},
     ApplicationConfig,
 };
 
-/// Starts an interactive chat session with an Ollama model
-///
-/// Implements a REPL (Read-Eval-Print Loop) for conversing with the model.
-/// Handles message history, tool execution, session management, and various
-/// slash commands for user interaction.
-///
-/// # Arguments
-/// * `client` - HTTP client for API requests
-/// * `app_config` - Application configuration (URL, model, etc.)
-/// * `session` - Optional session name to load/save
-///
-/// # Returns
-/// Result indicating success or failure
-///
-/// # Errors
-/// Returns error if API requests fail or critical errors occur
-///
-/// # Slash Commands
-/// - `/quit`, `/exit`, `/done` - Exit and optionally save session
-/// - `/save <name>` - Save session with name
-/// - `/edit`, `/editor` - Open external editor for message composition
-/// - `/tools` - Extract tool calls from last messages
-/// - `/reset` - Clear history (keep system message)
-/// - `/trim` - Trim to essential messages
-/// - `/compact` - Summarize history
-/// - `/send` - Force send without user message
-pub async fn chat_mode(
-    client: &Client,
-    app_config: ApplicationConfig,
-    mut session: Option<String>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut app_state = if let Some(ref session_name) = session {
-        ApplicationState::load_session(session_name, &app_config)?
-    } else {
-        ApplicationState::new_from_config(&app_config)
-    };
-    app_state.tools = vec![
-        create_shell_tool(),
-        create_read_file_tool(),
-        create_write_file_tool(),
-    ];
-
-    static DEFAULT_SYS_TOOLS_PROMPT: &str = r#"You are an AI assistant with access to specialized file tools and shell commands. ALWAYS prefer the dedicated file tools over shell commands for reading and writing files. If you don't have tool calling functionality a tool calling agent will be provided. Detail the tool call, the reason you need that tool call, as well as all parameters by name and the values you want. Put it in a newline delimeted block with a TOOL CALL header. The agent will turn your message into a set of tool calls and return the results to you.
+/// System prompt describing the built-in tools, added for tool capable models
+static DEFAULT_SYS_TOOLS_PROMPT: &str = r#"You are an AI assistant with access to specialized file tools and shell commands. ALWAYS prefer the dedicated file tools over shell commands for reading and writing files.
 
 **PREFERRED FILE TOOLS:**
 - `read_file`: Read file contents or specific line ranges. Use this instead of `cat`, `head`, `tail`, or `less`.
-- `write_file`: Write, append, insert, or replace file content. Use this instead of `echo >`, `sed`, `awk`, or text editors.
+- `search_files`: Search file contents with a regular expression, with optional context lines and glob or file type filters. Use this instead of `grep -r` or `rg`.
+- `find_files`: Find files by glob pattern, most recently modified first. Use this instead of `find`.
+- `list_directory`: Show a directory tree with file sizes, to a limited depth. Use this instead of `ls -R` or `tree` to get oriented.
+- `edit_file`: Replace an exact string in a file. PREFER this for changing existing files: copy the text to change from read_file output (without line numbers) as old_string, including enough surrounding lines to make it unique. Unlike line numbers, it stays correct after earlier edits.
+- `apply_patch`: Apply a unified diff to make coordinated changes across several files (add, delete, update, move) in a single step.
+- `write_file`: Create or overwrite files, or append to them. Use this instead of `echo >`, `sed`, `awk`, or text editors. Avoid its line based replace/insert modes, line numbers go stale after each edit.
 - `execute_shell`: Use only for operations that cannot be done with file tools (compilation, git, directory operations, etc.).
+- `start_process`: Run a long-lived command (dev server, watcher) in the background; follow it with `read_process_output`, `send_process_input` and `stop_process`. Never start servers with execute_shell, it waits for the command to exit.
 
 **Shell commands for non-file operations:**
-- `ls`: List directory contents.
 - `git`: Version control operations.
 - `make`, `cargo`, `gcc`: Build and compilation.
-- `grep`: Search within command output (not files - use read_file + search).
-- `find`: Locate files and directories.
+- `grep`: Search within command output (not files - use search_files).
 - `chmod`, `chown`: File permissions.
 - `curl`, `ssh`, `scp`: Network operations.
 
-**IMPORTANT:** Always work within the current or project directory. Always use read_file for reading files and write_file for modifications. Only use shell commands when the file tools cannot accomplish the task.
+**IMPORTANT:** Always work within the current or project directory. Always use read_file for reading files, edit_file for changing them and write_file for new files. Only use shell commands when the file tools cannot accomplish the task.
 "#;
 
-    static DEFAULT_SYS_AGENT_PROMPT: &str = r#"You are working in the current directory, which is a codebase. Your role is to efficiently manage files and run commands. Here are your instructions:
+/// Base system prompt for agentic work in a codebase
+static DEFAULT_SYS_AGENT_PROMPT: &str = r#"You are working in the current directory, which is a codebase. Your role is to efficiently manage files and run commands. Here are your instructions:
 
-1. **Use Dedicated File Tools**: ALWAYS use read_file for reading files and write_file for modifications. These tools are more reliable than shell commands for file operations. Instruct a tool calling agent if you don't know how to call tools yourself.
+1. **Use Dedicated File Tools**: ALWAYS use read_file for reading files, and edit_file or write_file for modifications. These tools are more reliable than shell commands for file operations. Write a TOOL CALL block if you can't call tools yourself.
 
-2. **Focus on Specific Files and Lines**: Use read_file with line ranges to examine specific parts of files. Use write_file with insert/replace modes for targeted changes.
+2. **Focus on Specific Files and Lines**: Use search_files and find_files to locate code, then read_file with line ranges to examine specific parts of files. Use edit_file for targeted changes.
 
-3. **Surgical Code Changes**: Make precise changes using write_file's replace mode for specific line ranges, or insert mode to add new code at exact locations.
+3. **Surgical Code Changes**: Make precise changes with edit_file, replacing an exact and unique snippet of the file with its new text.
 
-4. **Shell Commands for Non-File Tasks**: Use execute_shell only for compilation, git operations, directory listing, and other non-file tasks.
+4. **Shell Commands for Non-File Tasks**: Use execute_shell only for compilation, git operations, and other non-file tasks.
 
 5. **Compile and Test**: After file changes, use execute_shell for compilation (make, cargo, gcc, python) and testing.
 
-6. **Loop Through Operations**: Read files to understand code, make targeted changes with write_file, then compile/test with execute_shell.
+6. **Loop Through Operations**: Read files to understand code, make targeted changes with edit_file, then compile/test with execute_shell.
 
 7. **Version Control**: Use execute_shell for git operations - commits, branches, status checks.
 
-8. **Error Handling**: If operations fail, use read_file to examine error logs or configuration files, then use write_file to fix issues.
+8. **Error Handling**: If operations fail, use read_file to examine error logs or configuration files, then use edit_file to fix issues.
 
 9. **Efficiency**: Minimize context by reading only necessary file sections and making targeted writes rather than rewriting entire files.
 
-10. **File Tool Priority**: Remember - read_file and write_file are your primary tools. Use execute_shell as a secondary tool for everything else.
+10. **File Tool Priority**: Remember - read_file, edit_file and write_file are your primary tools. Use execute_shell as a secondary tool for everything else.
 
 By following these instructions, you will efficiently manage the codebase with precise file operations and minimal context growth.
 "#;
+
+/// Builds the system prompt for a new conversation
+///
+/// Starts from the default agent prompt, appends the project's AGENT.md
+/// (if present) and the tool usage prompt. Models without tool support are
+/// also told how to write tool calls as text, unless tool parsing is off.
+///
+/// # Arguments
+/// * `app_config` - Application configuration used to look up model capabilities
+/// * `model` - The model the prompt is for
+/// * `tools` - Tool definitions offered to the model
+///
+/// # Returns
+/// The system prompt content
+pub fn create_system_prompt(
+    app_config: &ApplicationConfig,
+    model: &str,
+    tools: &[Value],
+) -> String {
     let mut sys_content = DEFAULT_SYS_AGENT_PROMPT.to_string();
 
     // Add AGENT.md context if available
@@ -131,29 +130,96 @@ By following these instructions, you will efficiently manage the codebase with p
         sys_content += &agent_context;
     }
 
-    if app_config
-        .get_model(&app_config.model)
-        .map(|m| m.capabilities.iter().any(|s| s == "tools"))
-        .is_some_and(core::convert::identity)
-    {
+    if app_config.has_capability(model, "tools") {
+        sys_content += DEFAULT_SYS_TOOLS_PROMPT;
+    } else if app_config.tool_parsing.strategy != ToolParsingStrategy::Off {
         sys_content += DEFAULT_SYS_TOOLS_PROMPT;
+        sys_content += &text_tools_prompt(tools);
     }
+    sys_content
+}
+
+/// Starts an interactive chat session with an Ollama model
+///
+/// Implements a REPL (Read-Eval-Print Loop) for conversing with the model.
+/// Handles message history, tool execution, session management, and various
+/// slash commands for user interaction.
+///
+/// # Arguments
+/// * `client` - HTTP client for API requests
+/// * `app_config` - Application configuration (URL, model, etc.)
+/// * `session` - Optional session name to load/save
+///
+/// # Returns
+/// Result indicating success or failure
+///
+/// # Errors
+/// Returns error if API requests fail or critical errors occur
+///
+/// # Slash Commands
+/// - `/quit`, `/exit`, `/done` - Exit and optionally save session
+/// - `/save <name>` - Save session with name
+/// - `/edit`, `/editor` - Open external editor for message composition
+/// - `/tools` - Extract tool calls from the last response
+/// - `/model [name]` - Show or switch the model
+/// - `/format [schema.json|json|off]` - Show or set the required response format
+/// - `/attach [path]` - Attach a file to the next message, or list attachments
+/// - `/reset` - Clear history (keep system message)
+/// - `/trim` - Trim to essential messages
+/// - `/compact [turns]` - Summarize history, keeping the last turns
+/// - `/send` - Force send without user message (also resends after a failed request)
+/// - `/fork <name> [at <index>]` - Fork the session into a new named session
+/// - `/rewind <index>` - Fork the session at a message under a generated name
+/// - `/checkpoints` - List file checkpoints
+/// - `/undo` - Revert the last tool write
+/// - `/restore <id> [truncate]` - Revert files to a checkpoint
+/// - `/ps` - List background processes
+/// - `/kill <id>|all` - Stop background processes
+pub async fn chat_mode(
+    client: &Client,
+    mut app_config: ApplicationConfig,
+    mut session: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut app_state = if let Some(ref session_name) = session {
+        ApplicationState::load_session(session_name, &app_config)?
+    } else {
+        ApplicationState::new_from_config(&app_config)
+    };
+    let backend = app_config.backend();
+    app_config.load_model(client, &app_state.model).await?;
+    //Background processes are killed when this goes out of scope at the end of the chat
+    let executor = ToolExecutor::new(&app_config)?;
+    let processes = &executor.processes;
+    app_state.tools = executor.definitions();
 
     if app_state.messages.is_empty() {
         app_state.messages.push(OllamaChatMessage {
             role: "system".to_string(),
-            content: sys_content,
+            content: create_system_prompt(&app_config, &app_state.model, &app_state.tools),
             tool_calls: None,
             tool_name: None,
             tool_call_id: None,
+            images: None,
+            model: None,
         });
     }
 
     println!("Ollama URL: {}...", app_config.url);
-    println!("Model: {}...", app_config.model);
+    println!("Model: {}...", app_state.model);
     println!("Entering Chat mode with shell tools - type '/quit' to exit, '/compact' to force context compaction, '/editor' to open editor");
 
     let mut rl = DefaultEditor::new()?;
+    //Ctrl-C now interrupts the current generation or tool instead of exiting
+    interrupt::install();
+    let mut interrupted = false;
+    //Files queued with /attach for the next user message
+    let mut pending_attachments: Vec<Attachment> = Vec::new();
+    //Format set with /format, and how often the current response was retried
+    let mut output_format: Option<OutputFormat> = None;
+    let mut format_retries = 0;
+    let mut retry_format = false;
+    //A failed request hands control back to the user instead of resending
+    let mut request_failed = false;
 
     //REPL Loop
     loop {
@@ -165,45 +231,46 @@ By following these instructions, you will efficiently manage the codebase with p
             }
         }
         if !temp_tool_calls.is_empty() {
-            let mut tool_messages = process_tool_calls(&mut rl, &app_config, &temp_tool_calls);
-            //If the model is not tool capable then turn these into user messages
-            if !app_config
-                .get_model(&app_state.model)
-                .map(|m| m.capabilities.iter().any(|s| s == "tools"))
-                .is_some_and(core::convert::identity)
-            {
-                println!("Modifying role to user for tools (Model doesn't understand tools)");
-                tool_messages
-                    .iter_mut()
-                    .for_each(|m| m.role = "user".to_string());
+            interrupt::clear();
+            executor.execute(&app_config, &mut app_state, &temp_tool_calls, |_| {
+                prompt_approval(&mut rl)
+            });
+            if interrupt::is_interrupted() {
+                println!("\n⏹ Tool calls interrupted");
+                interrupted = true;
             }
-            app_state.messages.extend(tool_messages);
             continue;
         }
 
-        //Prompt user for a message
-        if app_state.should_prompt_user() {
-            //If I have num_ctx set, then that will be what to use
-            let mut max_context = app_config
-                .get_model(&app_state.model)
-                .and_then(|m| m.options.clone())
-                .and_then(|o| o.num_ctx)
-                .unwrap_or(4096);
-            //If not, ollama defaults to 4k, unless I am using a cloud model (url == ollama.com) or
-            //model ends with :cloud
-            if app_config.url.contains("ollama.com") {
-                max_context = app_config
-                    .get_model(&app_state.model)
-                    .and_then(|m| m.get_context_length())
-                    .unwrap_or(4096);
-            }
+        //Prompt user for a message (always after an interruption, even if tool results are pending)
+        //A retry for the structured output is sent without asking the user
+        if std::mem::take(&mut request_failed)
+            || (!std::mem::take(&mut retry_format) && app_state.should_prompt_user())
+            || interrupted
+        {
             println!(
                 "Waiting on your response... (Context {} tokens / Max {} tokens)",
                 app_state.get_token_count_estimate(),
-                max_context
+                app_config.max_context(&app_state.model)
             );
 
-            let line = rl.readline("> ").unwrap();
+            let line = match rl.readline("> ") {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) if !interrupted => {
+                    println!("(Press Ctrl-C again to save and exit)");
+                    interrupted = true;
+                    continue;
+                }
+                Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => {
+                    if let Some(ref session_name) = session {
+                        app_state.save_session(session_name, &app_config)?;
+                        println!("Session saved: {}", session_name);
+                    }
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            interrupted = false;
             let input = {
                 rl.add_history_entry(&line)?;
                 line.trim().to_string()
@@ -212,7 +279,7 @@ By following these instructions, you will efficiently manage the codebase with p
 
             if input == "/quit" || input == "/exit" || input == "/done" {
                 if let Some(ref session_name) = session {
-                    app_state.save_session(session_name)?;
+                    app_state.save_session(session_name, &app_config)?;
                     println!("Session saved: {}", session_name);
                 }
                 break;
@@ -224,7 +291,7 @@ By following these instructions, you will efficiently manage the codebase with p
                     eprintln!("Error: session name cannot be empty");
                     continue;
                 }
-                if let Err(e) = app_state.save_session(session_name) {
+                if let Err(e) = app_state.save_session(session_name, &app_config) {
                     eprintln!("Error saving session: {}", e);
                 } else {
                     println!("Session saved: {}", session_name);
@@ -233,6 +300,209 @@ By following these instructions, you will efficiently manage the codebase with p
                 continue;
             }
 
+            if input.starts_with("/fork") {
+                let args: Vec<&str> = input
+                    .trim_start_matches("/fork")
+                    .split_whitespace()
+                    .collect();
+                let (name, at) = match args.as_slice() {
+                    [name] => (*name, None),
+                    [name, "at", index] if index.parse::<usize>().is_ok() => {
+                        (*name, index.parse::<usize>().ok())
+                    }
+                    _ => {
+                        eprintln!("Error: usage /fork <name> [at <index>]");
+                        continue;
+                    }
+                };
+                if let Err(e) =
+                    fork_session(&mut app_state, &mut session, &app_config, Some(name), at)
+                {
+                    eprintln!("Error forking session: {}", e);
+                }
+                continue;
+            }
+
+            if input.starts_with("/rewind") {
+                let Ok(at) = input.trim_start_matches("/rewind").trim().parse::<usize>() else {
+                    eprintln!("Error: usage /rewind <index>");
+                    continue;
+                };
+                //Named sessions keep the original transcript and continue in a new session
+                let store = SessionStore::new(&app_config.sessions);
+                let name = session.as_ref().map(|current| {
+                    (1..)
+                        .map(|k| format!("{}-{}", current, k))
+                        .find(|n| store.find(n).is_none())
+                        .unwrap_or_default()
+                });
+                if let Err(e) = fork_session(
+                    &mut app_state,
+                    &mut session,
+                    &app_config,
+                    name.as_deref(),
+                    Some(at),
+                ) {
+                    eprintln!("Error rewinding session: {}", e);
+                }
+                continue;
+            }
+
+            if input == "/checkpoints" {
+                if app_state.checkpoints.is_empty() {
+                    println!("No checkpoints");
+                }
+                for checkpoint in &app_state.checkpoints {
+                    println!("{}", checkpoint.summary());
+                }
+                continue;
+            }
+
+            if input.starts_with("/attach") {
+                let path = input.trim_start_matches("/attach").trim();
+                if path.is_empty() {
+                    if pending_attachments.is_empty() {
+                        println!("No attachments, use /attach <path> or @path in a message");
+                    }
+                    for attachment in &pending_attachments {
+                        println!("- {}", attachment.path());
+                    }
+                    continue;
+                }
+                match Attachment::load(path) {
+                    Ok(attachment) => {
+                        println!("Attached {}, it is sent with your next message", path);
+                        pending_attachments.push(attachment);
+                    }
+                    Err(e) => eprintln!("{}", e),
+                }
+                continue;
+            }
+
+            if input.starts_with("/format") {
+                let format = match input.trim_start_matches("/format").trim() {
+                    "" => {
+                        println!(
+                            "Format: {}",
+                            output_format.as_ref().map_or("off", |f| f.source.as_str())
+                        );
+                        continue;
+                    }
+                    "off" => None,
+                    "json" => Some(OutputFormat::json()),
+                    path => match OutputFormat::from_file(path) {
+                        Ok(format) => Some(format),
+                        Err(e) => {
+                            eprintln!("Error: {}", e);
+                            continue;
+                        }
+                    },
+                };
+                app_state.format = format.as_ref().map(|f| f.format().clone());
+                output_format = format;
+                println!(
+                    "Format: {}",
+                    output_format.as_ref().map_or("off", |f| f.source.as_str())
+                );
+                continue;
+            }
+
+            if input.starts_with("/model") {
+                let name = input.trim_start_matches("/model").trim();
+                if !name.is_empty() {
+                    if let Err(e) =
+                        switch_model(client, &mut app_config, &mut app_state, name).await
+                    {
+                        eprintln!("Error switching to model '{}': {}", name, e);
+                        continue;
+                    }
+                }
+                let capabilities = app_config
+                    .get_model(&app_state.model)
+                    .map(|m| m.capabilities.join(", "))
+                    .unwrap_or_default();
+                println!("Model: {} ({})", app_state.model, capabilities);
+                continue;
+            }
+
+            if input == "/ps" {
+                let list = processes.list();
+                if list.is_empty() {
+                    println!("No background processes");
+                }
+                for p in list {
+                    println!(
+                        "#{} pid {} {} for {}s: {}",
+                        p.id,
+                        p.pid,
+                        p.status,
+                        p.uptime.as_secs(),
+                        p.command
+                    );
+                }
+                continue;
+            }
+
+            if input.starts_with("/kill") {
+                let ids: Vec<usize> = match input.trim_start_matches("/kill").trim() {
+                    "all" => processes.list().iter().map(|p| p.id).collect(),
+                    arg => match arg.trim_start_matches('#').parse() {
+                        Ok(id) => vec![id],
+                        Err(_) => {
+                            println!("Usage: /kill <id>|all");
+                            continue;
+                        }
+                    },
+                };
+                for id in ids {
+                    match processes.stop(id) {
+                        Ok((status, _)) => println!("Stopped process #{}: {}", id, status),
+                        Err(e) => eprintln!("{}", e),
+                    }
+                }
+                continue;
+            }
+
+            if input == "/undo" {
+                match app_state.undo() {
+                    Ok(Some(checkpoint)) => println!("Reverted {}", checkpoint.summary()),
+                    Ok(None) => println!("Nothing to undo"),
+                    Err(e) => eprintln!("Error undoing: {}", e),
+                }
+                continue;
+            }
+
+            if input.starts_with("/restore") {
+                let args: Vec<&str> = input
+                    .trim_start_matches("/restore")
+                    .split_whitespace()
+                    .collect();
+                let (id, truncate) = match args.as_slice() {
+                    [id] => (id.parse::<usize>(), false),
+                    [id, "truncate"] => (id.parse::<usize>(), true),
+                    _ => ("".parse::<usize>(), false),
+                };
+                let Ok(id) = id else {
+                    eprintln!("Error: usage /restore <id> [truncate]");
+                    continue;
+                };
+                match app_state.restore_checkpoint(id, truncate) {
+                    Ok(reverted) => {
+                        reverted
+                            .iter()
+                            .for_each(|c| println!("Reverted {}", c.summary()));
+                        if truncate {
+                            println!(
+                                "Conversation truncated to {} messages",
+                                app_state.messages.len()
+                            );
+                        }
+                    }
+                    Err(e) => eprintln!("Error restoring checkpoint: {}", e),
+                }
+                continue;
+            }
+
             if input == "/edit" || input == "/editor" {
                 //This will open up the system defined or config defined editor like vim, emacs, or
                 //nano. The whole session will be serialized as yaml and the user can edit any of it in
@@ -243,7 +513,20 @@ By following these instructions, you will efficiently manage the codebase with p
             }
 
             if input == "/tools" {
-                app_state.get_tool_calls(client, &app_config).await?;
+                //Runs the parser by hand even when automatic parsing is off
+                let strategy = match app_config.tool_parsing.strategy {
+                    ToolParsingStrategy::Off => ToolParsingStrategy::Parser,
+                    strategy => strategy,
+                };
+                interrupt::clear();
+                match app_state
+                    .extract_tool_calls(client, &app_config, strategy)
+                    .await
+                {
+                    Ok(0) => println!("No tool calls found in the last response"),
+                    Ok(count) => println!("Found {} tool call(s) in the last response", count),
+                    Err(e) => eprintln!("Error extracting tool calls: {}", e),
+                }
                 continue;
             }
 
@@ -257,18 +540,64 @@ By following these instructions, you will efficiently manage the codebase with p
                 continue;
             }
 
-            if input == "/compact" {
-                app_state.compact(client, &app_config).await?;
+            if input.starts_with("/compact") {
+                let keep_turns = match input.trim_start_matches("/compact").trim() {
+                    "" => app_config.compaction.keep_turns,
+                    n => match n.parse::<usize>() {
+                        Ok(n) => n,
+                        Err(_) => {
+                            eprintln!("Error: usage /compact [turns to keep]");
+                            continue;
+                        }
+                    },
+                };
+                interrupt::clear();
+                if let Err(e) = app_state.compact(client, &app_config, keep_turns).await {
+                    eprintln!("Error compacting: {}", e);
+                }
                 continue;
             }
 
             if input == "/send" {
                 //Skip creating a user message and just send the chat to the server as is
                 println!("Forcing send without user message...");
-            } else if user_content.is_empty() {
-                app_state.add_user_message(&input);
             } else {
-                app_state.add_user_message(&user_content);
+                if user_content.is_empty() {
+                    user_content = input;
+                }
+                let (content, mentioned) = extract_mentions(&user_content);
+                let attachments: Vec<Attachment> =
+                    pending_attachments.drain(..).chain(mentioned).collect();
+                for attachment in &attachments {
+                    println!("Attaching {}", attachment.path());
+                }
+                if attachments.iter().any(Attachment::is_image)
+                    && !app_config.has_capability(&app_state.model, "vision")
+                {
+                    eprintln!(
+                        "Warning: {} doesn't list the vision capability, images may be ignored",
+                        app_state.model
+                    );
+                }
+                let (content, images) = apply_attachments(&content, attachments);
+                app_state.add_user_message_with_images(&content, images);
+            }
+        }
+
+        //Compact before sending if the context is close to the model's limit
+        if app_state.needs_compaction(&app_config) {
+            println!(
+                "Context {} tokens is over {:.0}% of {} tokens, compacting...",
+                app_state.get_token_count_estimate(),
+                app_config.compaction.threshold * 100.0,
+                app_config.max_context(&app_state.model)
+            );
+            interrupt::clear();
+            if let Err(e) = app_state
+                .compact(client, &app_config, app_config.compaction.keep_turns)
+                .await
+            {
+                eprintln!("Error compacting: {}", e);
             }
         }
 
@@ -278,27 +607,9 @@ By following these instructions, you will efficiently manage the codebase with p
         );
 
         //Call Ollamas chat endpoint
-        let mut request: OllamaChatRequest = app_state.clone().into();
-        request.stream = app_config.stream;
-        request.think = false;
-        if let Some(model_config) = app_config.get_model(&app_state.model) {
-            request.think = model_config.capabilities.iter().any(|c| c == "tools");
-            request.tools = model_config
-                .capabilities
-                .iter()
-                .any(|c| c == "tools")
-                .then_some(app_state.tools.clone());
-            request.options = model_config.options.clone();
-        }
-        match post_ollama_chat(
-            client,
-            app_config.url.as_str(),
-            app_config.api_key.as_str(),
-            &request,
-            Some(&mut app_state),
-        )
-        .await
-        {
+        interrupt::clear();
+        let request = app_state.create_request(&app_config);
+        match backend.chat(client, &request, Some(&mut app_state)).await {
             Ok((response, streaming_state)) => {
                 //Assume this is the last of the streaming messages, it's marked done
                 //Assume this is the last of the streaming messages, it's marked done
@@ -314,19 +625,60 @@ By following these instructions, you will efficiently manage the codebase with p
                 if matches!(streaming_state, OllamaChatResponseStreamingState::NoStream) {
                     app_state.print_assistant_response(&response);
                 }
+                if matches!(
+                    streaming_state,
+                    OllamaChatResponseStreamingState::Interrupted
+                ) {
+                    println!("\n⏹ Generation interrupted");
+                    interrupted = true;
+                }
                 //Add the response to messages
                 app_state.add_assistant_response(response);
+                //Models without tool support write their tool calls into the response
+                if !interrupted && !app_config.has_capability(&app_state.model, "tools") {
+                    match app_state
+                        .extract_tool_calls(client, &app_config, app_config.tool_parsing.strategy)
+                        .await
+                    {
+                        Ok(0) => {}
+                        Ok(count) => println!("\nFound {} tool call(s) in the response", count),
+                        Err(e) => eprintln!("Error extracting tool calls: {}", e),
+                    }
+                }
+                //Check the structured output once the model is done calling tools
+                let last = app_state.messages.last().cloned().unwrap_or_default();
+                if let Some(format) = output_format.as_ref().filter(|_| {
+                    !interrupted && last.tool_calls.as_ref().is_none_or(|tc| tc.is_empty())
+                }) {
+                    match format.validate(&last.content) {
+                        Ok(_) => {
+                            println!("\n✓ Response matches {}", format.source);
+                            format_retries = 0;
+                        }
+                        Err(errors) => {
+                            eprintln!("\nResponse doesn't match {}:", format.source);
+                            for error in &errors {
+                                eprintln!("  - {}", error);
+                            }
+                            if format_retries < app_config.structured_output.max_retries {
+                                format_retries += 1;
+                                println!(
+                                    "Retrying ({}/{})...",
+                                    format_retries, app_config.structured_output.max_retries
+                                );
+                                app_state.add_user_message(&retry_prompt(&errors));
+                                retry_format = true;
+                            } else {
+                                eprintln!("Giving up after {} retries", format_retries);
+                                format_retries = 0;
+                            }
+                        }
+                    }
+                }
             }
             Err(e) => {
-                eprintln!("❌ API Error: {}", e);
-                eprintln!("Please check:");
-                eprintln!("  - Ollama is running (try: ollama serve)");
-                eprintln!(
-                    "  - Model '{}' is available (try: ollama list)",
-                    app_config.model
-                );
-                eprintln!("  - URL '{}' is correct", app_config.url);
-                return Err(e);
+                report_chat_error(&e, &app_config.url);
+                request_failed = true;
             }
         }
         println!();
@@ -335,210 +687,148 @@ By following these instructions, you will efficiently manage the codebase with p
     Ok(())
 }
 
-/// Will take a vec of tool calls, prompt the user for approval and return a set of tool messages
-/// Processes tool calls by prompting user for approval and executing approved tools
-///
-/// Displays each tool call request to the user with relevant details and asks
-/// for approval. If approved, executes the tool and returns the result as a
-/// tool message. If rejected, returns the user's feedback as the tool result.
+/// Asks the user whether to run a tool call
 ///
 /// # Arguments
 /// * `rl` - Readline interface for user input
-/// * `app_config` - Application configuration for output limits
-/// * `tool_calls` - List of tool calls to process
 ///
 /// # Returns
-/// Vector of tool result messages to add to conversation history
-fn process_tool_calls(
-    rl: &mut DefaultEditor,
-    app_config: &ApplicationConfig,
-    tool_calls: &Vec<ToolCall>,
-) -> Vec<OllamaChatMessage> {
-    let mut ret: Vec<OllamaChatMessage> = Vec::new();
-    for tc in tool_calls {
-        match tc.function.name.as_str() {
-            "execute_shell" => {
-                println!(
-                    "🛠️  Shell Command Requested!\n ● Command: {}\n ● Reason: {}",
-                    tc.function.arguments.get("command").unwrap(),
-                    tc.function.arguments.get("reason").unwrap()
-                );
-            }
-            "read_file" => {
-                let path = tc.function.arguments.get("path").unwrap().as_str().unwrap();
-                let start_line = tc
-                    .function
-                    .arguments
-                    .get("start_line")
-                    .and_then(|v| v.as_u64())
-                    .map(|v| v as usize);
-                let end_line = tc
-                    .function
-                    .arguments
-                    .get("end_line")
-                    .and_then(|v| v.as_u64())
-                    .map(|v| v as usize);
-
-                println!("🛠️  Read File Requested!");
-                match (start_line, end_line) {
-                    (Some(start), Some(end)) => {
-                        println!(" ● Reading file: {}, from line {} to {}", path, start, end)
-                    }
-                    (Some(start), None) => {
-                        println!(" ● Reading file: {}, from line {} to end", path, start)
-                    }
-                    (None, Some(end)) => {
-                        println!(" ● Reading file: {}, from start to line {}", path, end)
-                    }
-                    (None, None) => println!(" ● Reading file: {}", path),
-                }
-            }
-            "write_file" => {
-                let path = tc.function.arguments.get("path").unwrap().as_str().unwrap();
-                let content = tc
-                    .function
-                    .arguments
-                    .get("content")
-                    .unwrap()
-                    .as_str()
-                    .unwrap();
-                let mode = tc.function.arguments.get("mode").and_then(|v| v.as_str());
-                let start_line = tc
-                    .function
-                    .arguments
-                    .get("start_line")
-                    .and_then(|v| v.as_u64())
-                    .map(|v| v as usize);
-                let end_line = tc
-                    .function
-                    .arguments
-                    .get("end_line")
-                    .and_then(|v| v.as_u64())
-                    .map(|v| v as usize);
-
-                println!("🛠️  Write File Requested!");
-                match (mode.unwrap_or("overwrite"), start_line, end_line) {
-                    ("replace", Some(start), Some(end)) => {
-                        println!(" ● Replacing lines {}-{} in: {}", start, end, path)
-                    }
-                    ("replace", Some(start), None) => {
-                        println!(" ● Replacing from line {} in: {}", start, path)
-                    }
-                    ("insert", Some(line), _) => {
-                        println!(" ● Inserting at line {} in: {}", line, path)
-                    }
-                    ("append", _, _) => println!(" ● Appending to: {}", path),
-                    _ => println!(" ● Writing to: {}", path),
-                }
-                println!();
-
-                show_write_diff(path, content, mode, start_line, end_line);
-            }
-            _ => {
-                println!("Tool Call {} Requested! Allow?", tc.function.name);
-            }
-        }
-        let input = match rl.readline("y or no with reason/feedback > ") {
+/// The user's answer, anything but `y` or `a` rejects the call with the
+/// input as feedback. Ctrl-C rejects it and cancels the remaining calls.
+fn prompt_approval(rl: &mut DefaultEditor) -> ToolApproval {
+    let input =
+        match rl.readline("y, a (always allow for this session) or no with reason/feedback > ") {
             Ok(line) => line.trim().to_string(),
+            Err(ReadlineError::Interrupted) => {
+                interrupt::trigger();
+                "The user interrupted the tool calls".to_string()
+            }
             Err(_) => "ERROR".to_string(),
         };
-        if input == "y" || input == "Y" {
-            let tool_result = match tc.function.name.as_str() {
-                "execute_shell" => execute_command(
-                    tc.function
-                        .arguments
-                        .get("command")
-                        .unwrap()
-                        .as_str()
-                        .unwrap(),
-                    &app_config.output_limit,
-                ),
-                "read_file" => {
-                    let path = tc.function.arguments.get("path").unwrap().as_str().unwrap();
-                    let start_line = tc
-                        .function
-                        .arguments
-                        .get("start_line")
-                        .and_then(|v| v.as_u64())
-                        .map(|v| v as usize);
-                    let end_line = tc
-                        .function
-                        .arguments
-                        .get("end_line")
-                        .and_then(|v| v.as_u64())
-                        .map(|v| v as usize);
-                    let result = read_file_lines(path, start_line, end_line);
-                    let byte_count = result.len();
-
-                    // Count actual lines read
-                    let line_count = result.lines().count();
-                    let first_line = result
-                        .lines()
-                        .next()
-                        .and_then(|line| line.split(':').next())
-                        .and_then(|num| num.trim().parse::<usize>().ok())
-                        .unwrap_or(1);
-                    let last_line = first_line + line_count.saturating_sub(1);
-
-                    if line_count > 0 {
-                        println!(
-                            " ✓ Successfully read {} bytes, {} lines ({}-{}) from {}",
-                            byte_count, line_count, first_line, last_line, path
-                        );
-                    } else {
-                        println!(" ✓ Successfully read {} bytes from {}", byte_count, path);
-                    }
+    match input.as_str() {
+        "y" | "Y" => ToolApproval::Approve,
+        "a" | "A" => ToolApproval::ApproveForSession,
+        _ => ToolApproval::Reject(input),
+    }
+}
 
-                    // Print what we're sending to the LLM
-                    println!("OUTPUT:");
-                    println!("{}", result);
+/// Tells the user why a request failed and what to try
+///
+/// # Arguments
+/// * `error` - Why the request failed
+/// * `url` - Base URL of the server
+fn report_chat_error(error: &ChatError, url: &str) {
+    eprintln!("\n❌ {}", error);
+    match error {
+        ChatError::Connection { .. } => {
+            eprintln!("  - Check that the server is running (try: ollama serve)");
+            eprintln!("  - Check that the URL '{}' is correct", url);
+        }
+        ChatError::ModelNotFound { model } => {
+            eprintln!("  - Download it with: chatto models pull {}", model);
+            eprintln!("  - Or switch to an installed model (see: chatto list) with /model <name>");
+        }
+        ChatError::Status { status, .. } if status.as_u16() == 401 || status.as_u16() == 403 => {
+            eprintln!("  - Check the api key (--key or OLLAMA_API_KEY)");
+        }
+        ChatError::Status { status, .. } if status.is_server_error() => {
+            eprintln!("  - The server failed to answer, its log may tell why");
+        }
+        ChatError::Status { .. } => {
+            eprintln!("  - The server rejected the request, eg. the conversation may be too long (try: /compact)");
+        }
+        ChatError::InvalidResponse(_) | ChatError::Server(_) => {
+            eprintln!("  - The response broke off, the server's log may tell why");
+        }
+        ChatError::Interrupted => {}
+    }
+    eprintln!("The conversation is kept, type /send to send it again");
+}
 
-                    result
-                }
-                "write_file" => {
-                    let path = tc.function.arguments.get("path").unwrap().as_str().unwrap();
-                    let content = tc
-                        .function
-                        .arguments
-                        .get("content")
-                        .unwrap()
-                        .as_str()
-                        .unwrap();
-                    let mode = tc.function.arguments.get("mode").and_then(|v| v.as_str());
-                    let start_line = tc
-                        .function
-                        .arguments
-                        .get("start_line")
-                        .and_then(|v| v.as_u64())
-                        .map(|v| v as usize);
-                    let end_line = tc
-                        .function
-                        .arguments
-                        .get("end_line")
-                        .and_then(|v| v.as_u64())
-                        .map(|v| v as usize);
-                    write_file_content(path, content, mode, start_line, end_line)
-                }
-                _ => format!("Unknown tool: {}", tc.function.name),
-            };
-            ret.push(OllamaChatMessage {
-                role: "tool".to_string(),
-                content: tool_result,
-                tool_calls: None,
-                tool_call_id: tc.id.clone(),
-                tool_name: Some(tc.function.name.clone()),
-            });
-        } else {
-            ret.push(OllamaChatMessage {
-                role: "tool".to_string(),
-                content: format!("TOOL CALL REJECTED. Feedback/Reason: {}", &input),
-                tool_calls: None,
-                tool_call_id: tc.id.clone(),
-                tool_name: Some(tc.function.name.clone()),
-            });
+/// Switches the conversation to another model
+///
+/// Fetches the model's capabilities and merges them into the cached model
+/// list, so the next request uses its tool and think flags. The system prompt
+/// is rebuilt for the new model unless it was changed during the chat.
+///
+/// # Arguments
+/// * `client` - HTTP client for API requests
+/// * `app_config` - Application configuration receiving the model's capabilities
+/// * `app_state` - The chat's state, switched to the model
+/// * `model` - Name of the model to switch to
+///
+/// # Errors
+/// Returns error if the model cannot be fetched from the server
+async fn switch_model(
+    client: &Client,
+    app_config: &mut ApplicationConfig,
+    app_state: &mut ApplicationState,
+    model: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let old_prompt = create_system_prompt(app_config, &app_state.model, &app_state.tools);
+    app_config.load_model(client, model).await?;
+    let new_prompt = create_system_prompt(app_config, model, &app_state.tools);
+    if let Some(system) = app_state
+        .messages
+        .first_mut()
+        .filter(|m| m.role == "system" && m.content == old_prompt)
+    {
+        system.content = new_prompt;
+    }
+    app_state.model = model.to_string();
+    Ok(())
+}
+
+/// Switches the chat to a fork of the current session
+///
+/// The current session is saved first (if it has a name) so its transcript
+/// is kept. The fork shares the history up to and including message `at`
+/// and records the current session as its parent. A named fork is saved and
+/// becomes the chat's session, an unnamed fork replaces the conversation in
+/// memory only.
+///
+/// # Arguments
+/// * `app_state` - The chat's state, replaced by the fork
+/// * `session` - The chat's session name, replaced by `name` if given
+/// * `app_config` - Application configuration for the session store
+/// * `name` - Name to save the fork as
+/// * `at` - Index of the last message to keep, or None to keep all messages
+///
+/// # Errors
+/// Returns error if the index is out of range, a session named `name`
+/// already exists or a session cannot be saved
+fn fork_session(
+    app_state: &mut ApplicationState,
+    session: &mut Option<String>,
+    app_config: &ApplicationConfig,
+    name: Option<&str>,
+    at: Option<usize>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(name) = name {
+        if SessionStore::new(&app_config.sessions).find(name).is_some() {
+            return Err(format!("Session '{}' already exists", name).into());
         }
     }
-    ret
+    let mut forked = app_state.fork(at)?;
+    if let Some(current) = session.as_deref() {
+        app_state.save_session(current, app_config)?;
+        println!("Session saved: {}", current);
+    }
+    //Approvals given in this chat still apply to the fork
+    forked.session_approvals = app_state.session_approvals.clone();
+    if let Some(name) = name {
+        forked.save_session(name, app_config)?;
+        *session = Some(name.to_string());
+    }
+    println!(
+        "Continuing in {} with messages 0-{} ({} messages)",
+        name.map(|n| format!("session '{}'", n))
+            .unwrap_or_else(|| "an unsaved fork".to_string()),
+        forked.forked_at.unwrap_or_default(),
+        forked.messages.len()
+    );
+    *app_state = forked;
+    Ok(())
 }
 
 /// Loads AGENT.md context file from current directory
@@ -0,0 +1,260 @@
+//! File-change checkpoints for tool writes.
+//!
+//! Before an approved tool call writes files, the previous contents of every
+//! path it will write are captured in a `Checkpoint`. Checkpoints are kept in
+//! the session (and saved with it), keyed to the assistant message that
+//! requested the write, so changes made by the model can be reverted with
+//! `/undo` or `/restore` even if nothing was committed. Text files are saved
+//! as is and other files base64 encoded, so any file can be put back.
+
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::{self, Path},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Contents of a file before a tool wrote to it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileSnapshot {
+    /// Absolute path of the file
+    pub path: String,
+    /// Previous contents, or None if the file didn't exist
+    pub content: Option<FileContent>,
+}
+
+/// Saved contents of a file
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum FileContent {
+    /// A UTF-8 text file
+    Text(String),
+    /// Any other file, base64 encoded
+    Binary { base64: String },
+}
+
+impl FileContent {
+    /// Saves a file's bytes, as text when they are valid UTF-8
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        match String::from_utf8(bytes) {
+            Ok(text) => FileContent::Text(text),
+            Err(e) => FileContent::Binary {
+                base64: STANDARD.encode(e.into_bytes()),
+            },
+        }
+    }
+
+    /// The saved bytes
+    ///
+    /// # Errors
+    /// Returns error if binary contents aren't valid base64
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        match self {
+            FileContent::Text(text) => Ok(text.clone().into_bytes()),
+            FileContent::Binary { base64 } => STANDARD
+                .decode(base64)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+/// Snapshot of the files a single tool call wrote
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Checkpoint {
+    /// Id of the checkpoint, unique within the session
+    pub id: usize,
+    /// Index of the assistant message whose tool call made the change
+    pub message_index: usize,
+    /// Name of the tool that made the change
+    pub tool_name: String,
+    /// When the checkpoint was taken
+    pub created_at: DateTime<Utc>,
+    /// Previous contents of each written file
+    pub files: Vec<FileSnapshot>,
+}
+
+impl Checkpoint {
+    /// Captures the current contents of files that are about to be written
+    ///
+    /// # Arguments
+    /// * `checkpoints` - Existing checkpoints, used to pick the next id
+    /// * `message_index` - Index of the assistant message requesting the write
+    /// * `tool_name` - Name of the tool about to write
+    /// * `paths` - Paths the tool will write
+    ///
+    /// # Returns
+    /// The new checkpoint
+    ///
+    /// # Errors
+    /// Returns error if an existing file cannot be read
+    pub fn capture(
+        checkpoints: &[Checkpoint],
+        message_index: usize,
+        tool_name: &str,
+        paths: &[String],
+    ) -> io::Result<Self> {
+        let files = paths
+            .iter()
+            .map(|p| {
+                let path = path::absolute(p)?;
+                let content = match fs::read(&path) {
+                    Ok(bytes) => Some(FileContent::from_bytes(bytes)),
+                    Err(e) if e.kind() == ErrorKind::NotFound => None,
+                    Err(e) => {
+                        return Err(io::Error::new(
+                            e.kind(),
+                            format!("Can't snapshot '{}': {}", p, e),
+                        ))
+                    }
+                };
+                Ok(FileSnapshot {
+                    path: path.display().to_string(),
+                    content,
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Self {
+            id: checkpoints.last().map(|c| c.id + 1).unwrap_or(1),
+            message_index,
+            tool_name: tool_name.to_string(),
+            created_at: Utc::now(),
+            files,
+        })
+    }
+
+    /// Puts the files back the way they were when the checkpoint was taken
+    ///
+    /// Files that didn't exist are deleted.
+    ///
+    /// # Errors
+    /// Returns error if a file cannot be written or deleted
+    pub fn restore(&self) -> io::Result<()> {
+        for file in &self.files {
+            let path = Path::new(&file.path);
+            match &file.content {
+                Some(content) => {
+                    if let Some(parent) = path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::write(path, content.to_bytes()?)?;
+                }
+                None => match fs::remove_file(path) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Describes the checkpoint on one line for listings
+    ///
+    /// # Returns
+    /// Id, message, tool, time and files, with new files marked
+    pub fn summary(&self) -> String {
+        let files: Vec<String> = self
+            .files
+            .iter()
+            .map(|f| match f.content {
+                Some(_) => f.path.clone(),
+                None => format!("{} (new)", f.path),
+            })
+            .collect();
+        format!(
+            "#{} message {} {} at {}: {}",
+            self.id,
+            self.message_index,
+            self.tool_name,
+            self.created_at
+                .with_timezone(&chrono::Local)
+                .format("%H:%M:%S"),
+            files.join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restores_text_binary_and_new_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = dir.path().join("notes.txt");
+        let binary = dir.path().join("data.bin");
+        let new = dir.path().join("nested/new.txt");
+        fs::write(&text, "before\n").unwrap();
+        fs::write(&binary, [0xff, 0x00, 0xfe]).unwrap();
+        let paths: Vec<String> = [&text, &binary, &new]
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect();
+
+        let checkpoint = Checkpoint::capture(&[], 3, "write_file", &paths).unwrap();
+        assert_eq!(checkpoint.id, 1);
+        assert_eq!(checkpoint.message_index, 3);
+        let contents: Vec<Option<FileContent>> =
+            checkpoint.files.iter().map(|f| f.content.clone()).collect();
+        assert_eq!(
+            contents,
+            [
+                Some(FileContent::Text("before\n".into())),
+                Some(FileContent::Binary {
+                    base64: "/wD+".into()
+                }),
+                None
+            ]
+        );
+
+        fs::write(&text, "after\n").unwrap();
+        fs::write(&binary, "replaced").unwrap();
+        fs::create_dir_all(new.parent().unwrap()).unwrap();
+        fs::write(&new, "created").unwrap();
+        checkpoint.restore().unwrap();
+        assert_eq!(fs::read_to_string(&text).unwrap(), "before\n");
+        assert_eq!(fs::read(&binary).unwrap(), [0xff, 0x00, 0xfe]);
+        assert!(!new.exists());
+        //Restoring twice is harmless
+        checkpoint.restore().unwrap();
+    }
+
+    #[test]
+    fn restores_into_deleted_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a/b.txt");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, "kept").unwrap();
+        let paths = vec![file.display().to_string()];
+        let checkpoint = Checkpoint::capture(&[], 1, "apply_patch", &paths).unwrap();
+        fs::remove_dir_all(dir.path().join("a")).unwrap();
+        checkpoint.restore().unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "kept");
+    }
+
+    #[test]
+    fn refuses_unreadable_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = vec![dir.path().display().to_string()];
+        assert!(Checkpoint::capture(&[], 1, "write_file", &paths).is_err());
+    }
+
+    #[test]
+    fn numbers_checkpoints_and_reads_saved_sessions() {
+        let first = Checkpoint::capture(&[], 1, "write_file", &[]).unwrap();
+        let second = Checkpoint::capture(&[first], 2, "write_file", &[]).unwrap();
+        assert_eq!(second.id, 2);
+        //Sessions saved before binary snapshots keep working
+        let snapshot: FileSnapshot =
+            serde_json::from_str(r#"{"path": "/a.txt", "content": "old"}"#).unwrap();
+        assert_eq!(snapshot.content, Some(FileContent::Text("old".into())));
+        let json = serde_json::to_value(&FileSnapshot {
+            path: "/a.bin".into(),
+            content: Some(FileContent::from_bytes(vec![0xff])),
+        })
+        .unwrap();
+        assert_eq!(json["content"], serde_json::json!({"base64": "/w=="}));
+    }
+}
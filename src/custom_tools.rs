@@ -0,0 +1,368 @@
+//! User-defined tools running command templates.
+//!
+//! Each entry of the `tools` config section becomes a tool offered to the
+//! model next to the built-in ones:
+//!
+//! ```yaml
+//! tools:
+//!   run_test:
+//!     description: Run a single test by name
+//!     parameters:
+//!       type: object
+//!       properties:
+//!         name: { type: string, description: Name of the test }
+//!       required: [name]
+//!     command: cargo test {{name}} -- --exact
+//!     approval: always        # optional, overridden by approval.tools
+//!     timeout: 300            # optional, seconds
+//!     output_limit:           # optional, replaces the global output_limit
+//!       max_size: 8000
+//!       method: tail
+//! ```
+//!
+//! `{{param}}` placeholders are replaced by the call's arguments quoted for
+//! the shell, so arguments can't inject extra commands. Placeholders inside
+//! quotes in the template (`"{{param}}"`) are rejected, since the quoting
+//! doesn't protect arguments there. Omitted optional parameters are replaced
+//! by nothing. The rendered command runs like an
+//! `execute_shell` call (same shell environment, timeout and result format)
+//! and is matched against the `approval.shell` rules.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    error::Error,
+    sync::LazyLock,
+};
+
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    approval::{ApprovalMode, ApprovalPolicy, ApprovalSubject},
+    tools::{execute_command, OutputLimit, ShellArgs, Tool, ToolContext, ToolRegistry},
+};
+
+/// Matches `{{param}}` placeholders in command templates
+static PLACEHOLDER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{\{\s*([A-Za-z0-9_-]+)\s*\}\}").unwrap());
+
+/// A tool from the `tools` config section
+#[derive(Debug, Deserialize, Clone)]
+pub struct CustomToolConfig {
+    /// Description shown to the model
+    pub description: String,
+    /// JSON schema of the arguments
+    #[serde(default = "empty_schema")]
+    pub parameters: Value,
+    /// Command line with `{{param}}` placeholders
+    pub command: String,
+    /// Approval mode used unless `approval.tools` names the tool
+    #[serde(default)]
+    pub approval: Option<ApprovalMode>,
+    /// Seconds before the command is killed, defaults to `shell.timeout`
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    /// Output limit replacing the global `output_limit`
+    #[serde(default)]
+    pub output_limit: Option<OutputLimit>,
+}
+
+/// Schema of a tool without parameters
+fn empty_schema() -> Value {
+    serde_json::json!({ "type": "object", "properties": {} })
+}
+
+/// Tool running a command template from the configuration
+pub struct CommandTool {
+    name: String,
+    config: CustomToolConfig,
+}
+
+impl CommandTool {
+    /// Names of the parameters required by the schema
+    fn required(&self) -> impl Iterator<Item = &str> {
+        self.config.parameters["required"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+    }
+
+    /// Fills in the command template
+    ///
+    /// # Arguments
+    /// * `args` - Arguments of the tool call
+    ///
+    /// # Returns
+    /// The command with every placeholder replaced by its quoted argument
+    fn render(&self, args: &Value) -> String {
+        PLACEHOLDER
+            .replace_all(&self.config.command, |caps: &regex::Captures| {
+                match args.get(&caps[1]) {
+                    None | Some(Value::Null) => String::new(),
+                    Some(Value::Array(items)) => items
+                        .iter()
+                        .map(|item| shell_quote(&plain_value(item)))
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                    Some(value) => shell_quote(&plain_value(value)),
+                }
+            })
+            .into_owned()
+    }
+}
+
+impl Tool for CommandTool {
+    type Args = Value;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.config.description
+    }
+
+    fn parameters(&self) -> Value {
+        self.config.parameters.clone()
+    }
+
+    fn validate(&self, args: &Self::Args) -> Result<(), String> {
+        if !args.is_object() {
+            return Err(format!(
+                "Error: arguments for tool '{}' must be an object",
+                self.name
+            ));
+        }
+        let missing: Vec<&str> = self
+            .required()
+            .filter(|p| args.get(p).is_none_or(Value::is_null))
+            .collect();
+        match missing.is_empty() {
+            true => Ok(()),
+            false => Err(format!(
+                "Error: missing required arguments for tool '{}': {}",
+                self.name,
+                missing.join(", ")
+            )),
+        }
+    }
+
+    fn preview(&self, args: &Self::Args) {
+        eprintln!(
+            "🛠️  {} Requested!\n ● Command: {}",
+            self.name,
+            self.render(args)
+        );
+    }
+
+    fn approval_subject(&self, args: &Self::Args) -> ApprovalSubject {
+        ApprovalSubject::Command(self.render(args))
+    }
+
+    fn execute(&self, args: Self::Args, context: &ToolContext) -> String {
+        let shell_args = ShellArgs {
+            command: self.render(&args),
+            reason: String::new(),
+            cwd: None,
+            timeout: self.config.timeout,
+        };
+        let context = ToolContext {
+            output_limit: self
+                .config
+                .output_limit
+                .as_ref()
+                .unwrap_or(context.output_limit),
+            ..*context
+        };
+        execute_command(&shell_args, &context)
+    }
+}
+
+/// Formats an argument as the text substituted into the command
+fn plain_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+/// Quotes a string so the shell passes it through as a single word
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Finds a placeholder the template puts inside single or double quotes
+///
+/// A quoted argument inside double quotes is still subject to `$(...)` and
+/// backticks, and one inside single quotes ends the template's quotes.
+///
+/// # Returns
+/// The name of the first quoted placeholder, if any
+fn quoted_placeholder(command: &str) -> Option<String> {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let mut placeholders = PLACEHOLDER.captures_iter(command).peekable();
+    for (i, c) in command.char_indices() {
+        if let Some(caps) = placeholders.next_if(|caps| caps.get(0).unwrap().start() == i) {
+            if quote.is_some() {
+                return Some(caps[1].to_string());
+            }
+        }
+        match (quote, c) {
+            (_, _) if escaped => escaped = false,
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => escaped = true,
+            (Some('"'), '"') => quote = None,
+            (None, '\'' | '"') => quote = Some(c),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Registers the tools from the `tools` config section
+///
+/// # Arguments
+/// * `registry` - Registry to add the tools to
+/// * `policy` - Approval policy receiving the tools' own approval modes
+/// * `tools` - The `tools` config section
+///
+/// # Errors
+/// Returns error if a tool shadows an existing tool, or its template uses a
+/// placeholder that isn't one of its parameters or puts one inside quotes
+pub fn register_custom_tools(
+    registry: &mut ToolRegistry,
+    policy: &mut ApprovalPolicy,
+    tools: &BTreeMap<String, CustomToolConfig>,
+) -> Result<(), Box<dyn Error>> {
+    for (name, config) in tools {
+        if registry.get(name).is_some() {
+            return Err(format!(
+                "Tool '{}' in the tools config shadows a built-in tool",
+                name
+            )
+            .into());
+        }
+        let parameters: HashSet<&str> = config.parameters["properties"]
+            .as_object()
+            .map(|p| p.keys().map(String::as_str).collect())
+            .unwrap_or_default();
+        for caps in PLACEHOLDER.captures_iter(&config.command) {
+            if !parameters.contains(&caps[1]) {
+                return Err(format!(
+                    "Tool '{}' uses {{{{{}}}}} in its command but has no such parameter",
+                    name, &caps[1]
+                )
+                .into());
+            }
+        }
+        if let Some(param) = quoted_placeholder(&config.command) {
+            return Err(format!(
+                "Tool '{}' puts {{{{{}}}}} inside quotes in its command, leave it unquoted as arguments are quoted already",
+                name, param
+            )
+            .into());
+        }
+        if let Some(mode) = config.approval {
+            policy.set_tool_default(name, mode);
+        }
+        registry.register(CommandTool {
+            name: name.clone(),
+            config: config.clone(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use std::path::Path;
+
+    use super::*;
+    use crate::approval::ApprovalConfig;
+
+    fn config(command: &str) -> CustomToolConfig {
+        CustomToolConfig {
+            description: "Test tool".to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "name": {"type": "string"},
+                    "flags": {"type": "array"},
+                    "count": {"type": "integer"}
+                },
+                "required": ["name"]
+            }),
+            command: command.to_string(),
+            approval: None,
+            timeout: None,
+            output_limit: None,
+        }
+    }
+
+    fn tool(command: &str) -> CommandTool {
+        CommandTool {
+            name: "test".to_string(),
+            config: config(command),
+        }
+    }
+
+    /// Registers a single tool, returning the error message if it is refused
+    fn register(name: &str, command: &str) -> Result<(), String> {
+        let mut registry = ToolRegistry::with_builtin_tools();
+        let mut policy =
+            ApprovalPolicy::new(&ApprovalConfig::default(), Path::new("/project")).unwrap();
+        let tools = BTreeMap::from([(name.to_string(), config(command))]);
+        register_custom_tools(&mut registry, &mut policy, &tools).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn renders_quoted_arguments() {
+        let tool = tool("cargo test {{name}} --jobs={{ count }} {{flags}}");
+        let args = json!({"name": "it's $(touch pwned)", "count": 4, "flags": ["-q", "a b"]});
+        assert_eq!(
+            tool.render(&args),
+            r#"cargo test 'it'\''s $(touch pwned)' --jobs='4' '-q' 'a b'"#
+        );
+    }
+
+    #[test]
+    fn renders_omitted_optional_arguments_as_nothing() {
+        let tool = tool("run {{name}} {{count}}{{flags}}");
+        assert_eq!(tool.render(&json!({"name": "x"})), "run 'x' ");
+        assert_eq!(
+            tool.render(&json!({"name": "x", "count": null})),
+            "run 'x' "
+        );
+        assert!(tool.validate(&json!({"count": 1})).is_err());
+        assert!(tool.validate(&json!({"name": "x"})).is_ok());
+    }
+
+    #[test]
+    fn refuses_quoted_placeholders() {
+        assert!(register("t", r#"cmd "{{name}}""#)
+            .unwrap_err()
+            .contains("inside quotes"));
+        assert!(register("t", "cmd '--name={{name}}'")
+            .unwrap_err()
+            .contains("inside quotes"));
+        assert!(register("t", r#"echo "done" {{name}} 'x'"#).is_ok());
+        assert!(register("t", r#"echo \"{{name}}"#).is_ok());
+        assert!(register("t", r#"echo "it's" {{name}}"#).is_ok());
+    }
+
+    #[test]
+    fn refuses_unknown_placeholders_and_shadowing() {
+        assert!(register("t", "cmd {{other}}")
+            .unwrap_err()
+            .contains("no such parameter"));
+        assert!(register("read_file", "cat {{name}}")
+            .unwrap_err()
+            .contains("shadows"));
+    }
+}
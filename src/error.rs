@@ -0,0 +1,366 @@
+//! Errors of the chat API clients and the retry policy for transient ones.
+//!
+//! Requests to the server fail with a [`ChatError`] telling apart an
+//! unreachable server, a missing model, an error status and a broken
+//! response. Connection failures and `429`/`5xx` responses (Ollama answers
+//! `503` while a model is loading) are retried with exponential backoff:
+//!
+//! ```yaml
+//! retry:
+//!   max_retries: 3        # retries of a failed request, 0 to disable
+//!   initial_delay: 500    # milliseconds before the first retry, doubled for each one after
+//!   max_delay: 10000      # cap on the delay in milliseconds
+//! ```
+
+use std::{fmt, time::Duration};
+
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+use crate::interrupt::interrupted;
+
+/// Why a request to the server failed
+pub enum ChatError {
+    /// The server couldn't be reached (refused, DNS failure, timeout)
+    Connection { url: String, message: String },
+    /// The server doesn't have the requested model
+    ModelNotFound { model: String },
+    /// The server answered with an error status
+    Status { status: StatusCode, message: String },
+    /// The response couldn't be read or parsed, eg. a stream breaking off
+    InvalidResponse(String),
+    /// The server reported an error in the middle of a streamed response
+    Server(String),
+    /// The user pressed Ctrl-C
+    Interrupted,
+}
+
+impl ChatError {
+    /// Creates the error for a request that didn't reach the server
+    ///
+    /// # Arguments
+    /// * `url` - Base URL of the server
+    /// * `error` - The error of the HTTP client
+    pub fn connection(url: &str, error: reqwest::Error) -> Self {
+        ChatError::Connection {
+            url: url.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    /// Creates the error for an unsuccessful response
+    ///
+    /// Reads the server's message from the body, using the `error` field of a
+    /// JSON body when there is one. A 404 saying the model wasn't found
+    /// becomes [`ChatError::ModelNotFound`].
+    ///
+    /// # Arguments
+    /// * `response` - The unsuccessful response
+    /// * `model` - Model the request was for, if any
+    pub async fn from_response(response: reqwest::Response, model: Option<&str>) -> Self {
+        let status = response.status();
+        let text = response
+            .text()
+            .await
+            .unwrap_or_else(|_| "Unknown error".to_string());
+        let message = serde_json::from_str::<serde_json::Value>(&text)
+            .ok()
+            .and_then(|body| match &body["error"] {
+                serde_json::Value::String(s) => Some(s.clone()),
+                serde_json::Value::Object(o) => o
+                    .get("message")
+                    .and_then(|m| m.as_str())
+                    .map(str::to_string),
+                _ => None,
+            })
+            .unwrap_or(text);
+        match model {
+            Some(model) if status == StatusCode::NOT_FOUND && message.contains("not found") => {
+                ChatError::ModelNotFound {
+                    model: model.to_string(),
+                }
+            }
+            _ => ChatError::Status { status, message },
+        }
+    }
+
+    /// Whether the request may succeed when sent again
+    ///
+    /// True for connection failures, rate limiting and server errors,
+    /// including the 503 Ollama answers while a model is loading.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ChatError::Connection { .. } => true,
+            ChatError::Status { status, .. } => {
+                *status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatError::Connection { url, message } => {
+                write!(f, "Failed to connect to {}: {}", url, message)
+            }
+            ChatError::ModelNotFound { model } => write!(f, "Model '{}' not found", model),
+            ChatError::Status { status, message } => {
+                write!(f, "API Error: Status: {}: {}", status, message)
+            }
+            ChatError::InvalidResponse(message) => write!(f, "Invalid response: {}", message),
+            ChatError::Server(message) => write!(f, "Server error: {}", message),
+            ChatError::Interrupted => write!(f, "Interrupted"),
+        }
+    }
+}
+
+//Errors returned from main are printed with Debug, so it shows the message too
+impl fmt::Debug for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+impl std::error::Error for ChatError {}
+
+/// The `retry` config section
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// How often a failed request is sent again
+    pub max_retries: u32,
+    /// Milliseconds before the first retry, doubled for each one after
+    pub initial_delay: u64,
+    /// Cap on the delay between retries in milliseconds
+    pub max_delay: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_delay: 500,
+            max_delay: 10_000,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Delay before a retry, doubling from `initial_delay` up to `max_delay`
+    ///
+    /// # Arguments
+    /// * `attempt` - Number of retries made so far
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u64.saturating_pow(attempt);
+        Duration::from_millis(
+            self.initial_delay
+                .saturating_mul(factor)
+                .min(self.max_delay),
+        )
+    }
+
+    /// Waits before retrying a failed request
+    ///
+    /// # Arguments
+    /// * `error` - Why the request failed
+    /// * `attempt` - Number of retries made so far
+    ///
+    /// # Returns
+    /// False without waiting if the error isn't transient or the retries are
+    /// used up, and false if the user interrupts the wait
+    async fn backoff(&self, error: &ChatError, attempt: u32) -> bool {
+        if !error.is_transient() || attempt >= self.max_retries {
+            return false;
+        }
+        let delay = self.delay(attempt);
+        eprintln!(
+            "⚠ {} - retrying in {:.1}s ({}/{})",
+            error,
+            delay.as_secs_f64(),
+            attempt + 1,
+            self.max_retries
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => true,
+            _ = interrupted() => false,
+        }
+    }
+
+    /// Sends a request, retrying transient failures with backoff
+    ///
+    /// # Arguments
+    /// * `request` - Sends the request, called again for each retry. It may
+    ///   borrow mutably, eg. the handler a chat response is streamed to.
+    ///
+    /// # Errors
+    /// Returns the last error if the request doesn't succeed
+    pub async fn run<T>(
+        &self,
+        mut request: impl AsyncFnMut() -> Result<T, ChatError>,
+    ) -> Result<T, ChatError> {
+        let mut attempt = 0;
+        loop {
+            let error = match request().await {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            };
+            if !self.backoff(&error, attempt).await {
+                return Err(error);
+            }
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use super::*;
+
+    /// Answers one request with a canned response and returns it
+    async fn response(status: u16, body: &'static str) -> reqwest::Response {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            //Read the request headers before answering
+            let mut request = Vec::new();
+            let mut chunk = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut chunk).unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&chunk[..read]);
+            }
+            write!(
+                stream,
+                "HTTP/1.1 {} Error\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .unwrap();
+        });
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        client.get(url).send().await.unwrap()
+    }
+
+    fn status(status: u16) -> ChatError {
+        ChatError::Status {
+            status: StatusCode::from_u16(status).unwrap(),
+            message: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_error_messages_from_responses() {
+        let cases = [
+            (500, r#"{"error": "out of memory"}"#, "out of memory"),
+            (
+                400,
+                r#"{"error": {"message": "bad request"}}"#,
+                "bad request",
+            ),
+            (502, "upstream down", "upstream down"),
+            (500, r#"{"detail": "other"}"#, r#"{"detail": "other"}"#),
+        ];
+        for (code, body, expected) in cases {
+            match ChatError::from_response(response(code, body).await, None).await {
+                ChatError::Status { status, message } => {
+                    assert_eq!(status.as_u16(), code);
+                    assert_eq!(message, expected);
+                }
+                other => panic!("{} gave {}", body, other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn tells_missing_models_apart() {
+        let body = r#"{"error": "model 'llama9' not found"}"#;
+        let error = ChatError::from_response(response(404, body).await, Some("llama9")).await;
+        assert!(matches!(error, ChatError::ModelNotFound { model } if model == "llama9"));
+        //Without a model, or for other 404s, it stays a status error
+        let error = ChatError::from_response(response(404, body).await, None).await;
+        assert!(matches!(error, ChatError::Status { .. }));
+        let error = ChatError::from_response(response(404, "no route").await, Some("m")).await;
+        assert!(matches!(error, ChatError::Status { .. }));
+    }
+
+    #[test]
+    fn retries_only_transient_errors() {
+        let connection = ChatError::Connection {
+            url: "http://localhost".into(),
+            message: "refused".into(),
+        };
+        assert!(connection.is_transient());
+        assert!(status(429).is_transient());
+        assert!(status(500).is_transient());
+        assert!(status(503).is_transient());
+        assert!(!status(400).is_transient());
+        assert!(!status(401).is_transient());
+        assert!(!status(404).is_transient());
+        assert!(!ChatError::ModelNotFound { model: "m".into() }.is_transient());
+        assert!(!ChatError::InvalidResponse("eof".into()).is_transient());
+        assert!(!ChatError::Server("boom".into()).is_transient());
+        assert!(!ChatError::Interrupted.is_transient());
+    }
+
+    #[test]
+    fn doubles_delays_up_to_the_cap() {
+        let retry = RetryConfig::default();
+        let delays: Vec<u64> = (0..7).map(|a| retry.delay(a).as_millis() as u64).collect();
+        assert_eq!(delays, [500, 1000, 2000, 4000, 8000, 10_000, 10_000]);
+        //Huge attempt counts don't overflow
+        assert_eq!(retry.delay(200), Duration::from_millis(10_000));
+    }
+
+    #[tokio::test]
+    async fn runs_until_success_or_a_permanent_error() {
+        let retry = RetryConfig {
+            max_retries: 3,
+            initial_delay: 1,
+            max_delay: 1,
+        };
+        let mut calls = 0;
+        let result = retry
+            .run(async || {
+                calls += 1;
+                match calls {
+                    1 | 2 => Err(status(503)),
+                    _ => Ok(calls),
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 3);
+
+        let mut calls = 0;
+        let result: Result<(), ChatError> = retry
+            .run(async || {
+                calls += 1;
+                Err(status(400))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+
+        //Retries are used up after max_retries
+        let mut calls = 0;
+        let result: Result<(), ChatError> = retry
+            .run(async || {
+                calls += 1;
+                Err(status(500))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 4);
+    }
+}
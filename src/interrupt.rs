@@ -0,0 +1,57 @@
+//! Ctrl-C handling for interactive chat.
+//!
+//! Once [`install`] is called, Ctrl-C no longer terminates the process.
+//! Instead it raises a process-wide interrupt flag that long running work
+//! checks: streaming responses stop and keep what was received so far, and
+//! running shell commands have their process group killed.
+//!
+//! The flag stays raised until [`clear`] is called, which chat mode does
+//! before starting each new request or batch of tool calls.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tokio::sync::Notify;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static NOTIFY: Notify = Notify::const_new();
+
+/// Starts listening for Ctrl-C
+///
+/// Must be called from within the tokio runtime.
+pub fn install() {
+    tokio::spawn(async {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            trigger();
+        }
+    });
+}
+
+/// Raises the interrupt flag and wakes anything waiting on [`interrupted`]
+pub fn trigger() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    NOTIFY.notify_waiters();
+}
+
+/// Lowers the interrupt flag
+pub fn clear() {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+}
+
+/// Checks whether Ctrl-C was pressed since the flag was last cleared
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Waits until the interrupt flag is raised
+///
+/// Completes immediately if it is already raised. Intended for use in
+/// `tokio::select!` alongside the work that should be cancelled.
+pub async fn interrupted() {
+    let notified = NOTIFY.notified();
+    tokio::pin!(notified);
+    notified.as_mut().enable();
+    if is_interrupted() {
+        return;
+    }
+    notified.await;
+}
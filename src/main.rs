@@ -11,13 +11,22 @@
 //! - **Tool Support**: Execute shell commands, read/write files with AI assistance
 //! - **Session Management**: Save and load conversation sessions
 //! - **Streaming Responses**: Real-time streaming of AI responses
-//! - **Model Management**: List available models and query model capabilities
+//! - **Model Management**: List, pull, copy, create and delete models and query their capabilities
+//! - **Model Cache**: Model information is fetched when first needed and cached on disk
+//! - **Retries**: Transient server failures are retried with backoff, chat sessions survive errors
 //! - **Context Compaction**: Automatically summarize conversation history
+//! - **MCP Servers**: Offer tools from MCP servers launched over stdio
+//! - **Structured Output**: Force JSON responses matching a schema, validated and retried
+//! - **Attachments**: Attach text files and images to messages for vision models
+//! - **Text Tool Calls**: Models without tool support write tool calls that are parsed from the response
 //!
 //! ## Commands
 //!
 //! - `chat`: Start an interactive chat session with an Ollama model
 //! - `list`: List all available models on the Ollama instance
+//! - `models`: Pull, delete, copy, show, create and list loaded models
+//! - `run`: Send a single prompt non-interactively and print the response
+//! - `sessions`: List, show, rename, delete and export saved sessions
 //!
 //! ## Configuration
 //!
@@ -35,21 +44,58 @@
 //!
 //! # List available models
 //! chatto list --url http://localhost:11434
+//!
+//! # Download a model
+//! chatto models pull qwen3:8b
+//!
+//! # One-shot prompt with piped input
+//! git diff | chatto run "Write a commit message for this diff"
 //! ```
 
 use clap::{Parser, Subcommand};
 use config::Config;
 use reqwest::Client;
 use serde::Deserialize;
-use std::path::Path;
+use std::{collections::BTreeMap, path::Path};
 
+use crate::app::CompactionConfig;
+use crate::approval::ApprovalConfig;
+use crate::backend::{Backend, BackendKind, ChatBackend};
 use crate::chat::chat_mode;
-use crate::ollama::{list_models, show_model, OllamaModel};
+use crate::custom_tools::CustomToolConfig;
+use crate::error::RetryConfig;
+use crate::mcp::McpServerConfig;
+use crate::model_cache::{ModelCache, ModelCacheConfig};
+use crate::models::{list_command, models_command, ModelsCommand};
+use crate::ollama::OllamaModel;
+use crate::output_format::StructuredOutputConfig;
+use crate::run::run_mode;
+use crate::session::{sessions_command, SessionConfig, SessionsCommand};
+use crate::shell::ShellConfig;
+use crate::tool_parser::ToolParsingConfig;
 use crate::tools::OutputLimit;
 
 pub mod app;
+pub mod approval;
+pub mod attach;
+pub mod backend;
 pub mod chat;
+pub mod checkpoint;
+pub mod custom_tools;
+pub mod error;
+pub mod interrupt;
+pub mod mcp;
+pub mod model_cache;
+pub mod models;
 pub mod ollama;
+pub mod openai;
+pub mod output_format;
+pub mod patch;
+pub mod process;
+pub mod run;
+pub mod session;
+pub mod shell;
+pub mod tool_parser;
 pub mod tools;
 
 /// CLI argument structure for the chatto command
@@ -60,12 +106,18 @@ struct Cli {
     /// The base url for the ollama instance eg. http://192.168.0.1:11434
     #[arg(short, long)]
     url: Option<String>,
+    /// The kind of API the url speaks: ollama or openai (OpenAI-compatible)
+    #[arg(short, long)]
+    backend: Option<String>,
     /// The model to be used on the ollama instance eg. gemma3:12b or llama2
     #[arg(short, long)]
     model: Option<String>,
     /// API key for authentication (can also be set via OLLAMA_API_KEY env var)
     #[arg(short, long, env = "OLLAMA_API_KEY")]
     key: Option<String>,
+    /// Fetch model information from the server instead of using the model cache
+    #[arg(long)]
+    refresh_models: bool,
     #[command(subcommand)]
     command: Commands,
 }
@@ -74,16 +126,47 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// List all available models on the Ollama instance
-    List,
+    List {
+        /// Print the models with all their details as JSON
+        #[arg(long)]
+        json: bool,
+    },
     /// Start an interactive chat session with context management and tool support
     Chat {
         /// Disable streaming responses (receive complete response at once)
         #[arg(short, long)]
         disable_streaming: bool,
-        /// Load or create a named session (saves to .chatto-{session}.session.yaml)
+        /// Load or create a named session in the session store
         #[arg(short, long)]
         session: Option<String>,
     },
+    /// Send a single prompt (argument and/or stdin) and print the final response
+    Run {
+        /// The prompt to send, piped stdin is appended to it
+        prompt: Option<String>,
+        /// Load or create a named session in the session store
+        #[arg(short, long)]
+        session: Option<String>,
+        /// Approve all tool calls without asking (otherwise they are rejected)
+        #[arg(short = 'y', long)]
+        approve_tools: bool,
+        /// Maximum number of requests to send to the model before giving up
+        #[arg(long, default_value_t = 20)]
+        max_turns: usize,
+        /// JSON schema file the final response has to match
+        #[arg(long)]
+        schema: Option<String>,
+    },
+    /// Manage the models of the Ollama instance
+    Models {
+        #[command(subcommand)]
+        command: ModelsCommand,
+    },
+    /// Manage saved sessions
+    Sessions {
+        #[command(subcommand)]
+        command: SessionsCommand,
+    },
 }
 
 /// Application configuration loaded from config files and CLI arguments
@@ -91,6 +174,9 @@ enum Commands {
 pub struct ApplicationConfig {
     /// Base URL of the Ollama instance
     url: String,
+    /// Kind of API spoken by the url
+    #[serde(default)]
+    backend: BackendKind,
     /// API key for authentication
     api_key: String,
     /// Default model to use for chat
@@ -101,6 +187,39 @@ pub struct ApplicationConfig {
     output_limit: OutputLimit,
     /// Cached model information
     models: Vec<OllamaModel>,
+    /// Tool approval policy
+    #[serde(default)]
+    approval: ApprovalConfig,
+    /// Automatic context compaction
+    #[serde(default)]
+    compaction: CompactionConfig,
+    /// Session store location
+    #[serde(default)]
+    sessions: SessionConfig,
+    /// Shell command timeouts, root, environment and sandbox
+    #[serde(default)]
+    shell: ShellConfig,
+    /// Command template tools declared in the configuration, keyed by name
+    #[serde(default)]
+    tools: BTreeMap<String, CustomToolConfig>,
+    /// MCP servers whose tools are offered to the model, keyed by name
+    #[serde(default)]
+    mcp_servers: BTreeMap<String, McpServerConfig>,
+    /// How tool calls are read from models without tool support
+    #[serde(default)]
+    tool_parsing: ToolParsingConfig,
+    /// Retries for responses that don't match the requested format
+    #[serde(default)]
+    structured_output: StructuredOutputConfig,
+    /// Retries of requests failing with transient errors
+    #[serde(default)]
+    retry: RetryConfig,
+    /// How long fetched model information is cached
+    #[serde(default)]
+    model_cache: ModelCacheConfig,
+    /// Model information cached across runs
+    #[serde(skip)]
+    cache: ModelCache,
 }
 
 impl ApplicationConfig {
@@ -118,6 +237,86 @@ impl ApplicationConfig {
             .map(|v| v as _)
     }
 
+    /// Makes a model's information available to `get_model`
+    ///
+    /// Uses the model cache when it holds an entry within its TTL, without
+    /// asking the server, so a cached model also starts offline. Otherwise
+    /// the information is fetched from the server and cached with the
+    /// model's current digest. `--refresh-models` and `chatto models
+    /// pull/rm/cp/create` drop entries so they are fetched again.
+    ///
+    /// # Arguments
+    /// * `client` - HTTP client for API requests
+    /// * `model` - The model name to load
+    ///
+    /// # Errors
+    /// Returns error if the model isn't cached and can't be fetched
+    pub async fn load_model(
+        &mut self,
+        client: &Client,
+        model: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let info = match self.cache.get(&self.url, model, None) {
+            Some(info) => info.clone(),
+            None => {
+                let backend = self.backend();
+                let digest = backend.model_digest(client, model).await?;
+                let info = backend.show_model(client, model).await?;
+                self.cache
+                    .insert(&self.url, &info, digest.as_deref().unwrap_or_default());
+                self.cache.save();
+                info
+            }
+        };
+        self.merge_model(info);
+        Ok(())
+    }
+
+    /// Creates the chat backend for the configured endpoint
+    ///
+    /// # Returns
+    /// Backend speaking the configured API at the configured url
+    pub fn backend(&self) -> Backend {
+        Backend::new(self.backend, &self.url, &self.api_key, &self.retry)
+    }
+
+    /// Checks whether a model has the given capability (eg. tools, thinking)
+    ///
+    /// # Arguments
+    /// * `model` - The model name to look up
+    /// * `capability` - The capability to check for
+    ///
+    /// # Returns
+    /// True if the model is known and lists the capability
+    pub fn has_capability(&self, model: &str, capability: &str) -> bool {
+        self.get_model(model)
+            .is_some_and(|m| m.capabilities.iter().any(|c| c == capability))
+    }
+
+    /// Determines the context window size for a model
+    ///
+    /// Uses the context length the server reported for the model, capped by
+    /// the model's configured `num_ctx` if set. Falls back to `num_ctx`
+    /// alone, and to 4096 tokens when neither is known.
+    ///
+    /// # Arguments
+    /// * `model` - The model name to look up
+    ///
+    /// # Returns
+    /// Maximum number of tokens in the model's context
+    pub fn max_context(&self, model: &str) -> u64 {
+        let model = self.get_model(model);
+        let num_ctx = model
+            .and_then(|m| m.options.as_ref())
+            .and_then(|o| o.num_ctx);
+        match (model.and_then(|m| m.get_context_length()), num_ctx) {
+            (Some(length), Some(num_ctx)) => length.min(num_ctx),
+            (Some(length), None) => length,
+            (None, Some(num_ctx)) => num_ctx,
+            (None, None) => 4096,
+        }
+    }
+
     /// Merges model configuration into the cached list
     ///
     /// If the model already exists, updates its capabilities and info.
@@ -150,7 +349,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     //2. Model Config
     //3. Agent Config -> Might have model config in it
     //4. Prompt Config
-    //5. MCP/Tools Config
+    //5. MCP/Tools Config (mcp_servers, tools)
 
     //Check for config
     let config_builder = Config::builder()
@@ -168,37 +367,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .required(false),
         )
         .set_override_option("url", cli.url)?
+        .set_override_option("backend", cli.backend)?
         .set_override_option("api_key", cli.key)?
         .set_override_option("model", cli.model)?;
 
     let client = Client::new();
     let mut app_config: ApplicationConfig = config_builder.build()?.try_deserialize()?;
-    //Pull down the model config from the server
-    let model_info = show_model(
-        &client,
-        &app_config.url,
-        &app_config.api_key,
-        &app_config.model,
-    )
-    .await?;
-    app_config.merge_model(model_info);
+    //Model information is fetched lazily by the commands that need it
+    app_config.cache =
+        ModelCache::load(&app_config.model_cache, &app_config.url, cli.refresh_models);
 
     match cli.command {
-        Commands::List => {
-            let models = list_models(&client, &app_config.url, &app_config.api_key).await?;
-            println!("Available Models:");
-            for model in models {
-                let model_detail =
-                    show_model(&client, &app_config.url, &app_config.api_key, &model.name).await?;
-                println!(
-                    "- {} ctx: {:?}",
-                    model.name,
-                    model_detail.get_context_length()
-                );
-                for c in model_detail.capabilities {
-                    println!("  - {}", c);
-                }
-            }
+        Commands::List { json } => {
+            list_command(&client, &mut app_config, json).await?;
+        }
+        Commands::Models { command } => {
+            models_command(&client, &mut app_config, command).await?;
         }
         Commands::Chat {
             disable_streaming,
@@ -209,6 +393,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
             chat_mode(&client, app_config, session).await?;
         }
+        Commands::Run {
+            prompt,
+            session,
+            approve_tools,
+            max_turns,
+            schema,
+        } => {
+            let exit_code = run_mode(
+                &client,
+                app_config,
+                prompt,
+                session,
+                approve_tools,
+                max_turns,
+                schema,
+            )
+            .await?;
+            if exit_code != 0 {
+                std::process::exit(exit_code);
+            }
+        }
+        Commands::Sessions { command } => {
+            sessions_command(&app_config.sessions, command)?;
+        }
     }
 
     Ok(())
@@ -0,0 +1,545 @@
+//! Client for MCP (Model Context Protocol) tool servers.
+//!
+//! Servers declared in the `mcp_servers` config section are launched when a
+//! chat starts and spoken to over stdio with newline delimited JSON-RPC:
+//!
+//! ```yaml
+//! mcp_servers:
+//!   github:
+//!     command: github-mcp-server
+//!     args: [stdio]
+//!     env:
+//!       GITHUB_TOKEN: ghp_...
+//!     timeout: 60       # seconds to wait for a response
+//! ```
+//!
+//! After the `initialize` handshake the server's `tools/list` is registered
+//! in the [`ToolRegistry`] with the server name as prefix (`github__create_issue`),
+//! so the tools are offered to the model and go through the same approval
+//! flow as the built-in tools. Calls are forwarded with `tools/call`.
+//! Server and tool names have to match `[a-zA-Z0-9_-]+`, and tools whose
+//! name is already taken are skipped rather than replacing the other tool.
+//! Servers get the environment the `shell.env` rules pass to commands plus
+//! their own `env`, and are stopped when the registry is dropped.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError},
+        Arc, LazyLock, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    approval::ApprovalSubject,
+    interrupt,
+    shell::ShellEnvironment,
+    tools::{trim_output, Tool, ToolContext, ToolRegistry},
+};
+
+/// MCP protocol version requested in the handshake
+const PROTOCOL_VERSION: &str = "2024-11-05";
+/// Separator between the server name and the tool name in registered tools
+const TOOL_PREFIX_SEPARATOR: &str = "__";
+
+/// Names servers and tools may have, as accepted by the chat APIs
+static VALID_NAME: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[a-zA-Z0-9_-]+$").unwrap());
+
+/// An MCP server from the `mcp_servers` config section
+#[derive(Debug, Deserialize, Clone)]
+pub struct McpServerConfig {
+    /// Program starting the server
+    pub command: String,
+    /// Arguments for the program
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Extra environment variables for the server, on top of the ones the
+    /// `shell.env` rules pass on
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Working directory of the server, defaults to the current directory
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+    /// Seconds to wait for a response from the server
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+/// Default seconds to wait for a server response
+fn default_timeout() -> u64 {
+    60
+}
+
+/// A tool advertised by a server's `tools/list`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct McpToolDefinition {
+    name: String,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    input_schema: Option<Value>,
+}
+
+/// A running MCP server and the JSON-RPC connection to it
+pub struct McpClient {
+    /// Name of the server from the configuration
+    name: String,
+    child: Mutex<Child>,
+    stdin: Arc<Mutex<ChildStdin>>,
+    /// Responses from the server, read on a background thread
+    responses: Mutex<Receiver<Value>>,
+    next_id: AtomicU64,
+    timeout: Duration,
+}
+
+impl McpClient {
+    /// Launches a server and performs the `initialize` handshake
+    ///
+    /// # Arguments
+    /// * `name` - Name of the server from the configuration
+    /// * `config` - How to launch the server
+    /// * `shell` - Shell environment whose env rules filter the server's environment
+    ///
+    /// # Returns
+    /// The connected client
+    ///
+    /// # Errors
+    /// Returns an error message if the server can't be started or the
+    /// handshake fails
+    pub fn start(
+        name: &str,
+        config: &McpServerConfig,
+        shell: &ShellEnvironment,
+    ) -> Result<Self, String> {
+        let mut command = Command::new(&config.command);
+        command
+            .args(&config.args)
+            .env_clear()
+            .envs(shell.env())
+            .envs(&config.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            //Servers log to stderr, which would garble the chat
+            .stderr(Stdio::null());
+        if let Some(cwd) = &config.cwd {
+            command.current_dir(cwd);
+        }
+        let mut child = command
+            .spawn()
+            .map_err(|e| format!("can't start '{}': {}", config.command, e))?;
+        let stdin = Arc::new(Mutex::new(child.stdin.take().ok_or("no stdin")?));
+        let stdout = child.stdout.take().ok_or("no stdout")?;
+
+        let (sender, responses) = mpsc::channel();
+        let reply_to = stdin.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                let Ok(message) = serde_json::from_str::<Value>(&line) else {
+                    continue;
+                };
+                match (message.get("id"), message.get("method")) {
+                    //A request from the server, none of which chatto supports
+                    (Some(id), Some(_)) => {
+                        let reply = serde_json::json!({
+                            "jsonrpc": "2.0",
+                            "id": id,
+                            "error": { "code": -32601, "message": "Method not found" }
+                        });
+                        write_message(&reply_to, &reply).unwrap_or_default();
+                    }
+                    //The client stops the server once it's gone, ending this loop
+                    (Some(_), None) => sender.send(message).unwrap_or_default(),
+                    //Notifications (logging, progress, list changes) are ignored
+                    _ => {}
+                }
+            }
+        });
+
+        let client = Self {
+            name: name.to_string(),
+            child: Mutex::new(child),
+            stdin,
+            responses: Mutex::new(responses),
+            next_id: AtomicU64::new(1),
+            timeout: Duration::from_secs(config.timeout),
+        };
+        client.request(
+            "initialize",
+            serde_json::json!({
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": {},
+                "clientInfo": { "name": "chatto", "version": env!("CARGO_PKG_VERSION") }
+            }),
+        )?;
+        client.notify("notifications/initialized", serde_json::json!({}))?;
+        Ok(client)
+    }
+
+    /// Fetches every tool the server offers, following pagination
+    fn list_tools(&self) -> Result<Vec<McpToolDefinition>, String> {
+        let mut tools = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(cursor) => serde_json::json!({ "cursor": cursor }),
+                None => serde_json::json!({}),
+            };
+            let result = self.request("tools/list", params)?;
+            let page: Vec<McpToolDefinition> =
+                serde_json::from_value(result.get("tools").cloned().unwrap_or_default())
+                    .map_err(|e| format!("invalid tools/list response: {}", e))?;
+            tools.extend(page);
+            cursor = result
+                .get("nextCursor")
+                .and_then(Value::as_str)
+                .map(String::from);
+            if cursor.is_none() {
+                return Ok(tools);
+            }
+        }
+    }
+
+    /// Calls a tool on the server
+    ///
+    /// # Arguments
+    /// * `tool` - Name of the tool on the server, without the prefix
+    /// * `arguments` - Arguments from the model's tool call
+    ///
+    /// # Returns
+    /// The text content of the result, prefixed with `Error:` if the server
+    /// reported the call as failed
+    ///
+    /// # Errors
+    /// Returns an error message if the request fails
+    fn call_tool(&self, tool: &str, arguments: &Value) -> Result<String, String> {
+        let arguments = match arguments {
+            Value::Null => serde_json::json!({}),
+            v => v.clone(),
+        };
+        let result = self.request(
+            "tools/call",
+            serde_json::json!({ "name": tool, "arguments": arguments }),
+        )?;
+        let content: Vec<String> = result
+            .get("content")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .map(|item| match item.get("type").and_then(Value::as_str) {
+                Some("text") => item["text"].as_str().unwrap_or_default().to_string(),
+                Some("resource") => match item["resource"]["text"].as_str() {
+                    Some(text) => text.to_string(),
+                    None => format!("[resource {}]", item["resource"]["uri"]),
+                },
+                Some(kind) => format!(
+                    "[{} content: {}]",
+                    kind,
+                    item["mimeType"].as_str().unwrap_or("unknown type")
+                ),
+                None => item.to_string(),
+            })
+            .collect();
+        let mut text = content.join("\n");
+        if text.is_empty() {
+            if let Some(structured) = result.get("structuredContent") {
+                text = structured.to_string();
+            }
+        }
+        match result.get("isError").and_then(Value::as_bool) {
+            Some(true) => Ok(format!("Error: {}", text)),
+            _ => Ok(text),
+        }
+    }
+
+    /// Sends a request and waits for its response
+    ///
+    /// Waiting stops early if Ctrl-C is pressed, in which case the server is
+    /// told the request was cancelled.
+    ///
+    /// # Arguments
+    /// * `method` - JSON-RPC method
+    /// * `params` - Parameters of the request
+    ///
+    /// # Returns
+    /// The `result` of the response
+    ///
+    /// # Errors
+    /// Returns an error message for error responses, timeouts and
+    /// interruptions, or if the server has gone away
+    fn request(&self, method: &str, params: Value) -> Result<Value, String> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params
+        });
+        write_message(&self.stdin, &request)
+            .map_err(|e| format!("MCP server '{}' is not running: {}", self.name, e))?;
+
+        let responses = self.responses.lock().unwrap();
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            if interrupt::is_interrupted() {
+                self.notify(
+                    "notifications/cancelled",
+                    serde_json::json!({ "requestId": id, "reason": "Interrupted by the user" }),
+                )
+                .unwrap_or_default();
+                return Err(format!("{} interrupted by the user (Ctrl-C)", method));
+            }
+            if Instant::now() >= deadline {
+                return Err(format!(
+                    "MCP server '{}' didn't answer {} within {}s",
+                    self.name,
+                    method,
+                    self.timeout.as_secs()
+                ));
+            }
+            let response = match responses.recv_timeout(Duration::from_millis(50)) {
+                Ok(response) => response,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(format!("MCP server '{}' exited", self.name))
+                }
+            };
+            //Responses to earlier, abandoned requests are skipped
+            if response["id"].as_u64() != Some(id) {
+                continue;
+            }
+            if let Some(error) = response.get("error") {
+                return Err(format!(
+                    "MCP server '{}' returned an error for {}: {}",
+                    self.name,
+                    method,
+                    error["message"].as_str().unwrap_or(&error.to_string())
+                ));
+            }
+            return Ok(response.get("result").cloned().unwrap_or_default());
+        }
+    }
+
+    /// Sends a notification, which has no response
+    fn notify(&self, method: &str, params: Value) -> Result<(), String> {
+        let notification = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params
+        });
+        write_message(&self.stdin, &notification)
+            .map_err(|e| format!("MCP server '{}' is not running: {}", self.name, e))
+    }
+}
+
+impl Drop for McpClient {
+    fn drop(&mut self) {
+        let child = self.child.get_mut().unwrap();
+        child.kill().unwrap_or_default();
+        child.wait().unwrap_or_default();
+    }
+}
+
+/// Writes a JSON-RPC message as a single line
+fn write_message(stdin: &Mutex<ChildStdin>, message: &Value) -> std::io::Result<()> {
+    let mut stdin = stdin.lock().unwrap();
+    writeln!(stdin, "{}", message)?;
+    stdin.flush()
+}
+
+/// A tool offered by an MCP server
+pub struct McpTool {
+    client: Arc<McpClient>,
+    /// Name exposed to the model, `<server>__<tool>`
+    name: String,
+    /// Name of the tool on the server
+    tool: String,
+    description: String,
+    parameters: Value,
+}
+
+impl Tool for McpTool {
+    type Args = Value;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn parameters(&self) -> Value {
+        self.parameters.clone()
+    }
+
+    fn preview(&self, args: &Self::Args) {
+        eprintln!(
+            "🛠️  MCP Tool Requested!\n ● Server: {}\n ● Tool: {}",
+            self.client.name, self.tool
+        );
+        let args = serde_json::to_string_pretty(args).unwrap_or_default();
+        eprintln!(" ● Arguments: {}", args);
+    }
+
+    fn approval_subject(&self, _args: &Self::Args) -> ApprovalSubject {
+        ApprovalSubject::None
+    }
+
+    fn execute(&self, args: Self::Args, context: &ToolContext) -> String {
+        match self.client.call_tool(&self.tool, &args) {
+            Ok(result) => {
+                eprintln!(" ✓ {} returned {} bytes", self.name, result.len());
+                trim_output(&result, context.output_limit)
+            }
+            Err(e) => {
+                eprintln!("❌ {}", e);
+                format!("Error: {}", e)
+            }
+        }
+    }
+}
+
+/// Starts the configured MCP servers and registers their tools
+///
+/// A server that fails to start is reported and skipped, so one broken
+/// server doesn't prevent chatting. So are tools with invalid names or
+/// names that are already registered.
+///
+/// # Arguments
+/// * `registry` - Registry to add the servers' tools to
+/// * `servers` - The `mcp_servers` config section
+/// * `shell` - Shell environment whose env rules filter the servers' environment
+pub fn register_servers(
+    registry: &mut ToolRegistry,
+    servers: &BTreeMap<String, McpServerConfig>,
+    shell: &ShellEnvironment,
+) {
+    for (name, config) in servers {
+        if !VALID_NAME.is_match(name) {
+            eprintln!(
+                "⚠️  MCP server '{}' skipped: names may only contain letters, digits, '_' and '-'",
+                name
+            );
+            continue;
+        }
+        let tools = McpClient::start(name, config, shell).and_then(|client| {
+            let tools = client.list_tools()?;
+            Ok((Arc::new(client), tools))
+        });
+        let (client, tools) = match tools {
+            Ok(tools) => tools,
+            Err(e) => {
+                eprintln!("⚠️  MCP server '{}' unavailable: {}", name, e);
+                continue;
+            }
+        };
+        let mut registered = 0;
+        for tool in tools {
+            let tool_name = match qualified_name(registry, name, &tool.name) {
+                Ok(tool_name) => tool_name,
+                Err(e) => {
+                    eprintln!("⚠️  MCP server '{}': {}", name, e);
+                    continue;
+                }
+            };
+            registry.register(McpTool {
+                client: client.clone(),
+                name: tool_name,
+                description: tool.description.unwrap_or_default(),
+                parameters: tool
+                    .input_schema
+                    .unwrap_or_else(|| serde_json::json!({ "type": "object", "properties": {} })),
+                tool: tool.name,
+            });
+            registered += 1;
+        }
+        eprintln!("MCP server '{}': {} tools", name, registered);
+    }
+}
+
+/// Builds the name a server's tool is registered under
+///
+/// # Arguments
+/// * `registry` - Registry holding the tools registered so far
+/// * `server` - Name of the server
+/// * `tool` - Name of the tool on the server
+///
+/// # Returns
+/// `<server>__<tool>`
+///
+/// # Errors
+/// Returns an error message if the tool's name is invalid or the name is
+/// already taken by another tool
+fn qualified_name(registry: &ToolRegistry, server: &str, tool: &str) -> Result<String, String> {
+    if !VALID_NAME.is_match(tool) {
+        return Err(format!(
+            "tool '{}' skipped, names may only contain letters, digits, '_' and '-'",
+            tool
+        ));
+    }
+    let name = format!("{}{}{}", server, TOOL_PREFIX_SEPARATOR, tool);
+    if registry.get(&name).is_some() {
+        return Err(format!(
+            "tool '{}' skipped, '{}' is already registered",
+            tool, name
+        ));
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::{
+        approval::{ApprovalConfig, ApprovalPolicy},
+        custom_tools::register_custom_tools,
+    };
+
+    #[test]
+    fn qualifies_tool_names() {
+        let registry = ToolRegistry::with_builtin_tools();
+        assert_eq!(
+            qualified_name(&registry, "github", "create_issue").as_deref(),
+            Ok("github__create_issue")
+        );
+        assert_eq!(
+            qualified_name(&registry, "fs", "read-file2").as_deref(),
+            Ok("fs__read-file2")
+        );
+        for invalid in ["", "read file", "tool\"name", "a/b", "café"] {
+            let error = qualified_name(&registry, "fs", invalid).unwrap_err();
+            assert!(error.contains("names may only contain"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn refuses_names_already_taken() {
+        let mut registry = ToolRegistry::with_builtin_tools();
+        let mut policy =
+            ApprovalPolicy::new(&ApprovalConfig::default(), Path::new("/project")).unwrap();
+        let config = serde_json::from_value(serde_json::json!({
+            "description": "Custom tool",
+            "command": "true",
+        }))
+        .unwrap();
+        let tools = BTreeMap::from([("github__create_issue".to_string(), config)]);
+        register_custom_tools(&mut registry, &mut policy, &tools).unwrap();
+        let error = qualified_name(&registry, "github", "create_issue").unwrap_err();
+        assert!(error.contains("already registered"), "{}", error);
+        assert!(qualified_name(&registry, "github", "list_issues").is_ok());
+    }
+}
@@ -0,0 +1,253 @@
+//! On-disk cache of model information.
+//!
+//! The capabilities and context length of a model come from `/api/show`.
+//! Instead of asking the server on every start, the information is fetched
+//! the first time a model is used and kept in
+//! `$XDG_CACHE_HOME/chatto/models.json` (or `~/.cache/chatto/models.json`),
+//! keyed by server url, model name and digest. Entries older than the TTL are
+//! fetched again, and `--refresh-models` ignores the cache for one run:
+//!
+//! ```yaml
+//! model_cache:
+//!   ttl: 86400          # seconds an entry is used, 0 disables the cache
+//! ```
+//!
+//! A cached entry is used without asking the server until its TTL expires.
+//! The digest of a model is only known from the model list, so it is looked
+//! up when an entry is fetched, and `chatto list` replaces entries whose
+//! digest no longer matches. `chatto models pull/rm/cp/create` drop the
+//! entries of the models they change.
+
+use std::{env, fs, path::PathBuf};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::ollama::OllamaModel;
+
+/// The `model_cache` config section
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ModelCacheConfig {
+    /// Seconds a cached entry is used before it is fetched again, 0 to disable the cache
+    pub ttl: u64,
+}
+
+impl Default for ModelCacheConfig {
+    fn default() -> Self {
+        Self { ttl: 24 * 60 * 60 }
+    }
+}
+
+/// Cached information about one model
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct CacheEntry {
+    /// Server the model was fetched from
+    url: String,
+    /// Name of the model
+    model: String,
+    /// Digest of the model, empty if it wasn't known when fetching
+    #[serde(default)]
+    digest: String,
+    /// When the information was fetched
+    fetched_at: DateTime<Utc>,
+    /// The model information returned by the server
+    info: OllamaModel,
+}
+
+/// Model information cached across runs
+#[derive(Debug, Default)]
+pub struct ModelCache {
+    /// File the cache is stored in, None when the cache is disabled
+    path: Option<PathBuf>,
+    /// How long entries are used
+    ttl: Duration,
+    /// The cached models
+    entries: Vec<CacheEntry>,
+}
+
+impl ModelCache {
+    /// Loads the cache from disk
+    ///
+    /// A missing or unreadable cache file gives an empty cache.
+    ///
+    /// # Arguments
+    /// * `config` - The `model_cache` config section
+    /// * `url` - Server url whose entries are dropped when refreshing
+    /// * `refresh` - Ignore the cached entries of the server, fetching them again
+    pub fn load(config: &ModelCacheConfig, url: &str, refresh: bool) -> Self {
+        match cache_path() {
+            Some(path) => Self::load_from(path, config, url, refresh),
+            None => Self::default(),
+        }
+    }
+
+    /// Loads the cache from a file, see [`ModelCache::load`]
+    fn load_from(path: PathBuf, config: &ModelCacheConfig, url: &str, refresh: bool) -> Self {
+        if config.ttl == 0 {
+            return Self::default();
+        }
+        let mut entries: Vec<CacheEntry> = fs::read_to_string(&path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default();
+        if refresh {
+            entries.retain(|e| e.url != url);
+        }
+        Self {
+            path: Some(path),
+            ttl: Duration::seconds(config.ttl.min(i64::MAX as u64) as i64),
+            entries,
+        }
+    }
+
+    /// Looks up a model that was fetched within the TTL
+    ///
+    /// # Arguments
+    /// * `url` - Server url
+    /// * `model` - Name of the model
+    /// * `digest` - Digest the entry has to match, None to accept any
+    pub fn get(&self, url: &str, model: &str, digest: Option<&str>) -> Option<&OllamaModel> {
+        let expiry = Utc::now() - self.ttl;
+        self.entries
+            .iter()
+            .find(|e| {
+                e.url == url
+                    && e.model == model
+                    && digest.is_none_or(|d| d == e.digest)
+                    && e.fetched_at > expiry
+            })
+            .map(|e| &e.info)
+    }
+
+    /// Stores freshly fetched model information, replacing older entries
+    ///
+    /// # Arguments
+    /// * `url` - Server url
+    /// * `info` - The model information
+    /// * `digest` - Digest of the model, empty if unknown
+    pub fn insert(&mut self, url: &str, info: &OllamaModel, digest: &str) {
+        self.remove(url, &info.name);
+        self.entries.push(CacheEntry {
+            url: url.to_string(),
+            model: info.name.clone(),
+            digest: digest.to_string(),
+            fetched_at: Utc::now(),
+            info: info.clone(),
+        });
+    }
+
+    /// Forgets a model, eg. after it was pulled again or deleted
+    pub fn remove(&mut self, url: &str, model: &str) {
+        self.entries.retain(|e| e.url != url || e.model != model);
+    }
+
+    /// Writes the cache to disk
+    ///
+    /// Failing to write only means the information is fetched again next
+    /// time, so errors are ignored.
+    pub fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let expiry = Utc::now() - self.ttl;
+        let entries: Vec<&CacheEntry> = self
+            .entries
+            .iter()
+            .filter(|e| e.fetched_at > expiry)
+            .collect();
+        let Ok(text) = serde_json::to_string(&entries) else {
+            return;
+        };
+        //Write to a temporary file first so a concurrent run never reads half a cache
+        let tmp = path.with_extension(format!("json.{}", std::process::id()));
+        let written = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&tmp, text))
+            .and_then(|_| fs::rename(&tmp, path));
+        if written.is_err() {
+            fs::remove_file(&tmp).unwrap_or_default();
+        }
+    }
+}
+
+/// Location of the cache file following the XDG base directory spec
+fn cache_path() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| dir::home_dir().map(|h| h.join(".cache")))
+        .map(|d| d.join("chatto/models.json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const URL: &str = "http://localhost:11434";
+
+    fn model(name: &str) -> OllamaModel {
+        serde_json::from_value(json!({"name": name, "capabilities": ["completion"]})).unwrap()
+    }
+
+    fn cache(ttl: i64) -> ModelCache {
+        ModelCache {
+            path: None,
+            ttl: Duration::seconds(ttl),
+            entries: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn hits_fresh_entries() {
+        let mut cache = cache(60);
+        cache.insert(URL, &model("llama3.2"), "sha1");
+        assert!(cache.get(URL, "llama3.2", None).is_some());
+        assert!(cache.get(URL, "llama3.2", Some("sha1")).is_some());
+    }
+
+    #[test]
+    fn misses_other_models_servers_and_expired_entries() {
+        let mut cache = cache(60);
+        cache.insert(URL, &model("llama3.2"), "sha1");
+        assert!(cache.get(URL, "qwen3", None).is_none());
+        assert!(cache.get("http://other:11434", "llama3.2", None).is_none());
+        cache.entries[0].fetched_at = Utc::now() - Duration::seconds(61);
+        assert!(cache.get(URL, "llama3.2", None).is_none());
+    }
+
+    #[test]
+    fn invalidates_entries() {
+        let mut cache = cache(60);
+        cache.insert(URL, &model("llama3.2"), "sha1");
+        //A model pulled again has a new digest
+        assert!(cache.get(URL, "llama3.2", Some("sha2")).is_none());
+        cache.insert(URL, &model("llama3.2"), "sha2");
+        assert_eq!(cache.entries.len(), 1);
+        assert!(cache.get(URL, "llama3.2", Some("sha2")).is_some());
+        cache.remove(URL, "llama3.2");
+        assert!(cache.get(URL, "llama3.2", None).is_none());
+    }
+
+    #[test]
+    fn refresh_drops_the_servers_entries_on_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("models.json");
+        let config = ModelCacheConfig::default();
+        let mut cache = ModelCache::load_from(path.clone(), &config, URL, false);
+        cache.insert(URL, &model("llama3.2"), "sha1");
+        cache.insert("http://other:11434", &model("qwen3"), "sha3");
+        cache.save();
+
+        let loaded = ModelCache::load_from(path.clone(), &config, URL, false);
+        assert!(loaded.get(URL, "llama3.2", Some("sha1")).is_some());
+        let refreshed = ModelCache::load_from(path.clone(), &config, URL, true);
+        assert!(refreshed.get(URL, "llama3.2", None).is_none());
+        assert!(refreshed.get("http://other:11434", "qwen3", None).is_some());
+        let disabled = ModelCache::load_from(path, &ModelCacheConfig { ttl: 0 }, URL, false);
+        assert!(disabled.get(URL, "llama3.2", None).is_none());
+    }
+}
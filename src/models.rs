@@ -0,0 +1,363 @@
+//! Model listing and the `chatto models` subcommand.
+//!
+//! `chatto list` prints the available models with their context length and
+//! capabilities, fetching the details of all uncached models concurrently. The
+//! `models` subcommand manages the models of an Ollama server:
+//!
+//! - `pull <model>` - Download a model, with a progress bar
+//! - `rm <model>...` - Delete models
+//! - `cp <source> <destination>` - Copy a model under a new name
+//! - `show <model>` - Print a model's details, capabilities and parameters
+//! - `ps` - List the models loaded into memory
+//! - `create <name> --from <model>` - Create a model with its own system
+//!   prompt, template, parameters or quantization
+//!
+//! `list`, `show` and `ps` print JSON with `--json`.
+
+use std::{error::Error, io::Write};
+
+use clap::Subcommand;
+use futures::{stream, StreamExt};
+use reqwest::Client;
+use serde_json::{Map, Value};
+
+use crate::{
+    backend::{BackendKind, ChatBackend},
+    ollama::{
+        copy_model, create_model, delete_model, pull_model, running_models, server_version,
+        OllamaCreateRequest, OllamaModel, OllamaProgress,
+    },
+    tools::format_size,
+    ApplicationConfig,
+};
+
+/// How many model details are fetched at the same time
+const MAX_CONCURRENT_REQUESTS: usize = 8;
+/// Width of the download progress bar in characters
+const BAR_WIDTH: usize = 30;
+
+/// Actions of the `chatto models` subcommand
+#[derive(Subcommand)]
+pub enum ModelsCommand {
+    /// Download a model
+    Pull {
+        /// Name of the model, eg. llama3.2 or qwen3:8b
+        model: String,
+    },
+    /// Delete models
+    Rm {
+        /// Names of the models
+        #[arg(required = true)]
+        models: Vec<String>,
+    },
+    /// Copy a model under a new name
+    Cp {
+        /// Name of the existing model
+        source: String,
+        /// Name of the copy
+        destination: String,
+    },
+    /// Print a model's details, capabilities and parameters
+    Show {
+        /// Name of the model
+        model: String,
+        /// Print the model information as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// List the models loaded into memory
+    Ps {
+        /// Print the models as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Create a model from an existing one
+    Create {
+        /// Name of the new model
+        name: String,
+        /// Model to build on
+        #[arg(long)]
+        from: String,
+        /// System prompt of the new model
+        #[arg(long)]
+        system: Option<String>,
+        /// Prompt template of the new model
+        #[arg(long)]
+        template: Option<String>,
+        /// Model parameter as key=value, eg. num_ctx=8192 (repeat for several, or for a list like stop)
+        #[arg(long = "parameter", value_name = "KEY=VALUE")]
+        parameters: Vec<String>,
+        /// Quantization to apply, eg. q4_K_M
+        #[arg(long)]
+        quantize: Option<String>,
+    },
+}
+
+/// Prints the available models with their context length and capabilities
+///
+/// # Arguments
+/// * `client` - HTTP client for API requests
+/// * `app_config` - Application configuration naming the server and holding the model cache
+/// * `json` - Print the models as JSON instead of a list
+///
+/// # Errors
+/// Returns error if the models can't be listed
+pub async fn list_command(
+    client: &Client,
+    app_config: &mut ApplicationConfig,
+    json: bool,
+) -> Result<(), Box<dyn Error>> {
+    let backend = app_config.backend();
+    let models = backend.list_models(client).await?;
+    //Fetch the details of several models at once, keeping the order of the list
+    let cache = &app_config.cache;
+    let url = &app_config.url;
+    let details: Vec<_> = stream::iter(&models)
+        .map(|m| async {
+            match cache.get(url, &m.name, Some(&m.digest)) {
+                Some(info) => Ok((info.clone(), false)),
+                None => backend.show_model(client, &m.name).await.map(|i| (i, true)),
+            }
+        })
+        .buffered(MAX_CONCURRENT_REQUESTS)
+        .collect()
+        .await;
+    let models: Vec<OllamaModel> = models
+        .into_iter()
+        .zip(details)
+        .map(|(mut model, detail)| {
+            match detail {
+                Ok((detail, fetched)) => {
+                    if fetched {
+                        app_config
+                            .cache
+                            .insert(&app_config.url, &detail, &model.digest);
+                    }
+                    model.capabilities = detail.capabilities;
+                    model.model_info = detail.model_info;
+                    model.details = model.details.or(detail.details);
+                }
+                Err(e) => eprintln!("Error fetching details of {}: {}", model.name, e),
+            }
+            model
+        })
+        .collect();
+    app_config.cache.save();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&models)?);
+        return Ok(());
+    }
+    match app_config.backend {
+        BackendKind::Ollama => {
+            match server_version(client, &app_config.url, &app_config.api_key).await {
+                Ok(version) => println!("Available Models (Ollama {}):", version),
+                Err(_) => println!("Available Models:"),
+            }
+        }
+        BackendKind::OpenAi => println!("Available Models:"),
+    }
+    for model in models {
+        println!("- {} ctx: {:?}", model.name, model.get_context_length());
+        for c in model.capabilities {
+            println!("  - {}", c);
+        }
+    }
+    Ok(())
+}
+
+/// Runs a `chatto models` subcommand
+///
+/// # Arguments
+/// * `client` - HTTP client for API requests
+/// * `app_config` - Application configuration naming the server and holding the model cache
+/// * `command` - The subcommand to run
+///
+/// # Errors
+/// Returns error if the backend isn't Ollama or the server rejects the request
+pub async fn models_command(
+    client: &Client,
+    app_config: &mut ApplicationConfig,
+    command: ModelsCommand,
+) -> Result<(), Box<dyn Error>> {
+    if app_config.backend != BackendKind::Ollama {
+        return Err("chatto models needs the ollama backend".into());
+    }
+    let backend = app_config.backend();
+    let (url, key) = (app_config.url.as_str(), app_config.api_key.as_str());
+    let cache = &mut app_config.cache;
+    match command {
+        ModelsCommand::Pull { model } => {
+            let mut printer = ProgressPrinter::default();
+            let result = pull_model(client, url, key, &model, |p| printer.print(p)).await;
+            printer.finish();
+            result?;
+            cache.remove(url, &model);
+            println!("Pulled {}", model);
+        }
+        ModelsCommand::Rm { models } => {
+            for model in models {
+                delete_model(client, url, key, &model).await?;
+                cache.remove(url, &model);
+                println!("Deleted {}", model);
+            }
+        }
+        ModelsCommand::Cp {
+            source,
+            destination,
+        } => {
+            copy_model(client, url, key, &source, &destination).await?;
+            cache.remove(url, &destination);
+            println!("Copied {} to {}", source, destination);
+        }
+        ModelsCommand::Show { model, json } => {
+            let digest = backend.model_digest(client, &model).await?;
+            let model = backend.show_model(client, &model).await?;
+            cache.insert(url, &model, digest.as_deref().unwrap_or_default());
+            if json {
+                println!("{}", serde_json::to_string_pretty(&model)?);
+            } else {
+                print_model(&model);
+            }
+        }
+        ModelsCommand::Ps { json } => {
+            let models = running_models(client, url, key).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&models)?);
+                return Ok(());
+            }
+            if models.is_empty() {
+                println!("No models loaded");
+                return Ok(());
+            }
+            println!(
+                "{:<32} {:>10} {:>10} {:>8} UNTIL",
+                "NAME", "SIZE", "VRAM", "CONTEXT"
+            );
+            for m in models {
+                println!(
+                    "{:<32} {:>10} {:>10} {:>8} {}",
+                    m.name,
+                    format_size(m.size),
+                    format_size(m.size_vram),
+                    m.context_length.map(|c| c.to_string()).unwrap_or_default(),
+                    m.expires_at
+                );
+            }
+        }
+        ModelsCommand::Create {
+            name,
+            from,
+            system,
+            template,
+            parameters,
+            quantize,
+        } => {
+            let request = OllamaCreateRequest {
+                model: name.clone(),
+                from: Some(from),
+                system,
+                template,
+                parameters: parse_parameters(&parameters)?,
+                quantize,
+            };
+            let mut printer = ProgressPrinter::default();
+            let result = create_model(client, url, key, &request, |p| printer.print(p)).await;
+            printer.finish();
+            result?;
+            cache.remove(url, &name);
+            println!("Created {}", name);
+        }
+    }
+    cache.save();
+    Ok(())
+}
+
+/// Prints a model's details for `chatto models show`
+fn print_model(model: &OllamaModel) {
+    println!("Model: {}", model.name);
+    if let Some(details) = &model.details {
+        println!("Family: {}", details.family);
+        println!("Parameters: {}", details.parameter_size);
+        println!("Quantization: {}", details.quantization_level);
+    }
+    if let Some(context_length) = model.get_context_length() {
+        println!("Context length: {}", context_length);
+    }
+    println!("Capabilities: {}", model.capabilities.join(", "));
+    if let Some(parameters) = model.parameters.as_deref().filter(|p| !p.is_empty()) {
+        println!("\nModelfile parameters:\n{}", parameters.trim_end());
+    }
+    if let Some(system) = model.system.as_deref().filter(|s| !s.is_empty()) {
+        println!("\nSystem prompt:\n{}", system.trim_end());
+    }
+}
+
+/// Reads `key=value` parameters for `chatto models create`
+///
+/// Values are read as JSON when possible (numbers, booleans), and a key
+/// given more than once collects its values in a list.
+///
+/// # Errors
+/// Returns error if a parameter has no `=`
+fn parse_parameters(parameters: &[String]) -> Result<Map<String, Value>, Box<dyn Error>> {
+    let mut map = Map::new();
+    for parameter in parameters {
+        let (key, value) = parameter
+            .split_once('=')
+            .ok_or(format!("Parameter '{}' is not key=value", parameter))?;
+        let value = serde_json::from_str(value).unwrap_or(Value::String(value.to_string()));
+        match map.get_mut(key) {
+            Some(Value::Array(values)) => values.push(value),
+            Some(existing) => *existing = Value::Array(vec![existing.take(), value]),
+            None => {
+                map.insert(key.to_string(), value);
+            }
+        }
+    }
+    Ok(map)
+}
+
+/// Prints the progress lines of a pull or create on stderr
+///
+/// Layer downloads are drawn as a progress bar updated in place, other
+/// statuses are printed one per line.
+#[derive(Default)]
+struct ProgressPrinter {
+    /// Status of the progress bar currently drawn, if any
+    bar: Option<String>,
+}
+
+impl ProgressPrinter {
+    /// Prints a progress line
+    fn print(&mut self, progress: &OllamaProgress) {
+        let Some(total) = progress.total.filter(|t| *t > 0) else {
+            self.finish();
+            eprintln!("{}", progress.status);
+            return;
+        };
+        if self.bar.as_deref().is_some_and(|s| s != progress.status) {
+            self.finish();
+        }
+        let completed = progress.completed.unwrap_or_default().min(total);
+        let filled = (completed as f64 / total as f64 * BAR_WIDTH as f64) as usize;
+        eprint!(
+            "\r{} [{}{}] {:>3}% {} / {}",
+            progress.status,
+            "#".repeat(filled),
+            "-".repeat(BAR_WIDTH - filled),
+            completed * 100 / total,
+            format_size(completed),
+            format_size(total)
+        );
+        std::io::stderr().flush().unwrap_or_default();
+        self.bar = Some(progress.status.clone());
+    }
+
+    /// Ends the progress bar line, if one is drawn
+    fn finish(&mut self) {
+        if self.bar.take().is_some() {
+            eprintln!();
+        }
+    }
+}
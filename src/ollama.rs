@@ -11,7 +11,7 @@
 //! - **Tool Calling**: Support for function/tool calling in chat sessions
 //! - **Streaming**: Real-time response streaming with state tracking
 
-use std::{collections::HashMap, error::Error};
+use std::collections::HashMap;
 
 use futures::AsyncBufReadExt;
 use futures::TryStreamExt;
@@ -21,6 +21,12 @@ use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
+use crate::error::ChatError;
+use crate::interrupt::interrupted;
+
+/// `done_reason` given to responses that were cut short by Ctrl-C
+pub const INTERRUPTED_DONE_REASON: &str = "interrupted";
+
 /// Request structure for the Ollama chat API.
 ///
 /// This struct represents the data sent to Ollama when making a chat request,
@@ -34,6 +40,9 @@ pub struct OllamaChatRequest {
     pub options: Option<OllamaOptions>,
     pub stream: bool,
     pub think: bool,
+    /// `"json"` or a JSON schema the response has to follow
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<serde_json::Value>,
 }
 
 /// Model generation options for Ollama requests.
@@ -79,6 +88,12 @@ pub struct OllamaChatMessage {
     /// ID of the tool call this responds to (when role is tool)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_call_id: Option<String>,
+    /// Base64 encoded images for vision models (when role is user or tool)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub images: Option<Vec<String>>,
+    /// Model that produced the message (when role is assistant)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
 }
 
 impl OllamaChatMessage {
@@ -155,6 +170,22 @@ pub struct OllamaChatResponse {
 }
 
 impl OllamaChatResponse {
+    /// Marks this (partial) response as interrupted by the user.
+    ///
+    /// # Returns
+    /// The response with the interrupted streaming state, ready to be
+    /// returned from a chat request
+    pub fn into_interrupted(mut self) -> (Self, OllamaChatResponseStreamingState) {
+        self.done = true;
+        self.done_reason = Some(INTERRUPTED_DONE_REASON.to_string());
+        (self, OllamaChatResponseStreamingState::Interrupted)
+    }
+
+    /// Checks whether this response was cut short by the user.
+    pub fn is_interrupted(&self) -> bool {
+        self.done_reason.as_deref() == Some(INTERRUPTED_DONE_REASON)
+    }
+
     /// Merges an incoming streaming response chunk into this response.
     ///
     /// Accumulates content, thinking, and tool calls from streaming chunks
@@ -166,7 +197,7 @@ impl OllamaChatResponse {
     ///
     /// # Returns
     /// The current streaming state after merging (e.g., Thinking, Responding, CallingTools, Done)
-    fn merge(&mut self, incoming: &OllamaChatResponse) -> OllamaChatResponseStreamingState {
+    pub fn merge(&mut self, incoming: &OllamaChatResponse) -> OllamaChatResponseStreamingState {
         let mut ret: Option<OllamaChatResponseStreamingState> = None;
         self.model = incoming.model.clone();
         self.created_at = incoming.created_at.clone();
@@ -275,6 +306,8 @@ pub enum OllamaChatResponseStreamingState {
     CallingTools,
     /// Stream has completed
     Done,
+    /// Stream was cut short by the user
+    Interrupted,
 }
 
 /// Trait for handling streaming chat response chunks.
@@ -311,11 +344,30 @@ pub trait StreamingChatHandler {
     );
 }
 
+/// Lets a handler be lent to several requests, eg. when a request is retried
+impl<T: StreamingChatHandler> StreamingChatHandler for &mut T {
+    fn process_streaming_response(
+        &mut self,
+        previous_streaming_state: &OllamaChatResponseStreamingState,
+        current_streaming_state: &OllamaChatResponseStreamingState,
+        ollama_response: &OllamaChatResponse,
+    ) {
+        (**self).process_streaming_response(
+            previous_streaming_state,
+            current_streaming_state,
+            ollama_response,
+        )
+    }
+}
+
 /// Sends a chat request to the Ollama API and handles the response.
 ///
 /// Supports both streaming and non-streaming responses. For streaming responses,
 /// optionally calls a handler for each chunk received.
 ///
+/// If Ctrl-C is pressed while waiting, the content received so far is returned
+/// marked as interrupted, with the `Interrupted` streaming state.
+///
 /// # Arguments
 /// * `client` - The HTTP client to use for the request
 /// * `url` - The base URL of the Ollama API
@@ -339,22 +391,18 @@ pub async fn post_ollama_chat(
     key: &str,
     request: &OllamaChatRequest,
     mut streaming_chat_handler: Option<impl StreamingChatHandler>,
-) -> Result<(OllamaChatResponse, OllamaChatResponseStreamingState), Box<dyn Error>> {
-    let response = client
-        .post(format!("{}/api/chat", url))
-        .header("Authorization", format!("Bearer {}", key))
-        .json(&request)
-        .send()
-        .await
-        .map_err(|e| format!("Failed to connect to Ollama at {}: {}", url, e))?;
+) -> Result<(OllamaChatResponse, OllamaChatResponseStreamingState), ChatError> {
+    let response = tokio::select! {
+        response = client
+            .post(format!("{}/api/chat", url))
+            .header("Authorization", format!("Bearer {}", key))
+            .json(&request)
+            .send() => response.map_err(|e| ChatError::connection(url, e))?,
+        _ = interrupted() => return Ok(OllamaChatResponse::default().into_interrupted()),
+    };
 
     if !response.status().is_success() {
-        let status = response.status();
-        let error_text = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Unknown error".to_string());
-        return Err(format!("API Error: Status: {}: {}", status, error_text).into());
+        return Err(ChatError::from_response(response, Some(&request.model)).await);
     }
 
     // Check content type
@@ -365,7 +413,10 @@ pub async fn post_ollama_chat(
             .map(|cthv| cthv.to_str().unwrap_or_default())
             == Some("application/json")
     {
-        let body: OllamaChatResponse = response.json().await?;
+        let body: OllamaChatResponse = tokio::select! {
+            body = response.json() => body.map_err(|e| ChatError::InvalidResponse(e.to_string()))?,
+            _ = interrupted() => return Ok(OllamaChatResponse::default().into_interrupted()),
+        };
         return Ok((body, OllamaChatResponseStreamingState::NoStream));
     }
 
@@ -381,8 +432,11 @@ pub async fn post_ollama_chat(
     let mut ollama_response: OllamaChatResponse = OllamaChatResponse::default();
 
     let mut lines = reader.lines();
-    while let Some(line) = lines.next().await {
-        let line = line.map_err(|e| format!("Stream line error: {}", e))?;
+    while let Some(line) = tokio::select! {
+        line = lines.next() => line,
+        _ = interrupted() => return Ok(ollama_response.into_interrupted()),
+    } {
+        let line = line.map_err(|e| ChatError::InvalidResponse(e.to_string()))?;
         match serde_json::from_str::<OllamaChatResponse>(&line) {
             Ok(response_chunk) => {
                 let prev_streaming_state = streaming_state;
@@ -399,16 +453,24 @@ pub async fn post_ollama_chat(
                 }
             }
             Err(e) => {
-                println!("{line}");
-                return Err(format!("JSON Error: {}", e).into());
+                //Ollama reports failures in the middle of a stream as {"error": "..."}
+                if let Some(error) = serde_json::from_str::<Value>(&line)
+                    .ok()
+                    .and_then(|v| v["error"].as_str().map(str::to_string))
+                {
+                    return Err(ChatError::Server(error));
+                }
+                return Err(ChatError::InvalidResponse(format!("{}: {}", e, line)));
             }
         }
     }
 
-    Err(format!("No final response, {}", 0).into())
+    Err(ChatError::InvalidResponse(
+        "the stream ended without a final response".to_string(),
+    ))
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct OllamaModel {
     #[serde(default)]
     pub name: String,
@@ -425,7 +487,17 @@ pub struct OllamaModel {
     pub model_info: HashMap<String, Value>,
     #[serde(default)]
     pub capabilities: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub options: Option<OllamaOptions>,
+    /// Modelfile parameters, as returned by `/api/show`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<String>,
+    /// Prompt template, as returned by `/api/show`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// System prompt baked into the model, as returned by `/api/show`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
 }
 
 impl OllamaModel {
@@ -446,7 +518,7 @@ impl OllamaModel {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct OllamaModelDetails {
     pub parent_model: String,
     pub format: String,
@@ -476,18 +548,22 @@ pub async fn list_models(
     client: &Client,
     url: &str,
     key: &str,
-) -> Result<Vec<OllamaModel>, Box<dyn Error>> {
+) -> Result<Vec<OllamaModel>, ChatError> {
     let response = client
         .get(format!("{}/api/tags", url))
         .header("Authorization", format!("Bearer {}", key))
         .send()
         .await
-        .map_err(|e| format!("Failed to connect to Ollama at {}: {}", url, e))?;
+        .map_err(|e| ChatError::connection(url, e))?;
     #[derive(Debug, Deserialize, Clone)]
     pub struct AnonWrapper {
         pub models: Vec<OllamaModel>,
     }
-    let body: AnonWrapper = response.json().await?;
+    let body: AnonWrapper = check_status(response, None)
+        .await?
+        .json()
+        .await
+        .map_err(|e| ChatError::InvalidResponse(e.to_string()))?;
     Ok(body.models)
 }
 
@@ -515,24 +591,307 @@ pub async fn show_model(
     url: &str,
     key: &str,
     model: &str,
-) -> Result<OllamaModel, Box<dyn Error>> {
+) -> Result<OllamaModel, ChatError> {
     let response = client
         .post(format!("{}/api/show", url))
         .header("Authorization", format!("Bearer {}", key))
         .json(&json!({"model": model}))
         .send()
         .await
-        .map_err(|e| format!("Failed to connect to Ollama at {}: {}", url, e))?;
+        .map_err(|e| ChatError::connection(url, e))?;
 
-    if !response.status().is_success() {
-        let status = response.status();
-        let error_text = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Unknown error".to_string());
-        return Err(format!("API Error: Status: {}: {}", status, error_text).into());
-    }
-    let mut body: OllamaModel = response.json().await?;
+    let mut body: OllamaModel = check_status(response, Some(model))
+        .await?
+        .json()
+        .await
+        .map_err(|e| ChatError::InvalidResponse(e.to_string()))?;
     body.name = model.to_string();
     Ok(body)
 }
+
+/// A model loaded into memory, as listed by `/api/ps`
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct OllamaRunningModel {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub size_vram: u64,
+    #[serde(default)]
+    pub digest: String,
+    /// When the model will be unloaded
+    #[serde(default)]
+    pub expires_at: String,
+    #[serde(default)]
+    pub context_length: Option<u64>,
+    pub details: Option<OllamaModelDetails>,
+}
+
+/// A status line streamed by `/api/pull` and `/api/create`
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct OllamaProgress {
+    /// What the server is doing, eg. `pulling manifest` or `success`
+    #[serde(default)]
+    pub status: String,
+    /// Layer being transferred
+    pub digest: Option<String>,
+    /// Size of the layer in bytes
+    pub total: Option<u64>,
+    /// Bytes of the layer transferred so far
+    pub completed: Option<u64>,
+    /// Set if the operation failed
+    pub error: Option<String>,
+}
+
+/// Request body for `/api/create`
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct OllamaCreateRequest {
+    /// Name of the model to create
+    pub model: String,
+    /// Existing model to build on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    /// System prompt for the new model
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    /// Prompt template for the new model
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// Model parameters such as `temperature` or `num_ctx`
+    #[serde(skip_serializing_if = "serde_json::Map::is_empty")]
+    pub parameters: serde_json::Map<String, Value>,
+    /// Quantization type to apply, eg. `q4_K_M`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quantize: Option<String>,
+}
+
+/// Turns an unsuccessful response into an error carrying the server's message
+///
+/// # Arguments
+/// * `response` - The response to check
+/// * `model` - Model the request was for, to report it as not found
+///
+/// # Errors
+/// Returns an error with the status and the server's message if the status is not a success
+async fn check_status(
+    response: reqwest::Response,
+    model: Option<&str>,
+) -> Result<reqwest::Response, ChatError> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    Err(ChatError::from_response(response, model).await)
+}
+
+/// Reads the progress lines streamed by `/api/pull` and `/api/create`
+///
+/// # Arguments
+/// * `response` - The streaming response
+/// * `progress` - Called with every progress line
+///
+/// # Errors
+/// Returns an error if the stream breaks, a line can't be parsed, the server
+/// reports an error or the user interrupts
+async fn read_progress(
+    response: reqwest::Response,
+    mut progress: impl FnMut(&OllamaProgress),
+) -> Result<(), ChatError> {
+    let reader = BufReader::new(
+        response
+            .bytes_stream()
+            .map_err(futures::io::Error::other)
+            .into_async_read(),
+    );
+    let mut lines = reader.lines();
+    while let Some(line) = tokio::select! {
+        line = lines.next() => line,
+        _ = interrupted() => return Err(ChatError::Interrupted),
+    } {
+        let line = line.map_err(|e| ChatError::InvalidResponse(e.to_string()))?;
+        let status: OllamaProgress = serde_json::from_str(&line)
+            .map_err(|e| ChatError::InvalidResponse(format!("{}: {}", e, line)))?;
+        if let Some(error) = status.error {
+            return Err(ChatError::Server(error));
+        }
+        progress(&status);
+    }
+    Ok(())
+}
+
+/// Downloads a model from the registry
+///
+/// # Arguments
+/// * `client` - The HTTP client to use for the request
+/// * `url` - The base URL of the Ollama API
+/// * `key` - The API key for authentication
+/// * `model` - Name of the model to pull
+/// * `progress` - Called with every progress line while the model downloads
+///
+/// # Errors
+/// Returns an error if the connection fails, the model doesn't exist or the
+/// download fails
+pub async fn pull_model(
+    client: &Client,
+    url: &str,
+    key: &str,
+    model: &str,
+    progress: impl FnMut(&OllamaProgress),
+) -> Result<(), ChatError> {
+    let response = client
+        .post(format!("{}/api/pull", url))
+        .header("Authorization", format!("Bearer {}", key))
+        .json(&json!({"model": model, "stream": true}))
+        .send()
+        .await
+        .map_err(|e| ChatError::connection(url, e))?;
+    read_progress(check_status(response, Some(model)).await?, progress).await
+}
+
+/// Creates a model from an existing one
+///
+/// # Arguments
+/// * `client` - The HTTP client to use for the request
+/// * `url` - The base URL of the Ollama API
+/// * `key` - The API key for authentication
+/// * `request` - Name, base model and settings of the new model
+/// * `progress` - Called with every progress line
+///
+/// # Errors
+/// Returns an error if the connection fails or the model can't be created
+pub async fn create_model(
+    client: &Client,
+    url: &str,
+    key: &str,
+    request: &OllamaCreateRequest,
+    progress: impl FnMut(&OllamaProgress),
+) -> Result<(), ChatError> {
+    let response = client
+        .post(format!("{}/api/create", url))
+        .header("Authorization", format!("Bearer {}", key))
+        .json(request)
+        .send()
+        .await
+        .map_err(|e| ChatError::connection(url, e))?;
+    read_progress(
+        check_status(response, request.from.as_deref()).await?,
+        progress,
+    )
+    .await
+}
+
+/// Deletes a model
+///
+/// # Arguments
+/// * `client` - The HTTP client to use for the request
+/// * `url` - The base URL of the Ollama API
+/// * `key` - The API key for authentication
+/// * `model` - Name of the model to delete
+///
+/// # Errors
+/// Returns an error if the connection fails or the model doesn't exist
+pub async fn delete_model(
+    client: &Client,
+    url: &str,
+    key: &str,
+    model: &str,
+) -> Result<(), ChatError> {
+    let response = client
+        .delete(format!("{}/api/delete", url))
+        .header("Authorization", format!("Bearer {}", key))
+        .json(&json!({"model": model}))
+        .send()
+        .await
+        .map_err(|e| ChatError::connection(url, e))?;
+    check_status(response, Some(model)).await?;
+    Ok(())
+}
+
+/// Copies a model under a new name
+///
+/// # Arguments
+/// * `client` - The HTTP client to use for the request
+/// * `url` - The base URL of the Ollama API
+/// * `key` - The API key for authentication
+/// * `source` - Name of the existing model
+/// * `destination` - Name of the copy
+///
+/// # Errors
+/// Returns an error if the connection fails or the source doesn't exist
+pub async fn copy_model(
+    client: &Client,
+    url: &str,
+    key: &str,
+    source: &str,
+    destination: &str,
+) -> Result<(), ChatError> {
+    let response = client
+        .post(format!("{}/api/copy", url))
+        .header("Authorization", format!("Bearer {}", key))
+        .json(&json!({"source": source, "destination": destination}))
+        .send()
+        .await
+        .map_err(|e| ChatError::connection(url, e))?;
+    check_status(response, Some(source)).await?;
+    Ok(())
+}
+
+/// Lists the models currently loaded into memory
+///
+/// # Arguments
+/// * `client` - The HTTP client to use for the request
+/// * `url` - The base URL of the Ollama API
+/// * `key` - The API key for authentication
+///
+/// # Errors
+/// Returns an error if the connection fails or the response can't be parsed
+pub async fn running_models(
+    client: &Client,
+    url: &str,
+    key: &str,
+) -> Result<Vec<OllamaRunningModel>, ChatError> {
+    let response = client
+        .get(format!("{}/api/ps", url))
+        .header("Authorization", format!("Bearer {}", key))
+        .send()
+        .await
+        .map_err(|e| ChatError::connection(url, e))?;
+    #[derive(Debug, Deserialize)]
+    struct AnonWrapper {
+        models: Vec<OllamaRunningModel>,
+    }
+    let body: AnonWrapper = check_status(response, None)
+        .await?
+        .json()
+        .await
+        .map_err(|e| ChatError::InvalidResponse(e.to_string()))?;
+    Ok(body.models)
+}
+
+/// Retrieves the version of the Ollama server
+///
+/// # Arguments
+/// * `client` - The HTTP client to use for the request
+/// * `url` - The base URL of the Ollama API
+/// * `key` - The API key for authentication
+///
+/// # Errors
+/// Returns an error if the connection fails or the response can't be parsed
+pub async fn server_version(client: &Client, url: &str, key: &str) -> Result<String, ChatError> {
+    let response = client
+        .get(format!("{}/api/version", url))
+        .header("Authorization", format!("Bearer {}", key))
+        .send()
+        .await
+        .map_err(|e| ChatError::connection(url, e))?;
+    #[derive(Debug, Deserialize)]
+    struct AnonWrapper {
+        version: String,
+    }
+    let body: AnonWrapper = check_status(response, None)
+        .await?
+        .json()
+        .await
+        .map_err(|e| ChatError::InvalidResponse(e.to_string()))?;
+    Ok(body.version)
+}
@@ -0,0 +1,815 @@
+//! OpenAI-compatible API client implementation.
+//!
+//! This module talks to servers implementing the OpenAI `/v1/chat/completions`
+//! API, such as llama.cpp server, vLLM or LM Studio. Requests and responses
+//! are translated to and from the Ollama types used throughout chatto, so
+//! sessions and tools work the same regardless of the backend.
+//!
+//! ## Key Features
+//!
+//! - **Chat API**: Non-streaming and SSE streaming responses
+//! - **Tool Calling**: Tool calls with incremental argument deltas are
+//!   assembled into complete `ToolCall`s
+//! - **Tool Call IDs**: IDs are round-tripped between tool calls and tool
+//!   results, and generated for messages that don't have one
+//! - **Models**: Lists models from `/v1/models`
+
+use futures::AsyncBufReadExt;
+use futures::TryStreamExt;
+use futures_util::io::BufReader;
+use futures_util::StreamExt;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::attach::base64_mime;
+use crate::error::ChatError;
+use crate::interrupt::interrupted;
+use crate::ollama::{
+    OllamaChatMessage, OllamaChatRequest, OllamaChatResponse, OllamaChatResponseMessage,
+    OllamaChatResponseStreamingState, OllamaModel, StreamingChatHandler, ToolCall,
+    ToolCallFunction,
+};
+
+/// Request structure for the OpenAI chat completions API.
+#[derive(Debug, Serialize)]
+pub struct OpenAiChatRequest {
+    pub model: String,
+    pub messages: Vec<OpenAiChatMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Value>>,
+    pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<Value>,
+}
+
+/// A single message in an OpenAI chat conversation.
+#[derive(Debug, Serialize, Clone)]
+pub struct OpenAiChatMessage {
+    /// The role of this message sender (user, assistant, system, or tool)
+    pub role: String,
+    /// The text content of the message, or text and image parts
+    pub content: Value,
+    /// Tool calls requested by the assistant (when role is assistant)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<OpenAiToolCall>>,
+    /// ID of the tool call this responds to (when role is tool)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+/// A tool call as sent back to the server in the conversation history.
+#[derive(Debug, Serialize, Clone)]
+pub struct OpenAiToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: OpenAiToolCallFunction,
+}
+
+/// Function details of a tool call, with arguments as a JSON encoded string.
+#[derive(Debug, Serialize, Clone)]
+pub struct OpenAiToolCallFunction {
+    pub name: String,
+    pub arguments: String,
+}
+
+/// Converts an Ollama chat request into an OpenAI chat request.
+///
+/// Tool calls without an ID get a generated one, and tool results without
+/// an ID are matched to the pending tool calls in order, because the OpenAI
+/// API requires every tool result to reference its tool call.
+impl From<&OllamaChatRequest> for OpenAiChatRequest {
+    fn from(value: &OllamaChatRequest) -> Self {
+        let mut pending_ids: Vec<String> = Vec::new();
+        let messages = value
+            .messages
+            .iter()
+            .enumerate()
+            .map(|(i, m)| {
+                let tool_calls = m.tool_calls.as_ref().map(|tool_calls| {
+                    pending_ids.clear();
+                    tool_calls
+                        .iter()
+                        .enumerate()
+                        .map(|(j, tc)| {
+                            let id = tc.id.clone().unwrap_or(format!("call_{}_{}", i, j));
+                            pending_ids.push(id.clone());
+                            OpenAiToolCall {
+                                id,
+                                kind: "function".to_string(),
+                                function: OpenAiToolCallFunction {
+                                    name: tc.function.name.clone(),
+                                    arguments: match &tc.function.arguments {
+                                        Value::String(s) => s.clone(),
+                                        v => v.to_string(),
+                                    },
+                                },
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                });
+                let tool_call_id = if m.role == "tool" {
+                    match &m.tool_call_id {
+                        Some(id) => {
+                            pending_ids.retain(|p| p != id);
+                            Some(id.clone())
+                        }
+                        None if !pending_ids.is_empty() => Some(pending_ids.remove(0)),
+                        None => None,
+                    }
+                } else {
+                    None
+                };
+                OpenAiChatMessage {
+                    role: m.role.clone(),
+                    content: message_content(m),
+                    tool_calls,
+                    tool_call_id,
+                }
+            })
+            .collect();
+        let options = value.options.clone();
+        Self {
+            model: value.model.clone(),
+            messages,
+            tools: value.tools.clone().filter(|t| !t.is_empty()),
+            stream: value.stream,
+            seed: options.as_ref().and_then(|o| o.seed),
+            temperature: options.as_ref().and_then(|o| o.temperature),
+            top_k: options.as_ref().and_then(|o| o.top_k),
+            top_p: options.as_ref().and_then(|o| o.top_p),
+            min_p: options.as_ref().and_then(|o| o.min_p),
+            stop: options
+                .as_ref()
+                .and_then(|o| o.stop.clone())
+                .map(|s| vec![s]),
+            max_tokens: options.as_ref().and_then(|o| o.num_predict),
+            response_format: value.format.as_ref().map(response_format),
+        }
+    }
+}
+
+/// Converts an Ollama `format` into an OpenAI `response_format`
+///
+/// `"json"` becomes JSON mode, a schema becomes a JSON schema format.
+fn response_format(format: &Value) -> Value {
+    match format {
+        Value::Object(_) => serde_json::json!({
+            "type": "json_schema",
+            "json_schema": { "name": "response", "schema": format }
+        }),
+        _ => serde_json::json!({ "type": "json_object" }),
+    }
+}
+
+/// Converts the content of a message, adding its images as image parts
+///
+/// OpenAI-compatible servers only take images on user messages, the images
+/// of other messages are dropped.
+fn message_content(message: &OllamaChatMessage) -> Value {
+    let Some(images) = message.images.as_ref().filter(|_| message.role == "user") else {
+        return Value::String(message.content.clone());
+    };
+    let mut parts = vec![serde_json::json!({ "type": "text", "text": message.content })];
+    parts.extend(images.iter().map(|data| {
+        serde_json::json!({
+            "type": "image_url",
+            "image_url": {
+                "url": format!("data:{};base64,{}", base64_mime(data).unwrap_or("image/png"), data)
+            }
+        })
+    }));
+    Value::Array(parts)
+}
+
+/// A chat completion response, or a single chunk of a streaming response.
+#[derive(Debug, Default, Deserialize)]
+struct OpenAiChatResponse {
+    #[serde(default)]
+    model: String,
+    #[serde(default)]
+    created: u64,
+    #[serde(default)]
+    choices: Vec<OpenAiChoice>,
+    usage: Option<OpenAiUsage>,
+}
+
+/// A response choice, `message` for complete responses and `delta` for chunks.
+#[derive(Debug, Default, Deserialize)]
+struct OpenAiChoice {
+    message: Option<OpenAiResponseMessage>,
+    delta: Option<OpenAiResponseMessage>,
+    finish_reason: Option<String>,
+}
+
+/// The message (or message delta) of a response choice.
+#[derive(Debug, Default, Deserialize)]
+struct OpenAiResponseMessage {
+    content: Option<String>,
+    /// Reasoning output, used by several OpenAI-compatible servers
+    reasoning_content: Option<String>,
+    tool_calls: Option<Vec<OpenAiToolCallDelta>>,
+}
+
+/// A complete tool call, or a fragment of one when streaming.
+#[derive(Debug, Default, Deserialize)]
+struct OpenAiToolCallDelta {
+    index: Option<usize>,
+    id: Option<String>,
+    function: Option<OpenAiFunctionDelta>,
+}
+
+/// Function details of a tool call, arguments may be a partial JSON string.
+#[derive(Debug, Default, Deserialize)]
+struct OpenAiFunctionDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+/// Token usage reported by the server.
+#[derive(Debug, Default, Deserialize)]
+struct OpenAiUsage {
+    prompt_tokens: Option<u64>,
+    completion_tokens: Option<u64>,
+}
+
+/// A tool call being assembled from streaming deltas.
+#[derive(Debug, Default)]
+struct PartialToolCall {
+    id: Option<String>,
+    name: String,
+    arguments: String,
+}
+
+impl PartialToolCall {
+    /// Converts the assembled fragments into a ToolCall.
+    ///
+    /// Arguments that aren't valid JSON are kept as a string, so the tool
+    /// can report the problem back to the model.
+    fn into_tool_call(self) -> ToolCall {
+        let arguments = if self.arguments.trim().is_empty() {
+            Value::Object(Default::default())
+        } else {
+            serde_json::from_str(&self.arguments).unwrap_or(Value::String(self.arguments))
+        };
+        ToolCall {
+            id: self.id,
+            function: ToolCallFunction {
+                name: self.name,
+                description: None,
+                arguments,
+            },
+        }
+    }
+}
+
+/// Merges tool call deltas into the tool calls being assembled.
+///
+/// Deltas are matched by index, falling back to starting a new call when a
+/// delta carries an ID and no index.
+fn merge_tool_call_deltas(partials: &mut Vec<PartialToolCall>, deltas: Vec<OpenAiToolCallDelta>) {
+    for delta in deltas {
+        let index = match delta.index {
+            Some(index) => index,
+            None if delta.id.is_some() || partials.is_empty() => partials.len(),
+            None => partials.len() - 1,
+        };
+        while partials.len() <= index {
+            partials.push(PartialToolCall::default());
+        }
+        let partial = &mut partials[index];
+        if delta.id.is_some() {
+            partial.id = delta.id;
+        }
+        if let Some(function) = delta.function {
+            if let Some(name) = function.name {
+                partial.name.push_str(&name);
+            }
+            if let Some(arguments) = function.arguments {
+                partial.arguments.push_str(&arguments);
+            }
+        }
+    }
+}
+
+/// Assembles a streamed chat completion from the lines of its event stream.
+///
+/// Text and reasoning are passed on as they arrive, tool call fragments are
+/// collected by index until the stream ends.
+#[derive(Default)]
+struct StreamParser {
+    partials: Vec<PartialToolCall>,
+    finish_reason: Option<String>,
+    usage: OpenAiUsage,
+    model: String,
+    created_at: String,
+    /// Whether the `[DONE]` sentinel was received
+    finished: bool,
+}
+
+impl StreamParser {
+    fn new(model: &str) -> Self {
+        Self {
+            model: model.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Reads one line of the event stream.
+    ///
+    /// # Returns
+    /// The response chunks with the text and reasoning the line carried
+    ///
+    /// # Errors
+    /// Returns an error if the server reported one or the data isn't a chunk
+    fn feed(&mut self, line: &str) -> Result<Vec<OllamaChatResponse>, ChatError> {
+        let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+            //Blank separators, comments and other SSE fields
+            return Ok(Vec::new());
+        };
+        if data == "[DONE]" {
+            self.finished = true;
+            return Ok(Vec::new());
+        }
+        let invalid = |e: serde_json::Error| ChatError::InvalidResponse(format!("{}: {}", e, line));
+        let value: Value = serde_json::from_str(data).map_err(invalid)?;
+        //Servers report failures in the middle of a stream as {"error": ...}, which
+        //would otherwise read as an empty chunk since every field has a default
+        if let Some(error) = value.get("error").filter(|e| !e.is_null()) {
+            let message = error
+                .as_str()
+                .or(error["message"].as_str())
+                .map(str::to_string)
+                .unwrap_or_else(|| error.to_string());
+            return Err(ChatError::Server(message));
+        }
+        let chunk: OpenAiChatResponse = serde_json::from_value(value).map_err(invalid)?;
+        if !chunk.model.is_empty() {
+            self.model = chunk.model;
+        }
+        self.created_at = chunk.created.to_string();
+        if let Some(usage) = chunk.usage {
+            self.usage = usage;
+        }
+        let mut response_chunks = Vec::new();
+        for choice in chunk.choices {
+            if choice.finish_reason.is_some() {
+                self.finish_reason = choice.finish_reason;
+            }
+            let Some(delta) = choice.delta else {
+                continue;
+            };
+            merge_tool_call_deltas(&mut self.partials, delta.tool_calls.unwrap_or_default());
+            let content = delta.content.unwrap_or_default();
+            let thinking = delta.reasoning_content.filter(|t| !t.is_empty());
+            if content.is_empty() && thinking.is_none() {
+                continue;
+            }
+            response_chunks.push(OllamaChatResponse {
+                model: self.model.clone(),
+                created_at: self.created_at.clone(),
+                message: Some(OllamaChatResponseMessage {
+                    role: "assistant".to_string(),
+                    content,
+                    thinking,
+                    tool_calls: None,
+                }),
+                ..Default::default()
+            });
+        }
+        Ok(response_chunks)
+    }
+
+    /// Ends the stream.
+    ///
+    /// # Returns
+    /// A chunk with the assembled tool calls, if any, and the final chunk
+    ///
+    /// # Errors
+    /// Returns an error if the stream broke off before `[DONE]` or a finish reason
+    fn finish(self) -> Result<Vec<OllamaChatResponse>, ChatError> {
+        if !self.finished && self.finish_reason.is_none() {
+            return Err(ChatError::InvalidResponse(
+                "the stream ended without a final response".to_string(),
+            ));
+        }
+        let mut response_chunks = Vec::new();
+        if !self.partials.is_empty() {
+            response_chunks.push(OllamaChatResponse {
+                model: self.model.clone(),
+                created_at: self.created_at.clone(),
+                message: Some(OllamaChatResponseMessage {
+                    role: "assistant".to_string(),
+                    tool_calls: Some(
+                        self.partials
+                            .into_iter()
+                            .map(PartialToolCall::into_tool_call)
+                            .collect(),
+                    ),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            });
+        }
+        response_chunks.push(OllamaChatResponse {
+            model: self.model,
+            created_at: self.created_at,
+            done: true,
+            done_reason: self.finish_reason,
+            prompt_eval_count: self.usage.prompt_tokens,
+            eval_count: self.usage.completion_tokens,
+            ..Default::default()
+        });
+        Ok(response_chunks)
+    }
+}
+
+/// Builds the chat completions endpoint for a base URL.
+///
+/// Accepts base URLs with or without a trailing `/v1`.
+fn endpoint(url: &str, path: &str) -> String {
+    let base = url.trim_end_matches('/');
+    if base.ends_with("/v1") {
+        format!("{}/{}", base, path)
+    } else {
+        format!("{}/v1/{}", base, path)
+    }
+}
+
+/// Sends a chat request to an OpenAI-compatible API and handles the response.
+///
+/// The Ollama request is translated to the OpenAI format, and the response
+/// (or each SSE chunk of a streaming response) is translated back, so the
+/// streaming handler sees the same chunks it would from Ollama.
+///
+/// If Ctrl-C is pressed while waiting, the content received so far is returned
+/// marked as interrupted, with the `Interrupted` streaming state.
+///
+/// # Arguments
+/// * `client` - The HTTP client to use for the request
+/// * `url` - The base URL of the API (with or without `/v1`)
+/// * `key` - The API key for authentication
+/// * `request` - The chat request to send
+/// * `streaming_chat_handler` - Optional handler for processing streaming response chunks
+///
+/// # Returns
+/// A tuple containing the complete response and the final streaming state,
+/// or an error if the request fails.
+///
+/// # Errors
+/// Returns an error if:
+/// - Connection to the server fails
+/// - The API returns a non-success status code
+/// - JSON parsing fails
+/// - The stream ends without a final response
+pub async fn post_openai_chat(
+    client: &Client,
+    url: &str,
+    key: &str,
+    request: &OllamaChatRequest,
+    mut streaming_chat_handler: Option<impl StreamingChatHandler>,
+) -> Result<(OllamaChatResponse, OllamaChatResponseStreamingState), ChatError> {
+    let openai_request: OpenAiChatRequest = request.into();
+    let response = tokio::select! {
+        response = client
+            .post(endpoint(url, "chat/completions"))
+            .header("Authorization", format!("Bearer {}", key))
+            .json(&openai_request)
+            .send() => response.map_err(|e| ChatError::connection(url, e))?,
+        _ = interrupted() => return Ok(OllamaChatResponse::default().into_interrupted()),
+    };
+
+    if !response.status().is_success() {
+        return Err(ChatError::from_response(response, Some(&request.model)).await);
+    }
+
+    if !request.stream {
+        let body: OpenAiChatResponse = tokio::select! {
+            body = response.json() => body.map_err(|e| ChatError::InvalidResponse(e.to_string()))?,
+            _ = interrupted() => return Ok(OllamaChatResponse::default().into_interrupted()),
+        };
+        let choice = body.choices.into_iter().next().unwrap_or_default();
+        let message = choice.message.unwrap_or_default();
+        let mut partials: Vec<PartialToolCall> = Vec::new();
+        merge_tool_call_deltas(&mut partials, message.tool_calls.unwrap_or_default());
+        let tool_calls: Vec<ToolCall> = partials
+            .into_iter()
+            .map(PartialToolCall::into_tool_call)
+            .collect();
+        let usage = body.usage.unwrap_or_default();
+        return Ok((
+            OllamaChatResponse {
+                model: body.model,
+                created_at: body.created.to_string(),
+                message: Some(OllamaChatResponseMessage {
+                    role: "assistant".to_string(),
+                    content: message.content.unwrap_or_default(),
+                    thinking: message.reasoning_content.filter(|t| !t.is_empty()),
+                    tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+                }),
+                done: true,
+                done_reason: choice.finish_reason,
+                prompt_eval_count: usage.prompt_tokens,
+                eval_count: usage.completion_tokens,
+                ..Default::default()
+            },
+            OllamaChatResponseStreamingState::NoStream,
+        ));
+    }
+
+    let reader = BufReader::new(
+        response
+            .bytes_stream()
+            .map_err(futures::io::Error::other)
+            .into_async_read(),
+    );
+
+    let mut streaming_state: OllamaChatResponseStreamingState =
+        OllamaChatResponseStreamingState::Receiving;
+    let mut ollama_response: OllamaChatResponse = OllamaChatResponse::default();
+    let mut parser = StreamParser::new(&request.model);
+
+    let mut lines = reader.lines();
+    loop {
+        let line = match parser.finished {
+            true => None,
+            false => tokio::select! {
+                line = lines.next() => line,
+                _ = interrupted() => return Ok(ollama_response.into_interrupted()),
+            },
+        };
+        //Tool calls are only complete once the stream ends, so they are sent with the final chunk
+        let response_chunks = match line {
+            Some(line) => {
+                parser.feed(&line.map_err(|e| ChatError::InvalidResponse(e.to_string()))?)?
+            }
+            None => std::mem::take(&mut parser).finish()?,
+        };
+        for response_chunk in &response_chunks {
+            let prev_streaming_state = streaming_state;
+            streaming_state = ollama_response.merge(response_chunk);
+            if let Some(streaming_chat_handler) = streaming_chat_handler.as_mut() {
+                streaming_chat_handler.process_streaming_response(
+                    &prev_streaming_state,
+                    &streaming_state,
+                    response_chunk,
+                );
+            }
+        }
+        if response_chunks.last().is_some_and(|c| c.done) {
+            break;
+        }
+    }
+    Ok((ollama_response, streaming_state))
+}
+
+/// Lists all available models from an OpenAI-compatible API.
+///
+/// # Arguments
+/// * `client` - The HTTP client to use for the request
+/// * `url` - The base URL of the API (with or without `/v1`)
+/// * `key` - The API key for authentication
+///
+/// # Returns
+/// A vector of OllamaModel objects with their names set,
+/// or an error if the request fails.
+///
+/// # Errors
+/// Returns an error if the connection fails or the response cannot be parsed.
+pub async fn list_openai_models(
+    client: &Client,
+    url: &str,
+    key: &str,
+) -> Result<Vec<OllamaModel>, ChatError> {
+    let response = client
+        .get(endpoint(url, "models"))
+        .header("Authorization", format!("Bearer {}", key))
+        .send()
+        .await
+        .map_err(|e| ChatError::connection(url, e))?;
+
+    if !response.status().is_success() {
+        return Err(ChatError::from_response(response, None).await);
+    }
+    #[derive(Debug, Deserialize)]
+    struct AnonModel {
+        id: String,
+    }
+    #[derive(Debug, Deserialize)]
+    struct AnonWrapper {
+        data: Vec<AnonModel>,
+    }
+    let body: AnonWrapper = response
+        .json()
+        .await
+        .map_err(|e| ChatError::InvalidResponse(e.to_string()))?;
+    Ok(body.data.into_iter().map(|m| openai_model(&m.id)).collect())
+}
+
+/// Creates model information for a model served by an OpenAI-compatible API.
+///
+/// The API has no capability endpoint, so models are assumed to support
+/// tool calling.
+///
+/// # Arguments
+/// * `model` - The model name
+///
+/// # Returns
+/// An OllamaModel with the name and assumed capabilities set
+pub fn openai_model(model: &str) -> OllamaModel {
+    OllamaModel {
+        name: model.to_string(),
+        model: model.to_string(),
+        modified_at: String::new(),
+        size: 0,
+        digest: String::new(),
+        details: None,
+        model_info: Default::default(),
+        capabilities: vec!["completion".to_string(), "tools".to_string()],
+        options: None,
+        parameters: None,
+        template: None,
+        system: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Formats a chunk as an event stream line
+    fn event(chunk: Value) -> String {
+        format!("data: {}", chunk)
+    }
+
+    /// Formats tool call deltas as an event stream line
+    fn tool_deltas(deltas: Value) -> String {
+        event(json!({"choices": [{"delta": {"tool_calls": deltas}}]}))
+    }
+
+    /// Feeds the lines to a parser, then ends the stream
+    ///
+    /// # Returns
+    /// Every response chunk, in order
+    fn parse(lines: &[String]) -> Result<Vec<OllamaChatResponse>, ChatError> {
+        let mut parser = StreamParser::new("gpt");
+        let mut chunks = Vec::new();
+        for line in lines {
+            chunks.extend(parser.feed(line)?);
+            if parser.finished {
+                break;
+            }
+        }
+        chunks.extend(parser.finish()?);
+        Ok(chunks)
+    }
+
+    /// The tool calls sent with the final chunks
+    fn tool_calls(chunks: &[OllamaChatResponse]) -> Vec<ToolCall> {
+        chunks
+            .iter()
+            .filter_map(|c| c.message.as_ref()?.tool_calls.clone())
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn streams_content_and_finishes_on_done() {
+        let lines = [
+            ": keep-alive".to_string(),
+            event(
+                json!({"model": "gpt-4o", "choices": [{"delta": {"role": "assistant", "content": "Hel"}}]}),
+            ),
+            String::new(),
+            event(json!({"choices": [{"delta": {"content": "lo"}, "finish_reason": "stop"}]})),
+            event(json!({"choices": [], "usage": {"prompt_tokens": 5, "completion_tokens": 2}})),
+            "data: [DONE]".to_string(),
+            "data: not json, never read".to_string(),
+        ];
+        let chunks = parse(&lines).unwrap();
+        let text: Vec<_> = chunks
+            .iter()
+            .filter_map(|c| Some(c.message.as_ref()?.content.clone()))
+            .collect();
+        assert_eq!(text, vec!["Hel", "lo"]);
+        let last = chunks.last().unwrap();
+        assert!(last.done);
+        assert_eq!(last.model, "gpt-4o");
+        assert_eq!(last.done_reason.as_deref(), Some("stop"));
+        assert_eq!(
+            (last.prompt_eval_count, last.eval_count),
+            (Some(5), Some(2))
+        );
+    }
+
+    #[test]
+    fn assembles_split_tool_call_arguments() {
+        let lines = [
+            tool_deltas(
+                json!([{"index": 0, "id": "call_1", "type": "function", "function": {"name": "read_file", "arguments": ""}}]),
+            ),
+            tool_deltas(json!([{"index": 0, "function": {"arguments": "{\"pa"}}])),
+            tool_deltas(json!([{"index": 0, "function": {"arguments": "th\": \"src/ma"}}])),
+            tool_deltas(json!([{"index": 0, "function": {"arguments": "in.rs\"}"}}])),
+            event(json!({"choices": [{"delta": {}, "finish_reason": "tool_calls"}]})),
+            "data: [DONE]".to_string(),
+        ];
+        let chunks = parse(&lines).unwrap();
+        let calls = tool_calls(&chunks);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id.as_deref(), Some("call_1"));
+        assert_eq!(calls[0].function.name, "read_file");
+        assert_eq!(calls[0].function.arguments, json!({"path": "src/main.rs"}));
+        assert_eq!(
+            chunks.last().unwrap().done_reason.as_deref(),
+            Some("tool_calls")
+        );
+    }
+
+    #[test]
+    fn assembles_parallel_tool_calls_by_index() {
+        let lines = [
+            tool_deltas(json!([
+                {"index": 0, "id": "call_a", "function": {"name": "read_file", "arguments": "{\"path\":"}},
+                {"index": 1, "id": "call_b", "function": {"name": "find_files", "arguments": ""}}
+            ])),
+            tool_deltas(
+                json!([{"index": 1, "function": {"arguments": "{\"pattern\": \"*.rs\"}"}}]),
+            ),
+            tool_deltas(json!([{"index": 0, "function": {"arguments": " \"a.rs\"}"}}])),
+            tool_deltas(
+                json!([{"index": 2, "id": "call_c", "function": {"name": "list_directory"}}]),
+            ),
+            "data: [DONE]".to_string(),
+        ];
+        let calls = tool_calls(&parse(&lines).unwrap());
+        let calls: Vec<_> = calls
+            .iter()
+            .map(|c| {
+                (
+                    c.id.as_deref().unwrap(),
+                    c.function.name.as_str(),
+                    &c.function.arguments,
+                )
+            })
+            .collect();
+        assert_eq!(
+            calls,
+            vec![
+                ("call_a", "read_file", &json!({"path": "a.rs"})),
+                ("call_b", "find_files", &json!({"pattern": "*.rs"})),
+                ("call_c", "list_directory", &json!({})),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_invalid_arguments_as_a_string() {
+        let lines = [
+            tool_deltas(
+                json!([{"index": 0, "id": "call_1", "function": {"name": "read_file", "arguments": "{\"path\": "}}]),
+            ),
+            "data: [DONE]".to_string(),
+        ];
+        let calls = tool_calls(&parse(&lines).unwrap());
+        assert_eq!(calls[0].function.arguments, json!("{\"path\": "));
+    }
+
+    #[test]
+    fn fails_when_the_stream_breaks_off() {
+        let lines = [event(json!({"choices": [{"delta": {"content": "Hel"}}]}))];
+        assert!(matches!(parse(&lines), Err(ChatError::InvalidResponse(_))));
+        //A finish reason without [DONE] is a complete response
+        let lines = [event(
+            json!({"choices": [{"delta": {"content": "Hi"}, "finish_reason": "stop"}]}),
+        )];
+        assert!(parse(&lines).unwrap().last().unwrap().done);
+    }
+
+    #[test]
+    fn reports_errors_in_the_stream() {
+        let lines = [event(json!({"error": {"message": "overloaded"}}))];
+        assert!(matches!(parse(&lines), Err(ChatError::Server(m)) if m == "overloaded"));
+        let lines = [event(json!({"error": "model crashed"}))];
+        assert!(matches!(parse(&lines), Err(ChatError::Server(m)) if m == "model crashed"));
+        let lines = ["data: {broken".to_string()];
+        assert!(matches!(parse(&lines), Err(ChatError::InvalidResponse(_))));
+    }
+}
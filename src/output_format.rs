@@ -0,0 +1,229 @@
+//! Structured output constrained by a JSON schema.
+//!
+//! `chatto run --schema schema.json` and `/format <schema.json|json|off>` in
+//! chat mode send a format with every request, which Ollama uses to force the
+//! response into JSON (matching the schema, if one is given). The final
+//! response is validated against the schema, and an invalid response is sent
+//! back to the model with the validation errors until it conforms or the
+//! retry limit is reached:
+//!
+//! ```yaml
+//! structured_output:
+//!   max_retries: 2
+//! ```
+
+use std::{error::Error, fs};
+
+use jsonschema::Validator;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// The `structured_output` config section
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct StructuredOutputConfig {
+    /// How often an invalid response is sent back to the model
+    pub max_retries: usize,
+}
+
+impl Default for StructuredOutputConfig {
+    fn default() -> Self {
+        Self { max_retries: 2 }
+    }
+}
+
+/// Format the responses have to follow
+pub struct OutputFormat {
+    /// Where the format came from, for display
+    pub source: String,
+    /// Value of the request's `format` field, `"json"` or a schema
+    format: Value,
+    /// Validator for the schema, None for plain JSON
+    validator: Option<Validator>,
+}
+
+impl OutputFormat {
+    /// Creates a format accepting any JSON
+    pub fn json() -> Self {
+        Self {
+            source: "json".to_string(),
+            format: Value::String("json".to_string()),
+            validator: None,
+        }
+    }
+
+    /// Loads a JSON schema from a file
+    ///
+    /// # Arguments
+    /// * `path` - Path of the schema file
+    ///
+    /// # Errors
+    /// Returns error if the file can't be read or isn't a valid JSON schema
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read schema {}: {}", path, e))?;
+        let schema: Value = serde_json::from_str(&text)
+            .map_err(|e| format!("Schema {} is not valid JSON: {}", path, e))?;
+        let validator = jsonschema::validator_for(&schema)
+            .map_err(|e| format!("Schema {} is not a valid JSON schema: {}", path, e))?;
+        Ok(Self {
+            source: path.to_string(),
+            format: schema,
+            validator: Some(validator),
+        })
+    }
+
+    /// Value for the `format` field of requests
+    pub fn format(&self) -> &Value {
+        &self.format
+    }
+
+    /// Checks a response against the format
+    ///
+    /// A response wrapped in a fenced code block is accepted, as some
+    /// OpenAI-compatible servers add one.
+    ///
+    /// # Arguments
+    /// * `content` - Text of the response
+    ///
+    /// # Returns
+    /// The parsed response
+    ///
+    /// # Errors
+    /// Returns the list of problems if the response isn't JSON or doesn't
+    /// match the schema
+    pub fn validate(&self, content: &str) -> Result<Value, Vec<String>> {
+        let value: Value = serde_json::from_str(strip_fence(content))
+            .map_err(|e| vec![format!("The response is not valid JSON: {}", e)])?;
+        let Some(validator) = &self.validator else {
+            return Ok(value);
+        };
+        let errors: Vec<String> = validator
+            .iter_errors(&value)
+            .map(|e| {
+                let path = e.instance_path().to_string();
+                match path.is_empty() {
+                    true => e.to_string(),
+                    false => format!("{}: {}", path, e),
+                }
+            })
+            .collect();
+        match errors.is_empty() {
+            true => Ok(value),
+            false => Err(errors),
+        }
+    }
+}
+
+/// Builds the message sending an invalid response back to the model
+///
+/// # Arguments
+/// * `errors` - The validation errors
+pub fn retry_prompt(errors: &[String]) -> String {
+    format!(
+        "Your response does not match the required JSON format:\n- {}\n\nRespond again with only the corrected JSON.",
+        errors.join("\n- ")
+    )
+}
+
+/// Removes a fenced code block around the content
+fn strip_fence(content: &str) -> &str {
+    let trimmed = content.trim();
+    let Some(rest) = trimmed.strip_prefix("```") else {
+        return trimmed;
+    };
+    //Skip the language tag on the opening fence
+    let body = rest.split_once('\n').map(|(_, body)| body).unwrap_or("");
+    body.trim_end().strip_suffix("```").unwrap_or(body).trim()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Loads a schema from a temporary file
+    fn schema(text: &str) -> Result<OutputFormat, Box<dyn Error>> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("schema.json");
+        fs::write(&path, text).unwrap();
+        OutputFormat::from_file(path.to_str().unwrap())
+    }
+
+    fn person() -> OutputFormat {
+        schema(
+            r#"{
+                "type": "object",
+                "properties": {
+                    "name": {"type": "string"},
+                    "age": {"type": "integer", "minimum": 0}
+                },
+                "required": ["name", "age"]
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn accepts_valid_documents() {
+        let format = person();
+        assert_eq!(format.format()["required"], json!(["name", "age"]));
+        assert_eq!(
+            format.validate(r#"{"name": "Ada", "age": 36}"#),
+            Ok(json!({"name": "Ada", "age": 36}))
+        );
+        //Fenced responses are accepted too
+        assert_eq!(
+            format.validate("```json\n{\"name\": \"Ada\", \"age\": 36}\n```"),
+            Ok(json!({"name": "Ada", "age": 36}))
+        );
+        assert_eq!(OutputFormat::json().validate("[1, 2]"), Ok(json!([1, 2])));
+        assert_eq!(OutputFormat::json().format(), &json!("json"));
+    }
+
+    #[test]
+    fn reports_invalid_documents() {
+        let format = person();
+        let errors = format.validate(r#"{"name": 7, "age": -1}"#).unwrap_err();
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(
+            errors.iter().any(|e| e.starts_with("/name: ")),
+            "{:?}",
+            errors
+        );
+        assert!(
+            errors.iter().any(|e| e.starts_with("/age: ")),
+            "{:?}",
+            errors
+        );
+        let errors = format.validate(r#"{"name": "Ada"}"#).unwrap_err();
+        assert!(errors[0].contains("age"), "{:?}", errors);
+        let errors = format.validate("Sure! Here it is").unwrap_err();
+        assert!(errors[0].starts_with("The response is not valid JSON"));
+        assert!(OutputFormat::json().validate("{").is_err());
+
+        let prompt = retry_prompt(&["/age: bad".into(), "/name: bad".into()]);
+        assert!(
+            prompt.contains("\n- /age: bad\n- /name: bad\n"),
+            "{}",
+            prompt
+        );
+    }
+
+    #[test]
+    fn refuses_bad_schema_files() {
+        let error = schema("{not json").err().unwrap().to_string();
+        assert!(error.contains("is not valid JSON"), "{}", error);
+        let error = schema(r#"{"type": "no-such-type"}"#)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("is not a valid JSON schema"), "{}", error);
+        let error = OutputFormat::from_file("/nonexistent/schema.json")
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.starts_with("Failed to read schema"), "{}", error);
+    }
+}
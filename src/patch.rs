@@ -0,0 +1,763 @@
+//! Unified diff parsing and atomic application for the apply_patch tool.
+//!
+//! Patches are standard unified diffs, as produced by `diff -u` or `git diff`,
+//! and may touch any number of files:
+//!
+//! - **update**: `--- a/path` / `+++ b/path` followed by `@@` hunks
+//! - **add**: `--- /dev/null` (or git's `new file mode`)
+//! - **delete**: `+++ /dev/null` (or git's `deleted file mode`)
+//! - **move**: different old and new paths, or git's `rename from` / `rename to`
+//!
+//! Applying a patch happens in two steps. [`plan_patch`] validates every hunk
+//! against the current files and computes the resulting contents in memory,
+//! without touching the disk. [`apply_changes`] then writes the result,
+//! restoring every file it already changed if a later write fails, so a
+//! patch is never left half-applied.
+//!
+//! ## Fuzz
+//!
+//! Hunks are located by content rather than trusting the line numbers in the
+//! `@@` header, preferring the match closest to the stated line. A hunk whose
+//! lines don't match exactly is retried ignoring trailing and then all
+//! surrounding whitespace, and then with up to two context lines dropped from
+//! each end.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Maximum number of context lines dropped from each end of a hunk
+const MAX_CONTEXT_FUZZ: usize = 2;
+
+/// A line of a hunk
+#[derive(Debug, Clone)]
+enum HunkLine {
+    /// Unchanged line (leading ` `)
+    Context(String),
+    /// Removed line (leading `-`)
+    Remove(String),
+    /// Added line (leading `+`)
+    Add(String),
+}
+
+/// A `@@` section of a file patch
+#[derive(Debug, Clone, Default)]
+struct Hunk {
+    /// Line number in the old file stated in the header (1-based, 0 for empty files)
+    old_start: usize,
+    /// The hunk's lines in order
+    lines: Vec<HunkLine>,
+    /// Whether the new side ends with `\ No newline at end of file`
+    new_no_newline: bool,
+}
+
+impl Hunk {
+    /// Lines the hunk expects in the current file
+    fn old_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|l| match l {
+                HunkLine::Context(s) | HunkLine::Remove(s) => Some(s.as_str()),
+                HunkLine::Add(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// The changes a patch makes to one file
+#[derive(Debug, Clone, Default)]
+struct FilePatch {
+    /// Path before the change, None for added files
+    old_path: Option<String>,
+    /// Path after the change, None for deleted files
+    new_path: Option<String>,
+    /// Hunks in file order
+    hunks: Vec<Hunk>,
+}
+
+/// Resulting state of a file touched by a patch
+#[derive(Debug, Clone)]
+pub struct FileChange {
+    /// Path of the file
+    pub path: String,
+    /// Contents before the patch, None if the file didn't exist
+    pub original: Option<String>,
+    /// Contents after the patch, None if the file is deleted
+    pub content: Option<String>,
+    /// Path the file was moved from, if it was moved
+    pub moved_from: Option<String>,
+}
+
+/// A validated patch, ready to be applied
+#[derive(Debug, Clone)]
+pub struct PatchPlan {
+    /// Final state of every touched file, in the order they were first touched
+    pub changes: Vec<FileChange>,
+    /// One line per file operation, eg. `M src/main.rs`
+    pub summary: Vec<String>,
+    /// Hunks that needed an offset or fuzz to apply
+    pub notes: Vec<String>,
+}
+
+impl PatchPlan {
+    /// Paths of every file the patch creates, changes or deletes
+    pub fn paths(&self) -> Vec<String> {
+        self.changes.iter().map(|c| c.path.clone()).collect()
+    }
+}
+
+/// Parses a unified diff into per-file patches
+///
+/// Text outside of file sections (explanations, code fences) is ignored.
+///
+/// # Arguments
+/// * `text` - The unified diff
+///
+/// # Returns
+/// The file patches in order
+///
+/// # Errors
+/// Returns an error message if the diff contains no file changes or a hunk
+/// appears before any file header
+fn parse_patch(text: &str) -> Result<Vec<FilePatch>, String> {
+    let lines: Vec<&str> = text.lines().map(|l| l.trim_end_matches('\r')).collect();
+    let mut patches: Vec<FilePatch> = Vec::new();
+    //Whether the current patch was started by `diff --git` and still has no ---/+++ or hunks
+    let mut git_header = false;
+    let mut hunk: Option<Hunk> = None;
+    let mut i = 0;
+
+    while i < lines.len() {
+        let line = lines[i];
+        let next = lines.get(i + 1).copied().unwrap_or_default();
+
+        //Hunk body
+        if let Some(h) = hunk.as_mut() {
+            let body = match line.chars().next() {
+                Some(' ') => Some(HunkLine::Context(line[1..].to_string())),
+                Some('-') if !(line.starts_with("--- ") && next.starts_with("+++ ")) => {
+                    Some(HunkLine::Remove(line[1..].to_string()))
+                }
+                Some('+') => Some(HunkLine::Add(line[1..].to_string())),
+                //Models often drop the leading space of empty context lines
+                None => Some(HunkLine::Context(String::new())),
+                Some('\\') => {
+                    if matches!(
+                        h.lines.last(),
+                        Some(HunkLine::Add(_) | HunkLine::Context(_))
+                    ) {
+                        h.new_no_newline = true;
+                    }
+                    i += 1;
+                    continue;
+                }
+                _ => None,
+            };
+            if let Some(body) = body {
+                h.lines.push(body);
+                i += 1;
+                continue;
+            }
+            let h = hunk.take().unwrap();
+            patches.last_mut().unwrap().hunks.push(h);
+        }
+
+        if let Some(rest) = line.strip_prefix("diff --git ") {
+            let (old, new) = rest.split_once(' ').unwrap_or((rest, rest));
+            patches.push(FilePatch {
+                old_path: Some(strip_prefix_dir(old)),
+                new_path: Some(strip_prefix_dir(new)),
+                hunks: Vec::new(),
+            });
+            git_header = true;
+        } else if git_header && line.starts_with("new file mode") {
+            patches.last_mut().unwrap().old_path = None;
+        } else if git_header && line.starts_with("deleted file mode") {
+            patches.last_mut().unwrap().new_path = None;
+        } else if let Some(from) = line.strip_prefix("rename from ").filter(|_| git_header) {
+            patches.last_mut().unwrap().old_path = Some(from.to_string());
+        } else if let Some(to) = line.strip_prefix("rename to ").filter(|_| git_header) {
+            patches.last_mut().unwrap().new_path = Some(to.to_string());
+        } else if line.starts_with("--- ") && next.starts_with("+++ ") {
+            let old = header_path(&line[4..]);
+            let new = header_path(&next[4..]);
+            if git_header {
+                let patch = patches.last_mut().unwrap();
+                patch.old_path = old;
+                patch.new_path = new;
+            } else {
+                patches.push(FilePatch {
+                    old_path: old,
+                    new_path: new,
+                    hunks: Vec::new(),
+                });
+            }
+            git_header = false;
+            i += 1;
+        } else if line.starts_with("@@") {
+            if patches.is_empty() {
+                return Err("Error: hunk found before any ---/+++ file header".to_string());
+            }
+            git_header = false;
+            hunk = Some(Hunk {
+                old_start: parse_old_start(line),
+                ..Hunk::default()
+            });
+        }
+        i += 1;
+    }
+    if let Some(h) = hunk {
+        patches.last_mut().unwrap().hunks.push(h);
+    }
+
+    if patches.is_empty() {
+        return Err(
+            "Error: no file changes found, the patch must be a unified diff with ---/+++ headers and @@ hunks"
+                .to_string(),
+        );
+    }
+    Ok(patches)
+}
+
+/// Validates a patch against the current files and computes the result
+///
+/// # Arguments
+/// * `text` - The unified diff
+///
+/// # Returns
+/// The plan describing the final state of every touched file
+///
+/// # Errors
+/// Returns an error message for the model naming the file and hunk that
+/// failed, if the patch can't be parsed, a hunk doesn't match, a file to
+/// add already exists or a file to change doesn't exist
+pub fn plan_patch(text: &str) -> Result<PatchPlan, String> {
+    let patches = parse_patch(text)?;
+    //Files as the patch sees them, earlier file patches are visible to later ones
+    let mut state: HashMap<String, Option<String>> = HashMap::new();
+    let mut changes: Vec<FileChange> = Vec::new();
+    let mut summary: Vec<String> = Vec::new();
+    let mut notes: Vec<String> = Vec::new();
+
+    let read = |path: &str,
+                state: &mut HashMap<String, Option<String>>,
+                changes: &mut Vec<FileChange>|
+     -> Result<Option<String>, String> {
+        if let Some(content) = state.get(path) {
+            return Ok(content.clone());
+        }
+        let content = match fs::read_to_string(path) {
+            Ok(content) => Some(content),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(format!("Error reading file {}: {}", path, e)),
+        };
+        state.insert(path.to_string(), content.clone());
+        changes.push(FileChange {
+            path: path.to_string(),
+            original: content.clone(),
+            content: content.clone(),
+            moved_from: None,
+        });
+        Ok(content)
+    };
+
+    for patch in patches {
+        match (&patch.old_path, &patch.new_path) {
+            (None, Some(path)) => {
+                if read(path, &mut state, &mut changes)?.is_some() {
+                    return Err(format!(
+                        "Error: cannot add {}, the file already exists",
+                        path
+                    ));
+                }
+                let (content, _) = apply_hunks(path, "", &patch.hunks)?;
+                state.insert(path.clone(), Some(content));
+                summary.push(format!("A {}", path));
+            }
+            (Some(path), None) => {
+                if read(path, &mut state, &mut changes)?.is_none() {
+                    return Err(format!(
+                        "Error: cannot delete {}, the file doesn't exist",
+                        path
+                    ));
+                }
+                state.insert(path.clone(), None);
+                summary.push(format!("D {}", path));
+            }
+            (Some(old), Some(new)) => {
+                let Some(content) = read(old, &mut state, &mut changes)? else {
+                    return Err(format!(
+                        "Error: cannot patch {}, the file doesn't exist",
+                        old
+                    ));
+                };
+                let (content, hunk_notes) = apply_hunks(old, &content, &patch.hunks)?;
+                notes.extend(hunk_notes);
+                if old == new {
+                    summary.push(format!("M {}", old));
+                } else {
+                    if read(new, &mut state, &mut changes)?.is_some() {
+                        return Err(format!(
+                            "Error: cannot move {} to {}, the destination already exists",
+                            old, new
+                        ));
+                    }
+                    state.insert(old.clone(), None);
+                    if let Some(change) = changes.iter_mut().find(|c| c.path == *new) {
+                        change.moved_from = Some(old.clone());
+                    }
+                    summary.push(format!("R {} → {}", old, new));
+                }
+                state.insert(new.clone(), Some(content));
+            }
+            (None, None) => return Err("Error: file header without any paths".to_string()),
+        }
+    }
+
+    for change in changes.iter_mut() {
+        change.content = state.get(&change.path).cloned().flatten();
+    }
+    changes.retain(|c| c.content != c.original);
+    Ok(PatchPlan {
+        changes,
+        summary,
+        notes,
+    })
+}
+
+/// Writes a validated patch to disk
+///
+/// Each file is written to a temporary file next to it and renamed into
+/// place. If any write fails, the files already changed are put back.
+///
+/// # Arguments
+/// * `plan` - The validated patch
+///
+/// # Errors
+/// Returns an error message if a file couldn't be written, after rolling
+/// back the files that were
+pub fn apply_changes(plan: &PatchPlan) -> Result<(), String> {
+    for (i, change) in plan.changes.iter().enumerate() {
+        if let Err(e) = write_state(&change.path, change.content.as_deref()) {
+            let rollback: Vec<String> = plan.changes[..i]
+                .iter()
+                .rev()
+                .filter_map(|c| {
+                    write_state(&c.path, c.original.as_deref())
+                        .err()
+                        .map(|e| format!("{}: {}", c.path, e))
+                })
+                .collect();
+            return Err(match rollback.is_empty() {
+                true => format!(
+                    "Error writing {}: {}. No changes were applied",
+                    change.path, e
+                ),
+                false => format!(
+                    "Error writing {}: {}. Rolling back also failed for {}",
+                    change.path,
+                    e,
+                    rollback.join(", ")
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Puts a file into the given state, writing or deleting it
+fn write_state(path: &str, content: Option<&str>) -> std::io::Result<()> {
+    let path = Path::new(path);
+    match content {
+        Some(content) => {
+            if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(parent)?;
+            }
+            let mut temp = PathBuf::from(path);
+            temp.set_extension(format!(
+                "{}chatto-patch",
+                path.extension()
+                    .map(|e| format!("{}.", e.to_string_lossy()))
+                    .unwrap_or_default()
+            ));
+            fs::write(&temp, content)?;
+            fs::rename(&temp, path).inspect_err(|_| {
+                let _ = fs::remove_file(&temp);
+            })
+        }
+        None => match fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        },
+    }
+}
+
+/// Applies hunks to a file's contents
+///
+/// # Arguments
+/// * `path` - Path of the file, used in messages
+/// * `content` - Current contents of the file
+/// * `hunks` - Hunks to apply, in file order
+///
+/// # Returns
+/// The new contents and notes about hunks applied with an offset or fuzz
+///
+/// # Errors
+/// Returns an error message showing the lines of the first hunk that
+/// couldn't be found
+fn apply_hunks(path: &str, content: &str, hunks: &[Hunk]) -> Result<(String, Vec<String>), String> {
+    let newline = if content.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let mut trailing_newline = content.is_empty() || content.ends_with('\n');
+    let mut lines: Vec<String> = content.lines().map(String::from).collect();
+    let mut notes: Vec<String> = Vec::new();
+    //Hunks apply in order, so each is searched for after the previous one
+    let mut search_from = 0;
+    //Difference between the line numbers in the headers and the current lines
+    let mut offset: isize = 0;
+
+    for (n, hunk) in hunks.iter().enumerate() {
+        let expected = (hunk.old_start.saturating_sub(1) as isize + offset).max(0) as usize;
+        let Some(found) = locate_hunk(&lines, hunk, search_from, expected) else {
+            let old = hunk.old_lines();
+            return Err(format!(
+                "Error: hunk {} (@@ -{}) does not match {}. Expected these lines:\n{}\nUse read_file to check the current contents",
+                n + 1,
+                hunk.old_start,
+                path,
+                old.iter().map(|l| format!("  {}", l)).collect::<Vec<_>>().join("\n")
+            ));
+        };
+
+        //Dropped context lines are left untouched outside of the matched range
+        let body = &hunk.lines[found.skip_start..hunk.lines.len() - found.skip_end];
+        let mut replacement: Vec<String> = Vec::new();
+        let mut file_line = found.at;
+        for line in body {
+            match line {
+                //Keep the file's version of context lines in case they matched with fuzz
+                HunkLine::Context(_) => {
+                    replacement.push(lines[file_line].clone());
+                    file_line += 1;
+                }
+                HunkLine::Remove(_) => file_line += 1,
+                HunkLine::Add(s) => replacement.push(s.clone()),
+            }
+        }
+        let old_len = file_line - found.at;
+        let new_len = replacement.len();
+        let reaches_end = file_line == lines.len();
+        lines.splice(found.at..file_line, replacement);
+        if reaches_end && new_len > 0 {
+            trailing_newline = !hunk.new_no_newline;
+        }
+
+        let stated = expected + found.skip_start;
+        if found.at != stated || found.fuzz > 0 || found.skip_start + found.skip_end > 0 {
+            let mut note = format!("{} hunk {} applied at line {}", path, n + 1, found.at + 1);
+            if found.at != stated {
+                note += &format!(" (offset {:+})", found.at as isize - stated as isize);
+            }
+            match found.fuzz {
+                1 => note += " ignoring trailing whitespace",
+                2 => note += " ignoring whitespace",
+                _ => {}
+            }
+            if found.skip_start + found.skip_end > 0 {
+                note += &format!(
+                    " with {} context line(s) ignored",
+                    found.skip_start + found.skip_end
+                );
+            }
+            notes.push(note);
+        }
+        offset += new_len as isize - old_len as isize + found.at as isize - stated as isize;
+        search_from = found.at + new_len;
+    }
+
+    let mut result = lines.join(newline);
+    if trailing_newline && !lines.is_empty() {
+        result.push_str(newline);
+    }
+    Ok((result, notes))
+}
+
+/// Where a hunk matched in a file
+struct HunkMatch {
+    /// Index of the first matched line
+    at: usize,
+    /// Whitespace tolerance used: 0 exact, 1 trailing, 2 all surrounding
+    fuzz: usize,
+    /// Context lines dropped from the start of the hunk
+    skip_start: usize,
+    /// Context lines dropped from the end of the hunk
+    skip_end: usize,
+}
+
+/// Finds where a hunk applies, trying stricter matches first
+///
+/// # Arguments
+/// * `lines` - Current lines of the file
+/// * `hunk` - The hunk to locate
+/// * `search_from` - First line the hunk may start at
+/// * `expected` - Line the hunk header says it starts at
+///
+/// # Returns
+/// The closest match to `expected` at the strictest level that matches
+fn locate_hunk(
+    lines: &[String],
+    hunk: &Hunk,
+    search_from: usize,
+    expected: usize,
+) -> Option<HunkMatch> {
+    let leading = hunk
+        .lines
+        .iter()
+        .take_while(|l| matches!(l, HunkLine::Context(_)))
+        .count();
+    let trailing = hunk
+        .lines
+        .iter()
+        .rev()
+        .take_while(|l| matches!(l, HunkLine::Context(_)))
+        .count()
+        .min(hunk.lines.len() - leading);
+
+    for skip in 0..=MAX_CONTEXT_FUZZ {
+        let (skip_start, skip_end) = (skip.min(leading), skip.min(trailing));
+        if skip > 0 && skip_start + skip_end == 0 {
+            break;
+        }
+        let body = &hunk.lines[skip_start..hunk.lines.len() - skip_end];
+        let old: Vec<&str> = body
+            .iter()
+            .filter_map(|l| match l {
+                HunkLine::Context(s) | HunkLine::Remove(s) => Some(s.as_str()),
+                HunkLine::Add(_) => None,
+            })
+            .collect();
+        //Pure additions (eg. to an empty file) go where the header says
+        if old.is_empty() {
+            return Some(HunkMatch {
+                at: expected.clamp(search_from, lines.len().max(search_from)),
+                fuzz: 0,
+                skip_start,
+                skip_end,
+            });
+        }
+        for fuzz in 0..=2 {
+            let normalize = |s: &str| -> String {
+                match fuzz {
+                    0 => s.to_string(),
+                    1 => s.trim_end().to_string(),
+                    _ => s.trim().to_string(),
+                }
+            };
+            let old: Vec<String> = old.iter().map(|l| normalize(l)).collect();
+            let expected = expected + skip_start;
+            let best = (search_from..=lines.len().saturating_sub(old.len()))
+                .filter(|&at| {
+                    old.iter()
+                        .zip(&lines[at..at + old.len()])
+                        .all(|(o, l)| *o == normalize(l))
+                })
+                .min_by_key(|&at| at.abs_diff(expected));
+            if let Some(at) = best {
+                return Some(HunkMatch {
+                    at,
+                    fuzz,
+                    skip_start,
+                    skip_end,
+                });
+            }
+        }
+    }
+    None
+}
+
+/// Parses the old start line from a `@@ -l,s +l,s @@` header
+fn parse_old_start(header: &str) -> usize {
+    header
+        .split_whitespace()
+        .find_map(|part| part.strip_prefix('-'))
+        .and_then(|range| range.split(',').next())
+        .and_then(|start| start.parse().ok())
+        .unwrap_or(1)
+}
+
+/// Extracts the path from a `---`/`+++` header, None for /dev/null
+fn header_path(header: &str) -> Option<String> {
+    //Timestamps from diff -u follow a tab
+    let path = header.split('\t').next().unwrap_or_default().trim();
+    if path == "/dev/null" {
+        return None;
+    }
+    Some(strip_prefix_dir(path))
+}
+
+/// Removes git's `a/` and `b/` path prefixes
+fn strip_prefix_dir(path: &str) -> String {
+    path.strip_prefix("a/")
+        .or_else(|| path.strip_prefix("b/"))
+        .unwrap_or(path)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Applies the hunks of a single file patch to `content`
+    fn apply(content: &str, patch: &str) -> Result<(String, Vec<String>), String> {
+        let patches = parse_patch(patch)?;
+        apply_hunks("file.txt", content, &patches[0].hunks)
+    }
+
+    const LINES: &str = "a\nb\nc\nd\ne\n";
+
+    #[test]
+    fn adds_deletes_and_renames_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let d = dir.path().to_str().unwrap();
+        fs::write(format!("{}/gone.txt", d), "bye\n").unwrap();
+        fs::write(format!("{}/old.txt", d), "one\ntwo\n").unwrap();
+        let patch = format!(
+            "--- /dev/null\n+++ {d}/new.txt\n@@ -0,0 +1,2 @@\n+hello\n+world\n\
+             --- {d}/gone.txt\n+++ /dev/null\n@@ -1 +0,0 @@\n-bye\n\
+             diff --git a/{d}/old.txt b/{d}/moved.txt\nrename from {d}/old.txt\nrename to {d}/moved.txt\n\
+             --- a/{d}/old.txt\n+++ b/{d}/moved.txt\n@@ -1,2 +1,2 @@\n one\n-two\n+three\n"
+        );
+        let plan = plan_patch(&patch).unwrap();
+        assert_eq!(
+            plan.summary,
+            vec![
+                format!("A {}/new.txt", d),
+                format!("D {}/gone.txt", d),
+                format!("R {d}/old.txt → {d}/moved.txt"),
+            ]
+        );
+        apply_changes(&plan).unwrap();
+        let read = |name: &str| fs::read_to_string(format!("{}/{}", d, name)).ok();
+        assert_eq!(read("new.txt").as_deref(), Some("hello\nworld\n"));
+        assert_eq!(read("gone.txt"), None);
+        assert_eq!(read("old.txt"), None);
+        assert_eq!(read("moved.txt").as_deref(), Some("one\nthree\n"));
+    }
+
+    #[test]
+    fn refuses_to_add_existing_or_patch_missing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let d = dir.path().to_str().unwrap();
+        fs::write(format!("{}/there.txt", d), "x\n").unwrap();
+        let add = format!("--- /dev/null\n+++ {d}/there.txt\n@@ -0,0 +1 @@\n+y\n");
+        assert!(plan_patch(&add).unwrap_err().contains("already exists"));
+        let update = format!("--- {d}/missing.txt\n+++ {d}/missing.txt\n@@ -1 +1 @@\n-x\n+y\n");
+        assert!(plan_patch(&update).unwrap_err().contains("doesn't exist"));
+    }
+
+    #[test]
+    fn applies_hunks_at_an_offset() {
+        let content = format!("x\ny\nz\n{}", LINES);
+        let (result, notes) =
+            apply(&content, "--- f\n+++ f\n@@ -2,3 +2,3 @@\n b\n-c\n+C\n d\n").unwrap();
+        assert_eq!(result, "x\ny\nz\na\nb\nC\nd\ne\n");
+        assert_eq!(notes.len(), 1);
+        assert!(notes[0].contains("offset +3"), "{:?}", notes);
+    }
+
+    #[test]
+    fn exact_hunks_need_no_notes() {
+        let (result, notes) =
+            apply(LINES, "--- f\n+++ f\n@@ -2,3 +2,3 @@\n b\n-c\n+C\n d\n").unwrap();
+        assert_eq!(result, "a\nb\nC\nd\ne\n");
+        assert!(notes.is_empty(), "{:?}", notes);
+    }
+
+    #[test]
+    fn ignores_trailing_whitespace() {
+        let (result, notes) = apply(
+            "a\nb  \nc\nd\n",
+            "--- f\n+++ f\n@@ -1,3 +1,3 @@\n a\n b\n-c\n+C\n",
+        )
+        .unwrap();
+        //Context lines keep the file's version
+        assert_eq!(result, "a\nb  \nC\nd\n");
+        assert!(
+            notes[0].ends_with("ignoring trailing whitespace"),
+            "{:?}",
+            notes
+        );
+    }
+
+    #[test]
+    fn ignores_surrounding_whitespace() {
+        let (result, notes) = apply(
+            "fn f() {\n\tlet x = 1;\n}\n",
+            "--- f\n+++ f\n@@ -1,3 +1,3 @@\n fn f() {\n-    let x = 1;\n+    let x = 2;\n }\n",
+        )
+        .unwrap();
+        assert_eq!(result, "fn f() {\n    let x = 2;\n}\n");
+        assert!(notes[0].ends_with("ignoring whitespace"), "{:?}", notes);
+    }
+
+    #[test]
+    fn drops_context_lines_that_dont_match() {
+        let (result, notes) = apply(
+            LINES,
+            "--- f\n+++ f\n@@ -1,5 +1,5 @@\n A\n b\n-c\n+C\n d\n E\n",
+        )
+        .unwrap();
+        assert_eq!(result, "a\nb\nC\nd\ne\n");
+        assert!(
+            notes[0].contains("with 2 context line(s) ignored"),
+            "{:?}",
+            notes
+        );
+    }
+
+    #[test]
+    fn fails_when_the_context_is_missing() {
+        let error = apply(LINES, "--- f\n+++ f\n@@ -2,3 +2,3 @@\n x\n-y\n+Y\n z\n").unwrap_err();
+        assert!(error.contains("hunk 1 (@@ -2) does not match"), "{}", error);
+        assert!(error.contains("  x\n  y\n  z"), "{}", error);
+    }
+
+    #[test]
+    fn handles_missing_newline_at_end_of_file() {
+        let keep = "--- f\n+++ f\n@@ -1,2 +1,2 @@\n a\n-b\n\\ No newline at end of file\n+c\n\\ No newline at end of file\n";
+        assert_eq!(apply("a\nb", keep).unwrap().0, "a\nc");
+        let add = "--- f\n+++ f\n@@ -1,2 +1,2 @@\n a\n-b\n\\ No newline at end of file\n+c\n";
+        assert_eq!(apply("a\nb", add).unwrap().0, "a\nc\n");
+        let remove = "--- f\n+++ f\n@@ -1,2 +1,2 @@\n a\n-b\n+c\n\\ No newline at end of file\n";
+        assert_eq!(apply("a\nb\n", remove).unwrap().0, "a\nc");
+    }
+
+    #[test]
+    fn rolls_back_when_a_later_write_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let d = dir.path().to_str().unwrap();
+        let first = format!("{}/first.txt", d);
+        fs::write(&first, "old\n").unwrap();
+        let patch = format!(
+            "--- {d}/first.txt\n+++ {d}/first.txt\n@@ -1 +1 @@\n-old\n+new\n\
+             --- /dev/null\n+++ {d}/second.txt\n@@ -0,0 +1 @@\n+added\n"
+        );
+        let plan = plan_patch(&patch).unwrap();
+        //Something else puts a directory where the second file goes after planning
+        fs::create_dir(format!("{}/second.txt", d)).unwrap();
+        let error = apply_changes(&plan).unwrap_err();
+        assert!(error.contains("No changes were applied"), "{}", error);
+        assert_eq!(fs::read_to_string(&first).unwrap(), "old\n");
+        let mut leftovers: Vec<_> = fs::read_dir(d)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        leftovers.sort();
+        assert_eq!(leftovers, vec!["first.txt", "second.txt"]);
+    }
+}
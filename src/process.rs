@@ -0,0 +1,461 @@
+//! Background processes started by the model.
+//!
+//! The `start_process` tool runs long-lived commands such as dev servers or
+//! file watchers without blocking the chat. Each process is owned by the
+//! session's [`ProcessManager`]: its stdout and stderr are collected in a ring
+//! buffer that the model reads incrementally with `read_process_output`, and
+//! the process groups are killed when the manager is dropped at the end of
+//! the chat, taking any children the processes started with them.
+//!
+//! In chat mode `/ps` lists the processes and `/kill <id>` stops one.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, Read, Write},
+    os::unix::process::CommandExt,
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Bytes of output kept per process
+const BUFFER_SIZE: usize = 64 * 1024;
+/// How long a stopped process gets to exit after SIGTERM before it is killed
+const STOP_GRACE: Duration = Duration::from_secs(2);
+/// How long the children of an exited process get after SIGTERM before they are killed
+const ORPHAN_GRACE: Duration = Duration::from_millis(200);
+
+/// Output of a process, keeping only the most recent bytes
+struct OutputBuffer {
+    /// The most recent output, at most `BUFFER_SIZE` bytes
+    data: VecDeque<u8>,
+    /// Total bytes written since the process started
+    written: u64,
+    /// Value of `written` when the output was last read
+    read: u64,
+}
+
+impl OutputBuffer {
+    fn new() -> Self {
+        Self {
+            data: VecDeque::with_capacity(BUFFER_SIZE),
+            written: 0,
+            read: 0,
+        }
+    }
+
+    /// Appends output, dropping the oldest bytes once the buffer is full
+    fn push(&mut self, bytes: &[u8]) {
+        self.written += bytes.len() as u64;
+        let bytes = &bytes[bytes.len().saturating_sub(BUFFER_SIZE)..];
+        let overflow = (self.data.len() + bytes.len()).saturating_sub(BUFFER_SIZE);
+        self.data.drain(..overflow);
+        self.data.extend(bytes);
+    }
+
+    /// Takes the output written since the last read
+    ///
+    /// # Returns
+    /// The new output, and how many new bytes were dropped before they could be read
+    fn take_new(&mut self) -> (String, u64) {
+        let unread = self.written - self.read;
+        let available = (self.data.len() as u64).min(unread);
+        let start = self.data.len() - available as usize;
+        let bytes: Vec<u8> = self.data.range(start..).copied().collect();
+        self.read = self.written;
+        (
+            String::from_utf8_lossy(&bytes).into_owned(),
+            unread - available,
+        )
+    }
+}
+
+/// Whether a managed process is still running
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProcessStatus {
+    /// The process hasn't exited yet
+    Running,
+    /// The process exited with a code
+    Exited(i32),
+    /// The process was killed by a signal
+    Killed,
+}
+
+impl fmt::Display for ProcessStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessStatus::Running => write!(f, "running"),
+            ProcessStatus::Exited(code) => write!(f, "exited with code {}", code),
+            ProcessStatus::Killed => write!(f, "killed by a signal"),
+        }
+    }
+}
+
+/// A process started by the model
+struct ManagedProcess {
+    id: usize,
+    command: String,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    output: Arc<Mutex<OutputBuffer>>,
+    started: Instant,
+    /// Status once the process has finished
+    exit: Option<ProcessStatus>,
+}
+
+impl ManagedProcess {
+    /// Checks whether the process has exited
+    ///
+    /// An exited process isn't reaped until it is killed, so its pid and
+    /// process group id can't be reused by an unrelated process while the
+    /// group is still being signalled.
+    ///
+    /// # Returns
+    /// Running, or how the process exited
+    fn status(&mut self) -> ProcessStatus {
+        if self.exit.is_none() {
+            self.exit = peek_exit(self.child.id());
+        }
+        self.exit.unwrap_or(ProcessStatus::Running)
+    }
+
+    /// Sends a signal to the process and all of its descendants
+    ///
+    /// The group can outlive the process itself, so this is sent whatever the
+    /// status. Failing with ESRCH only means the whole group is gone.
+    fn signal(&self, signal: libc::c_int) {
+        // SAFETY: kill has no memory safety requirements, a negative pid targets the process group
+        unsafe {
+            libc::kill(-(self.child.id() as libc::pid_t), signal);
+        }
+    }
+
+    /// Kills the process group, then reaps the process
+    fn kill(&mut self) {
+        self.signal(libc::SIGKILL);
+        self.child.wait().unwrap_or_default();
+    }
+}
+
+/// Checks whether a child has exited without reaping it
+///
+/// Until the child is waited for, its pid and process group id can't be
+/// reused, so its group can still be killed safely.
+///
+/// # Arguments
+/// * `pid` - Pid of a child of this process
+///
+/// # Returns
+/// How the child exited, or None while it is running
+pub fn peek_exit(pid: u32) -> Option<ProcessStatus> {
+    // SAFETY: siginfo_t is plain data, all zeroes is a valid value
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    // SAFETY: info is valid for writes, WNOWAIT leaves the child waitable
+    let result = unsafe {
+        libc::waitid(
+            libc::P_PID,
+            pid as libc::id_t,
+            &mut info,
+            libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+        )
+    };
+    // SAFETY: waitid filled in the child fields, si_pid stays 0 if it hasn't exited
+    if result != 0 || unsafe { info.si_pid() } == 0 {
+        return None;
+    }
+    match info.si_code {
+        // SAFETY: as above
+        libc::CLD_EXITED => Some(ProcessStatus::Exited(unsafe { info.si_status() })),
+        _ => Some(ProcessStatus::Killed),
+    }
+}
+
+/// Summary of a managed process for `/ps`
+pub struct ProcessInfo {
+    /// Id used by the process tools and `/kill`
+    pub id: usize,
+    /// Operating system process id
+    pub pid: u32,
+    /// The command line
+    pub command: String,
+    /// Running, or how the process exited
+    pub status: ProcessStatus,
+    /// How long ago the process was started
+    pub uptime: Duration,
+}
+
+/// Background processes belonging to a chat session
+///
+/// Processes are killed when the manager is dropped.
+#[derive(Default)]
+pub struct ProcessManager {
+    processes: Mutex<Vec<ManagedProcess>>,
+    /// Last id handed out, ids aren't reused after a process is stopped
+    last_id: AtomicUsize,
+}
+
+impl ProcessManager {
+    /// Creates a manager with no processes
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a command in the background
+    ///
+    /// The command gets its own process group, and its stdout and stderr are
+    /// collected into the process's output buffer.
+    ///
+    /// # Arguments
+    /// * `command` - The command, with its environment and working directory set up
+    /// * `command_line` - The command line, for listings
+    ///
+    /// # Returns
+    /// Id and operating system pid of the new process
+    ///
+    /// # Errors
+    /// Returns error if the command cannot be started
+    pub fn start(&self, mut command: Command, command_line: &str) -> io::Result<(usize, u32)> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .spawn()?;
+        let output = Arc::new(Mutex::new(OutputBuffer::new()));
+        collect_output(child.stdout.take(), output.clone());
+        collect_output(child.stderr.take(), output.clone());
+
+        let mut processes = self.processes.lock().unwrap();
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let pid = child.id();
+        processes.push(ManagedProcess {
+            id,
+            command: command_line.to_string(),
+            stdin: child.stdin.take(),
+            child,
+            output,
+            started: Instant::now(),
+            exit: None,
+        });
+        Ok((id, pid))
+    }
+
+    /// Reads the output a process wrote since it was last read
+    ///
+    /// # Arguments
+    /// * `id` - Id of the process
+    ///
+    /// # Returns
+    /// The process status, the new output and the number of bytes dropped
+    /// because the buffer overflowed before they were read
+    ///
+    /// # Errors
+    /// Returns an error message if there is no such process
+    pub fn read_output(&self, id: usize) -> Result<(ProcessStatus, String, u64), String> {
+        let mut processes = self.processes.lock().unwrap();
+        let process = find(&mut processes, id)?;
+        let status = process.status();
+        let (output, dropped) = process.output.lock().unwrap().take_new();
+        Ok((status, output, dropped))
+    }
+
+    /// Writes to a process's stdin
+    ///
+    /// # Arguments
+    /// * `id` - Id of the process
+    /// * `input` - Text to write
+    ///
+    /// # Errors
+    /// Returns an error message if there is no such process or it stopped reading
+    pub fn send_input(&self, id: usize, input: &str) -> Result<(), String> {
+        let mut processes = self.processes.lock().unwrap();
+        let process = find(&mut processes, id)?;
+        if process.status() != ProcessStatus::Running {
+            return Err(format!("Error: process #{} has {}", id, process.status()));
+        }
+        let stdin = process
+            .stdin
+            .as_mut()
+            .ok_or(format!("Error: process #{} has no stdin", id))?;
+        stdin
+            .write_all(input.as_bytes())
+            .and_then(|_| stdin.flush())
+            .map_err(|e| format!("Error: failed to write to process #{}: {}", id, e))
+    }
+
+    /// Stops a process and removes it from the manager
+    ///
+    /// The process group gets SIGTERM, and SIGKILL if the process hasn't
+    /// exited after a short grace period. The group is signalled even when
+    /// the process itself has exited, so nothing it started keeps running.
+    /// Its children then get `ORPHAN_GRACE` to exit, as there is no telling
+    /// when they are done.
+    ///
+    /// # Arguments
+    /// * `id` - Id of the process
+    ///
+    /// # Returns
+    /// How the process ended and the output it wrote since it was last read
+    ///
+    /// # Errors
+    /// Returns an error message if there is no such process
+    pub fn stop(&self, id: usize) -> Result<(ProcessStatus, String), String> {
+        let mut process = {
+            let mut processes = self.processes.lock().unwrap();
+            let index = processes
+                .iter()
+                .position(|p| p.id == id)
+                .ok_or(format!("Error: no background process #{}", id))?;
+            processes.remove(index)
+        };
+        let running = process.status() == ProcessStatus::Running;
+        process.signal(libc::SIGTERM);
+        if running {
+            let deadline = Instant::now() + STOP_GRACE;
+            while process.status() == ProcessStatus::Running && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(50));
+            }
+        } else {
+            thread::sleep(ORPHAN_GRACE);
+        }
+        process.kill();
+        //Let the readers pick up anything written while the process shut down
+        thread::sleep(Duration::from_millis(50));
+        let status = process.status();
+        let (output, _) = process.output.lock().unwrap().take_new();
+        Ok((status, output))
+    }
+
+    /// Lists the managed processes
+    pub fn list(&self) -> Vec<ProcessInfo> {
+        self.processes
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .map(|p| ProcessInfo {
+                id: p.id,
+                pid: p.child.id(),
+                command: p.command.clone(),
+                status: p.status(),
+                uptime: p.started.elapsed(),
+            })
+            .collect()
+    }
+}
+
+impl Drop for ProcessManager {
+    fn drop(&mut self) {
+        for process in self.processes.get_mut().unwrap().iter_mut() {
+            process.kill();
+        }
+    }
+}
+
+/// Looks up a process by id
+fn find(processes: &mut [ManagedProcess], id: usize) -> Result<&mut ManagedProcess, String> {
+    processes
+        .iter_mut()
+        .find(|p| p.id == id)
+        .ok_or(format!("Error: no background process #{}", id))
+}
+
+/// Copies a child's pipe into its output buffer on a background thread
+fn collect_output(pipe: Option<impl Read + Send + 'static>, output: Arc<Mutex<OutputBuffer>>) {
+    let Some(mut pipe) = pipe else {
+        return;
+    };
+    thread::spawn(move || {
+        let mut chunk = [0; 4096];
+        loop {
+            match pipe.read(&mut chunk) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => output.lock().unwrap().push(&chunk[..n]),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether a process is alive, zombies waiting to be reaped count as dead
+    fn alive(pid: &str) -> bool {
+        std::fs::read_to_string(format!("/proc/{}/stat", pid))
+            .is_ok_and(|stat| !stat.contains(") Z "))
+    }
+
+    #[test]
+    fn stop_kills_children_of_exited_processes() {
+        let manager = ProcessManager::new();
+        let mut command = Command::new("sh");
+        command.args(["-c", "sleep 1000 & echo $!"]);
+        let (id, _) = manager.start(command, "sleep 1000 &").unwrap();
+        //Wait for sh to exit, leaving sleep behind in its process group
+        let mut output = String::new();
+        for _ in 0..100 {
+            let (status, new, _) = manager.read_output(id).unwrap();
+            output.push_str(&new);
+            if status != ProcessStatus::Running && !output.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let child = output.trim().to_string();
+        assert!(alive(&child), "sleep {:?} should be running", child);
+
+        let (status, _) = manager.stop(id).unwrap();
+        assert_eq!(status, ProcessStatus::Exited(0));
+        for _ in 0..100 {
+            if !alive(&child) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("sleep {} outlived stop", child);
+    }
+
+    #[test]
+    fn exited_processes_stay_waitable_until_stopped() {
+        let manager = ProcessManager::new();
+        let (id, pid) = manager.start(Command::new("true"), "true").unwrap();
+        for _ in 0..100 {
+            if manager.read_output(id).unwrap().0 != ProcessStatus::Running {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(manager.read_output(id).unwrap().0, ProcessStatus::Exited(0));
+        //The zombie keeps the pid and process group from being reused
+        let pid = pid.to_string();
+        assert!(std::path::Path::new(&format!("/proc/{}", pid)).exists());
+        manager.stop(id).unwrap();
+        assert!(!std::path::Path::new(&format!("/proc/{}", pid)).exists());
+    }
+
+    #[test]
+    fn stop_terminates_children_before_killing_them() {
+        let manager = ProcessManager::new();
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            "sh -c 'trap \"echo terminated; exit\" TERM; while :; do sleep 0.02; done' & echo started",
+        ]);
+        let (id, _) = manager.start(command, "trap").unwrap();
+        for _ in 0..100 {
+            let (status, output, _) = manager.read_output(id).unwrap();
+            if status != ProcessStatus::Running && output.contains("started") {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let (_, output) = manager.stop(id).unwrap();
+        //The shell may also report the killed sleep, in either order
+        assert!(output.lines().any(|l| l == "terminated"), "{:?}", output);
+    }
+}
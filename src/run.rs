@@ -0,0 +1,216 @@
+//! Non-interactive one-shot mode for chatto.
+//!
+//! This module implements `chatto run`, which sends a single prompt (from the
+//! command line and/or stdin) through the same agent loop as chat mode and
+//! prints the final assistant response to stdout. It is intended for shell
+//! pipelines, git hooks and Makefiles.
+//!
+//! Only the final response is written to stdout. Tool previews and progress
+//! are written to stderr so they don't end up in a pipe.
+//!
+//! ## Exit Codes
+//!
+//! - `0` - The model produced a final response
+//! - `1` - An error occurred (connection, API, session file, ...)
+//! - `2` - No prompt was given on the command line or stdin
+//! - `3` - The turn limit was reached before the model finished
+//! - `4` - The final response didn't match `--schema` after all retries
+
+use std::io::{self, IsTerminal, Read};
+
+use reqwest::Client;
+
+use crate::{
+    app::{ApplicationState, ToolApproval, ToolExecutor},
+    backend::ChatBackend,
+    chat::create_system_prompt,
+    ollama::OllamaChatMessage,
+    output_format::{retry_prompt, OutputFormat},
+    ApplicationConfig,
+};
+
+/// Exit code when the model produced a final response
+pub const EXIT_SUCCESS: i32 = 0;
+/// Exit code when no prompt was given on the command line or stdin
+pub const EXIT_NO_PROMPT: i32 = 2;
+/// Exit code when the turn limit was reached before the model finished
+pub const EXIT_MAX_TURNS: i32 = 3;
+/// Exit code when the final response didn't match the schema after all retries
+pub const EXIT_INVALID_OUTPUT: i32 = 4;
+
+/// Runs a single prompt through the agent loop and prints the final response
+///
+/// The prompt argument and piped stdin are combined (argument first) into a
+/// single user message. Tool calls follow the configured approval policy,
+/// except that calls the policy would ask about are executed when
+/// `approve_tools` is set and rejected otherwise, since there is nobody to ask.
+///
+/// # Arguments
+/// * `client` - HTTP client for API requests
+/// * `app_config` - Application configuration (URL, model, etc.)
+/// * `prompt` - Optional prompt from the command line
+/// * `session` - Optional session name to load/save
+/// * `approve_tools` - Whether calls that need approval are approved
+/// * `max_turns` - Maximum number of requests to send to the model
+/// * `schema` - Optional JSON schema file the final response has to match
+///
+/// # Returns
+/// The process exit code
+///
+/// # Errors
+/// Returns error if stdin can't be read, the schema can't be loaded, the
+/// session can't be loaded or saved, or an API request fails
+pub async fn run_mode(
+    client: &Client,
+    mut app_config: ApplicationConfig,
+    prompt: Option<String>,
+    session: Option<String>,
+    approve_tools: bool,
+    max_turns: usize,
+    schema: Option<String>,
+) -> Result<i32, Box<dyn std::error::Error>> {
+    let Some(prompt) = read_prompt(prompt)? else {
+        eprintln!("Error: no prompt given, pass one as an argument or pipe it on stdin");
+        return Ok(EXIT_NO_PROMPT);
+    };
+    let output_format = schema.as_deref().map(OutputFormat::from_file).transpose()?;
+
+    let mut app_state = if let Some(ref session_name) = session {
+        ApplicationState::load_session(session_name, &app_config)?
+    } else {
+        ApplicationState::new_from_config(&app_config)
+    };
+    let backend = app_config.backend();
+    app_config.load_model(client, &app_state.model).await?;
+    //Background processes are killed when this goes out of scope at the end of the run
+    let executor = ToolExecutor::new(&app_config)?;
+    app_state.tools = executor.definitions();
+
+    if app_state.messages.is_empty() {
+        app_state.messages.push(OllamaChatMessage {
+            role: "system".to_string(),
+            content: create_system_prompt(&app_config, &app_state.model, &app_state.tools),
+            tool_calls: None,
+            tool_name: None,
+            tool_call_id: None,
+            images: None,
+            model: None,
+        });
+    }
+    app_state.add_user_message(&prompt);
+
+    app_state.format = output_format.as_ref().map(|f| f.format().clone());
+
+    let mut turns = 0;
+    let mut retries = 0;
+    let exit_code = loop {
+        let last = app_state.messages.last().cloned().unwrap_or_default();
+        if let Some(tool_calls) = last.tool_calls.filter(|tc| !tc.is_empty()) {
+            //There is nobody to ask, so --approve-tools decides
+            executor.execute(&app_config, &mut app_state, &tool_calls, |_| {
+                if approve_tools {
+                    return ToolApproval::Approve;
+                }
+                eprintln!(" ⛔ Rejected, tool calls need --approve-tools");
+                ToolApproval::Reject(
+                    "tool calls are not approved in non-interactive mode".to_string(),
+                )
+            });
+            continue;
+        }
+
+        if last.role == "assistant" {
+            //A response that doesn't match the schema goes back to the model with the errors
+            match output_format
+                .as_ref()
+                .map(|f| (f, f.validate(&last.content)))
+            {
+                None | Some((_, Ok(_))) => {
+                    println!("{}", last.content);
+                    break EXIT_SUCCESS;
+                }
+                Some((format, Err(errors))) => {
+                    eprintln!("Response doesn't match {}:", format.source);
+                    for error in &errors {
+                        eprintln!("  - {}", error);
+                    }
+                    if retries >= app_config.structured_output.max_retries {
+                        eprintln!("Error: giving up after {} retries", retries);
+                        break EXIT_INVALID_OUTPUT;
+                    }
+                    retries += 1;
+                    app_state.add_user_message(&retry_prompt(&errors));
+                }
+            }
+        }
+
+        if turns >= max_turns {
+            eprintln!(
+                "Error: reached the limit of {} turns without a final response",
+                max_turns
+            );
+            break EXIT_MAX_TURNS;
+        }
+        turns += 1;
+
+        if app_state.needs_compaction(&app_config) {
+            eprintln!("Context is close to the model's limit, compacting...");
+            if let Err(e) = app_state
+                .compact(client, &app_config, app_config.compaction.keep_turns)
+                .await
+            {
+                eprintln!("Error compacting: {}", e);
+            }
+        }
+
+        let mut request = app_state.create_request(&app_config);
+        request.stream = false;
+        let (response, _) = backend
+            .chat(client, &request, Option::<&mut ApplicationState>::None)
+            .await?;
+        app_state.add_assistant_response(response);
+        //Models without tool support write their tool calls into the response
+        if !app_config.has_capability(&app_state.model, "tools") {
+            if let Err(e) = app_state
+                .extract_tool_calls(client, &app_config, app_config.tool_parsing.strategy)
+                .await
+            {
+                eprintln!("Error extracting tool calls: {}", e);
+            }
+        }
+    };
+
+    if let Some(ref session_name) = session {
+        app_state.save_session(session_name, &app_config)?;
+    }
+    Ok(exit_code)
+}
+
+/// Combines the prompt argument and piped stdin into a single prompt
+///
+/// Stdin is only read when it is not a terminal, so `chatto run "hi"` doesn't
+/// wait for input.
+///
+/// # Arguments
+/// * `prompt` - Optional prompt from the command line
+///
+/// # Returns
+/// The combined prompt, or None if both sources were empty
+///
+/// # Errors
+/// Returns error if stdin cannot be read
+fn read_prompt(prompt: Option<String>) -> io::Result<Option<String>> {
+    let mut parts: Vec<String> = Vec::new();
+    if let Some(prompt) = prompt.filter(|p| !p.trim().is_empty()) {
+        parts.push(prompt);
+    }
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        let mut piped = String::new();
+        stdin.lock().read_to_string(&mut piped)?;
+        if !piped.trim().is_empty() {
+            parts.push(piped);
+        }
+    }
+    Ok((!parts.is_empty()).then(|| parts.join("\n\n")))
+}
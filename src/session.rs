@@ -0,0 +1,596 @@
+//! Session storage and the `chatto sessions` subcommand.
+//!
+//! Sessions are saved as YAML files named `<name>.yaml` in a session store.
+//! By default the store is made up of two directories:
+//!
+//! - **project**: `.chatto/sessions` in the current directory, where new
+//!   sessions are saved
+//! - **global**: `$XDG_DATA_HOME/chatto/sessions` (or
+//!   `~/.local/share/chatto/sessions`)
+//!
+//! Setting `sessions.dir` in the configuration replaces both with a single
+//! directory. Sessions saved by older versions as `.chatto-<name>.session.yaml`
+//! in the current directory are still found, and are saved into the store
+//! the next time they are written.
+//!
+//! ## Subcommands
+//!
+//! - `list` - Table of sessions with model, message count, tokens and last
+//!   update, with forks listed below their parent session
+//! - `show <name>` - Print a session's metadata and messages
+//! - `rm <name>` - Delete a session
+//! - `rename <from> <to>` - Rename a session
+//! - `fork <from> <to> [--at <index>]` - Copy a session's history up to a message
+//! - `export <name>` - Export a session as markdown, json or yaml
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use chrono::Utc;
+use clap::Subcommand;
+use serde::Deserialize;
+use strum::{Display, EnumString};
+
+use crate::app::ApplicationState;
+
+/// Session store configuration loaded from the `sessions` config section
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// Single directory to store sessions in instead of the project and global directories
+    pub dir: Option<PathBuf>,
+}
+
+/// A session file found in the store
+pub struct SessionEntry {
+    /// Name of the session
+    pub name: String,
+    /// Label of the location the session was found in (project, global, ...)
+    pub location: String,
+    /// Path of the session file
+    pub path: PathBuf,
+}
+
+/// Directories sessions are loaded from and saved to
+pub struct SessionStore {
+    /// Labelled directories, new sessions are saved in the first one
+    dirs: Vec<(String, PathBuf)>,
+}
+
+impl SessionStore {
+    /// Creates the session store described by the configuration
+    ///
+    /// # Arguments
+    /// * `config` - The sessions section of the application configuration
+    ///
+    /// # Returns
+    /// Store using the configured directory, or the project and global directories
+    pub fn new(config: &SessionConfig) -> Self {
+        if let Some(dir) = &config.dir {
+            //Expand a leading ~ since config files can't rely on the shell for it
+            let dir = match (dir.strip_prefix("~"), dir::home_dir()) {
+                (Ok(rest), Some(home)) => home.join(rest),
+                _ => dir.clone(),
+            };
+            return Self {
+                dirs: vec![("config".to_string(), dir)],
+            };
+        }
+        let mut dirs = vec![("project".to_string(), PathBuf::from(".chatto/sessions"))];
+        if let Some(global) = global_dir() {
+            dirs.push(("global".to_string(), global));
+        }
+        Self { dirs }
+    }
+
+    /// Finds an existing session by name
+    ///
+    /// The store directories are searched in order, followed by the legacy
+    /// `.chatto-<name>.session.yaml` file in the current directory.
+    ///
+    /// # Arguments
+    /// * `name` - Name of the session
+    ///
+    /// # Returns
+    /// The session entry if a session file exists
+    pub fn find(&self, name: &str) -> Option<SessionEntry> {
+        self.dirs
+            .iter()
+            .map(|(label, dir)| (label.as_str(), dir.join(format!("{}.yaml", name))))
+            .chain(std::iter::once(("legacy", legacy_path(name))))
+            .find(|(_, path)| path.is_file())
+            .map(|(label, path)| SessionEntry {
+                name: name.to_string(),
+                location: label.to_string(),
+                path,
+            })
+    }
+
+    /// Loads a session by name
+    ///
+    /// # Arguments
+    /// * `name` - Name of the session
+    ///
+    /// # Returns
+    /// The session state, or None if no session with that name exists
+    ///
+    /// # Errors
+    /// Returns error if the name is invalid or the session file exists but
+    /// cannot be read or parsed
+    pub fn load(&self, name: &str) -> Result<Option<ApplicationState>, Box<dyn std::error::Error>> {
+        validate_name(name)?;
+        match self.find(name) {
+            Some(entry) => Ok(Some(read_session(&entry.path)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Saves a session by name
+    ///
+    /// Sessions already in a store directory are saved in place, anything
+    /// else (new or legacy sessions) is saved in the first store directory.
+    ///
+    /// # Arguments
+    /// * `name` - Name of the session
+    /// * `app_state` - The session state to save
+    ///
+    /// # Returns
+    /// Path the session was written to
+    ///
+    /// # Errors
+    /// Returns error if the name is invalid or the file cannot be written
+    pub fn save(
+        &self,
+        name: &str,
+        app_state: &ApplicationState,
+    ) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        validate_name(name)?;
+        let path = match self.find(name) {
+            Some(entry) if entry.location != "legacy" => entry.path,
+            _ => self.dirs[0].1.join(format!("{}.yaml", name)),
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, serde_yaml::to_string(app_state)?)?;
+        Ok(path)
+    }
+
+    /// Lists all sessions in the store
+    ///
+    /// A name found in several locations is only listed once, for the
+    /// location it would be loaded from.
+    ///
+    /// # Returns
+    /// Session entries in store order
+    ///
+    /// # Errors
+    /// Returns error if a store directory exists but cannot be read
+    pub fn list(&self) -> Result<Vec<SessionEntry>, Box<dyn std::error::Error>> {
+        let mut entries: Vec<SessionEntry> = Vec::new();
+        for (label, dir) in &self.dirs {
+            if !dir.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(dir)? {
+                let path = file?.path();
+                let Some(name) = path
+                    .file_name()
+                    .and_then(|f| f.to_str())
+                    .and_then(|f| f.strip_suffix(".yaml"))
+                else {
+                    continue;
+                };
+                if path.is_file() && !entries.iter().any(|e| e.name == name) {
+                    entries.push(SessionEntry {
+                        name: name.to_string(),
+                        location: label.clone(),
+                        path: path.clone(),
+                    });
+                }
+            }
+        }
+        for file in fs::read_dir(".")? {
+            let path = file?.path();
+            let Some(name) = path
+                .file_name()
+                .and_then(|f| f.to_str())
+                .and_then(|f| f.strip_prefix(".chatto-"))
+                .and_then(|f| f.strip_suffix(".session.yaml"))
+            else {
+                continue;
+            };
+            if !entries.iter().any(|e| e.name == name) {
+                entries.push(SessionEntry {
+                    name: name.to_string(),
+                    location: "legacy".to_string(),
+                    path: path.clone(),
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Deletes a session
+    ///
+    /// # Arguments
+    /// * `name` - Name of the session
+    ///
+    /// # Errors
+    /// Returns error if the session doesn't exist or cannot be deleted
+    pub fn remove(&self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let entry = self.require(name)?;
+        fs::remove_file(&entry.path)?;
+        Ok(())
+    }
+
+    /// Renames a session
+    ///
+    /// The session stays in its directory. Legacy sessions are moved into
+    /// the first store directory.
+    ///
+    /// # Arguments
+    /// * `from` - Current name of the session
+    /// * `to` - New name of the session
+    ///
+    /// # Errors
+    /// Returns error if either name is invalid, the session doesn't exist or
+    /// a session named `to` already exists
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), Box<dyn std::error::Error>> {
+        validate_name(to)?;
+        let entry = self.require(from)?;
+        if self.find(to).is_some() {
+            return Err(format!("Session '{}' already exists", to).into());
+        }
+        let dir = match entry.location.as_str() {
+            "legacy" => self.dirs[0].1.clone(),
+            _ => entry
+                .path
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default(),
+        };
+        fs::create_dir_all(&dir)?;
+        fs::rename(&entry.path, dir.join(format!("{}.yaml", to)))?;
+        Ok(())
+    }
+
+    /// Finds an existing session or fails with a "not found" error
+    fn require(&self, name: &str) -> Result<SessionEntry, Box<dyn std::error::Error>> {
+        validate_name(name)?;
+        self.find(name)
+            .ok_or_else(|| format!("Session '{}' not found", name).into())
+    }
+}
+
+/// Actions of the `chatto sessions` subcommand
+#[derive(Subcommand)]
+pub enum SessionsCommand {
+    /// List saved sessions
+    List,
+    /// Print a session's metadata and messages
+    Show {
+        /// Name of the session
+        name: String,
+    },
+    /// Delete a session
+    Rm {
+        /// Name of the session
+        name: String,
+    },
+    /// Rename a session
+    Rename {
+        /// Current name of the session
+        from: String,
+        /// New name of the session
+        to: String,
+    },
+    /// Create a new session sharing another session's history up to a message
+    Fork {
+        /// Name of the session to fork
+        from: String,
+        /// Name of the new session
+        to: String,
+        /// Index of the last message to keep (as shown by `show`), defaults to all
+        #[arg(long)]
+        at: Option<usize>,
+    },
+    /// Export a session
+    Export {
+        /// Name of the session
+        name: String,
+        /// Output format: markdown, json or yaml
+        #[arg(short, long, default_value_t = ExportFormat::Markdown)]
+        format: ExportFormat,
+        /// File to write to instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+/// Format used by `chatto sessions export`
+#[derive(Debug, Clone, Copy, Display, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum ExportFormat {
+    /// Readable transcript
+    Markdown,
+    /// The session state as JSON
+    Json,
+    /// The session state as YAML (same as the session file)
+    Yaml,
+}
+
+/// Runs a `chatto sessions` subcommand
+///
+/// # Arguments
+/// * `config` - The sessions section of the application configuration
+/// * `command` - The subcommand to run
+///
+/// # Errors
+/// Returns error if a session is not found or a file operation fails
+pub fn sessions_command(
+    config: &SessionConfig,
+    command: SessionsCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let store = SessionStore::new(config);
+    match command {
+        SessionsCommand::List => {
+            let mut sessions: Vec<(SessionEntry, Option<ApplicationState>)> = store
+                .list()?
+                .into_iter()
+                .map(|e| {
+                    let state = read_session(&e.path).ok();
+                    (e, state)
+                })
+                .collect();
+            //Most recently updated first
+            sessions.sort_by_key(|(_, s)| std::cmp::Reverse(s.as_ref().and_then(|s| s.updated_at)));
+            println!(
+                "{:<24} {:<24} {:>8} {:>8} {:<17} LOCATION",
+                "NAME", "MODEL", "MESSAGES", "TOKENS", "UPDATED"
+            );
+            for (depth, (entry, state)) in fork_tree(&sessions) {
+                //Forks are indented below the session they were forked from
+                let name = match depth {
+                    0 => entry.name.clone(),
+                    _ => format!("{}└ {}", "  ".repeat(depth - 1), entry.name),
+                };
+                match state {
+                    Some(s) => println!(
+                        "{:<24} {:<24} {:>8} {:>8} {:<17} {}",
+                        name,
+                        s.model,
+                        s.message_count,
+                        s.token_estimate,
+                        format_time(&s.updated_at),
+                        entry.location
+                    ),
+                    None => println!(
+                        "{:<24} {:<24} {:>8} {:>8} {:<17} {}",
+                        name, "(unreadable)", "-", "-", "-", entry.location
+                    ),
+                }
+            }
+        }
+        SessionsCommand::Show { name } => {
+            let entry = store.require(&name)?;
+            let state = read_session(&entry.path)?;
+            println!("Session: {}", name);
+            println!("Path: {}", entry.path.display());
+            println!("Model: {}", state.model);
+            println!("Created: {}", format_time(&state.created_at));
+            println!("Updated: {}", format_time(&state.updated_at));
+            if let Some(parent) = &state.parent_session {
+                let parent_name = store
+                    .list()?
+                    .into_iter()
+                    .find(|e| read_session(&e.path).is_ok_and(|s| s.session_id == *parent))
+                    .map(|e| e.name)
+                    .unwrap_or_else(|| format!("(unsaved session {})", parent));
+                println!(
+                    "Forked from: {} at message {}",
+                    parent_name,
+                    state.forked_at.unwrap_or_default()
+                );
+            }
+            println!(
+                "Messages: {} (~{} tokens)",
+                state.message_count, state.token_estimate
+            );
+            for (i, m) in state.messages.iter().enumerate() {
+                match &m.model {
+                    Some(model) => println!("\n[{}] {} ({}):", i, m.role, model),
+                    None => println!("\n[{}] {}:", i, m.role),
+                }
+                println!("{}", m.content);
+                for tc in m.tool_calls.iter().flatten() {
+                    println!("  → {} {}", tc.function.name, tc.function.arguments);
+                }
+            }
+        }
+        SessionsCommand::Rm { name } => {
+            store.remove(&name)?;
+            println!("Session removed: {}", name);
+        }
+        SessionsCommand::Rename { from, to } => {
+            store.rename(&from, &to)?;
+            println!("Session renamed: {} → {}", from, to);
+        }
+        SessionsCommand::Fork { from, to, at } => {
+            validate_name(&to)?;
+            if store.find(&to).is_some() {
+                return Err(format!("Session '{}' already exists", to).into());
+            }
+            let entry = store.require(&from)?;
+            let mut parent = read_session(&entry.path)?;
+            //Sessions saved before ids were generated need one to be referenced
+            if parent.session_id.is_empty() {
+                parent.session_id = new_session_id();
+                store.save(&from, &parent)?;
+            }
+            let mut forked = parent.fork(at)?;
+            forked.updated_at = Some(Utc::now());
+            forked.refresh_metadata();
+            store.save(&to, &forked)?;
+            println!(
+                "Session forked: {} → {} (at message {})",
+                from,
+                to,
+                forked.forked_at.unwrap_or_default()
+            );
+        }
+        SessionsCommand::Export {
+            name,
+            format,
+            output,
+        } => {
+            let entry = store.require(&name)?;
+            let state = read_session(&entry.path)?;
+            let content = match format {
+                ExportFormat::Markdown => export_markdown(&name, &state),
+                ExportFormat::Json => serde_json::to_string_pretty(&state)?,
+                ExportFormat::Yaml => serde_yaml::to_string(&state)?,
+            };
+            match output {
+                Some(path) => {
+                    fs::write(&path, content)?;
+                    println!("Session exported to {}", path.display());
+                }
+                None => println!("{}", content),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Renders a session as a markdown transcript
+///
+/// # Arguments
+/// * `name` - Name of the session, used as the title
+/// * `state` - The session state
+///
+/// # Returns
+/// Markdown with one section per message
+fn export_markdown(name: &str, state: &ApplicationState) -> String {
+    let mut out = format!("# Session: {}\n\n", name);
+    out.push_str(&format!("- Model: {}\n", state.model));
+    out.push_str(&format!("- Created: {}\n", format_time(&state.created_at)));
+    out.push_str(&format!("- Updated: {}\n", format_time(&state.updated_at)));
+    out.push_str(&format!("- Messages: {}\n", state.messages.len()));
+    for m in &state.messages {
+        match m.tool_name.as_ref().or(m.model.as_ref()) {
+            Some(label) => out.push_str(&format!("\n## {} ({})\n\n", m.role, label)),
+            None => out.push_str(&format!("\n## {}\n\n", m.role)),
+        }
+        if m.role == "tool" {
+            out.push_str(&format!("```\n{}\n```\n", m.content));
+        } else {
+            out.push_str(&format!("{}\n", m.content));
+        }
+        for tc in m.tool_calls.iter().flatten() {
+            out.push_str(&format!(
+                "\nTool call `{}`:\n\n```json\n{}\n```\n",
+                tc.function.name,
+                serde_json::to_string_pretty(&tc.function.arguments).unwrap_or_default()
+            ));
+        }
+    }
+    out
+}
+
+/// Orders sessions so forks follow the session they were forked from
+///
+/// # Arguments
+/// * `sessions` - Sessions in the order roots should be listed
+///
+/// # Returns
+/// The sessions with their depth in the fork tree, depth first
+fn fork_tree(
+    sessions: &[(SessionEntry, Option<ApplicationState>)],
+) -> Vec<(usize, &(SessionEntry, Option<ApplicationState>))> {
+    let id = |i: usize| sessions[i].1.as_ref().map(|s| s.session_id.as_str());
+    let parent = |i: usize| {
+        sessions[i]
+            .1
+            .as_ref()
+            .and_then(|s| s.parent_session.as_deref())
+            .and_then(|p| (0..sessions.len()).find(|&j| j != i && id(j) == Some(p)))
+    };
+    let mut ordered = Vec::new();
+    let mut stack: Vec<(usize, usize)> = (0..sessions.len())
+        .rev()
+        .filter(|&i| parent(i).is_none())
+        .map(|i| (0, i))
+        .collect();
+    while let Some((depth, i)) = stack.pop() {
+        ordered.push((depth, &sessions[i]));
+        stack.extend(
+            (0..sessions.len())
+                .rev()
+                .filter(|&j| parent(j) == Some(i))
+                .map(|j| (depth + 1, j)),
+        );
+    }
+    ordered
+}
+
+/// Creates a unique id for a new session
+///
+/// # Returns
+/// Id made of the creation time, process id and a counter
+pub fn new_session_id() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    format!(
+        "{}-{:x}-{}",
+        Utc::now().format("%Y%m%d%H%M%S%3f"),
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Reads and parses a session file
+///
+/// Sessions saved before the metadata existed get it filled in.
+fn read_session(path: &Path) -> Result<ApplicationState, Box<dyn std::error::Error>> {
+    let content = fs::read_to_string(path)?;
+    let mut app_state: ApplicationState = serde_yaml::from_str(&content)?;
+    if app_state.message_count == 0 && !app_state.messages.is_empty() {
+        app_state.refresh_metadata();
+    }
+    Ok(app_state)
+}
+
+/// Formats an optional timestamp in local time for display
+fn format_time(time: &Option<chrono::DateTime<chrono::Utc>>) -> String {
+    time.map(|t| {
+        t.with_timezone(&chrono::Local)
+            .format("%Y-%m-%d %H:%M")
+            .to_string()
+    })
+    .unwrap_or_else(|| "-".to_string())
+}
+
+/// Path of a session saved by older versions in the current directory
+fn legacy_path(name: &str) -> PathBuf {
+    PathBuf::from(format!(".chatto-{}.session.yaml", name))
+}
+
+/// Global session directory following the XDG base directory spec
+fn global_dir() -> Option<PathBuf> {
+    env::var_os("XDG_DATA_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| dir::home_dir().map(|h| h.join(".local/share")))
+        .map(|d| d.join("chatto/sessions"))
+}
+
+/// Rejects session names that can't be used as a file name
+fn validate_name(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        return Err(format!("Invalid session name '{}'", name).into());
+    }
+    Ok(())
+}
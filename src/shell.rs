@@ -0,0 +1,392 @@
+//! Environment shell commands run in.
+//!
+//! The `shell` config section controls where and how `execute_shell` runs
+//! commands:
+//!
+//! ```yaml
+//! shell:
+//!   timeout: 120        # seconds before a command is killed, 0 = no timeout
+//!   max_timeout: 600    # cap on the timeout a tool call may ask for, 0 = no cap
+//!   root: .             # directory commands and file tools work in, defaults to where chatto started
+//!   env:                # glob patterns matched against variable names
+//!     allow: []         # when not empty, only matching variables are passed on
+//!     deny: ['*API_KEY*', '*TOKEN*', '*SECRET*', '*PASSWORD*']
+//!   sandbox: none       # none | bwrap
+//!   network: true       # whether sandboxed commands may use the network
+//! ```
+//!
+//! A tool call may ask for a working directory, which must be inside the
+//! root. Without a sandbox a command can still `cd` out of the root, so for
+//! unattended use set `sandbox: bwrap` to run commands under
+//! [bubblewrap](https://github.com/containers/bubblewrap): the filesystem is
+//! mounted read-only, the home directory is replaced by an empty one, and only
+//! the root and a private `/tmp` are writable.
+
+use std::{
+    env,
+    error::Error,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+use strum::{Display, EnumString};
+
+/// Glob patterns selecting the environment variables passed to commands
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct EnvRules {
+    /// When not empty, only variables matching one of these are passed on
+    pub allow: Vec<String>,
+    /// Variables matching any of these are never passed on
+    pub deny: Vec<String>,
+}
+
+/// Default deny list, keeping credentials such as `OLLAMA_API_KEY` out of commands
+impl Default for EnvRules {
+    fn default() -> Self {
+        Self {
+            allow: Vec::new(),
+            deny: ["*API_KEY*", "*TOKEN*", "*SECRET*", "*PASSWORD*"]
+                .map(String::from)
+                .to_vec(),
+        }
+    }
+}
+
+/// How commands are isolated from the rest of the system
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Display, EnumString)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum SandboxMode {
+    /// Run commands directly
+    #[default]
+    None,
+    /// Run commands under bubblewrap in new namespaces
+    Bwrap,
+}
+
+/// Shell configuration loaded from the `shell` config section
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ShellConfig {
+    /// Seconds a command may run before it is killed, 0 for no timeout
+    pub timeout: u64,
+    /// Largest timeout a tool call may ask for, 0 for no cap
+    pub max_timeout: u64,
+    /// Directory commands and file tools work in, defaults to the current directory
+    pub root: Option<PathBuf>,
+    /// Environment variables passed to commands
+    pub env: EnvRules,
+    /// Sandbox commands run in
+    pub sandbox: SandboxMode,
+    /// Whether sandboxed commands may use the network
+    pub network: bool,
+}
+
+impl Default for ShellConfig {
+    fn default() -> Self {
+        Self {
+            timeout: 120,
+            max_timeout: 600,
+            root: None,
+            env: EnvRules::default(),
+            sandbox: SandboxMode::None,
+            network: true,
+        }
+    }
+}
+
+/// Compiled shell configuration
+///
+/// Built once from `ShellConfig` so that a bad root or invalid patterns are
+/// reported at startup rather than on the first command.
+pub struct ShellEnvironment {
+    timeout: u64,
+    max_timeout: u64,
+    root: PathBuf,
+    env_allow: Option<GlobSet>,
+    env_deny: GlobSet,
+    sandbox: SandboxMode,
+    network: bool,
+}
+
+impl ShellEnvironment {
+    /// Compiles the shell configuration
+    ///
+    /// # Arguments
+    /// * `config` - The shell section of the application configuration
+    ///
+    /// # Errors
+    /// Returns error if the root is not a directory, a pattern is invalid or
+    /// the sandbox helper is missing
+    pub fn new(config: &ShellConfig) -> Result<Self, Box<dyn Error>> {
+        let root = config.root.clone().unwrap_or_else(|| PathBuf::from("."));
+        //Expand a leading ~ since config files can't rely on the shell for it
+        let root = match (root.strip_prefix("~"), dir::home_dir()) {
+            (Ok(rest), Some(home)) => home.join(rest),
+            _ => root,
+        };
+        let root = root
+            .canonicalize()
+            .map_err(|e| format!("Invalid shell root '{}': {}", root.display(), e))?;
+        if !root.is_dir() {
+            return Err(format!("Shell root '{}' is not a directory", root.display()).into());
+        }
+        if config.sandbox == SandboxMode::Bwrap {
+            Command::new("bwrap")
+                .arg("--version")
+                .output()
+                .map_err(|e| format!("Sandbox helper bwrap can't be run: {}", e))?;
+        }
+        let globs = |patterns: &[String]| -> Result<GlobSet, Box<dyn Error>> {
+            let mut set = GlobSetBuilder::new();
+            for p in patterns {
+                set.add(Glob::new(p).map_err(|e| format!("Invalid env pattern '{}': {}", p, e))?);
+            }
+            Ok(set.build()?)
+        };
+        Ok(Self {
+            timeout: config.timeout,
+            max_timeout: config.max_timeout,
+            env_allow: match config.env.allow.is_empty() {
+                true => None,
+                false => Some(globs(&config.env.allow)?),
+            },
+            env_deny: globs(&config.env.deny)?,
+            root,
+            sandbox: config.sandbox,
+            network: config.network,
+        })
+    }
+
+    /// Works out how long a command may run
+    ///
+    /// # Arguments
+    /// * `requested` - Timeout in seconds asked for by the tool call, if any
+    ///
+    /// # Returns
+    /// The timeout capped at `max_timeout`, or None if commands may run forever
+    pub fn timeout(&self, requested: Option<u64>) -> Option<Duration> {
+        let secs = requested.unwrap_or(self.timeout);
+        let secs = match (secs, self.max_timeout) {
+            (secs, 0) => secs,
+            (0, max) => max,
+            (secs, max) => secs.min(max),
+        };
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+
+    /// Resolves the working directory asked for by a tool call
+    ///
+    /// # Arguments
+    /// * `cwd` - Directory relative to the root (or absolute), None for the root
+    ///
+    /// # Returns
+    /// The canonical directory
+    ///
+    /// # Errors
+    /// Returns an error message for the model if the directory doesn't exist
+    /// or is outside the root
+    pub fn working_dir(&self, cwd: Option<&str>) -> Result<PathBuf, String> {
+        let Some(cwd) = cwd else {
+            return Ok(self.root.clone());
+        };
+        let dir = self
+            .root
+            .join(cwd)
+            .canonicalize()
+            .map_err(|e| format!("Error: invalid working directory '{}': {}", cwd, e))?;
+        if !dir.starts_with(&self.root) {
+            return Err(format!(
+                "Error: working directory '{}' is outside the project root {}",
+                cwd,
+                self.root.display()
+            ));
+        }
+        Ok(dir)
+    }
+
+    /// The canonical project root commands run in
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Whether an environment variable may be passed to commands
+    fn allows_var(&self, name: &OsString) -> bool {
+        let name = Path::new(name);
+        !self.env_deny.is_match(name) && self.env_allow.as_ref().is_none_or(|a| a.is_match(name))
+    }
+
+    /// Builds the command running a shell command line
+    ///
+    /// The environment is filtered by the env rules and, when a sandbox is
+    /// configured, the shell is started through the sandbox helper.
+    ///
+    /// # Arguments
+    /// * `command` - Command line passed to `sh -c`
+    /// * `cwd` - Working directory, from [`ShellEnvironment::working_dir`]
+    ///
+    /// # Returns
+    /// The command, ready to have its stdio configured and be spawned
+    pub fn command(&self, command: &str, cwd: &Path) -> Command {
+        let mut cmd = match self.sandbox {
+            SandboxMode::None => Command::new("sh"),
+            SandboxMode::Bwrap => {
+                let mut cmd = Command::new("bwrap");
+                cmd.args(["--ro-bind", "/", "/"])
+                    .args(["--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp"]);
+                //Hide the home directory (ssh keys, tokens, chatto's own config) unless it's the project
+                if let Some(home) = dir::home_dir().filter(|h| !h.starts_with(&self.root)) {
+                    cmd.arg("--tmpfs").arg(home);
+                }
+                cmd.arg("--bind").arg(&self.root).arg(&self.root);
+                cmd.arg("--chdir").arg(cwd);
+                cmd.args(["--unshare-all", "--die-with-parent"]);
+                if self.network {
+                    cmd.arg("--share-net");
+                }
+                cmd.arg("sh");
+                cmd
+            }
+        };
+        cmd.arg("-c")
+            .arg(command)
+            .current_dir(cwd)
+            .env_clear()
+            .envs(self.env());
+        cmd
+    }
+
+    /// The environment variables the env rules pass on to commands
+    ///
+    /// # Returns
+    /// The variables of chatto's own environment that may be passed on
+    pub fn env(&self) -> impl Iterator<Item = (OsString, OsString)> + '_ {
+        env::vars_os().filter(|(name, _)| self.allows_var(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shell(config: ShellConfig) -> ShellEnvironment {
+        ShellEnvironment::new(&config).unwrap()
+    }
+
+    #[test]
+    fn caps_timeouts() {
+        let capped = shell(ShellConfig::default());
+        assert_eq!(capped.timeout(None), Some(Duration::from_secs(120)));
+        assert_eq!(capped.timeout(Some(5)), Some(Duration::from_secs(5)));
+        assert_eq!(capped.timeout(Some(3600)), Some(Duration::from_secs(600)));
+        //Asking for no timeout gets the cap
+        assert_eq!(capped.timeout(Some(0)), Some(Duration::from_secs(600)));
+
+        let uncapped = shell(ShellConfig {
+            timeout: 0,
+            max_timeout: 0,
+            ..ShellConfig::default()
+        });
+        assert_eq!(uncapped.timeout(None), None);
+        assert_eq!(
+            uncapped.timeout(Some(3600)),
+            Some(Duration::from_secs(3600))
+        );
+    }
+
+    #[test]
+    fn keeps_working_directories_inside_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::create_dir(dir.path().join("other")).unwrap();
+        let shell = shell(ShellConfig {
+            root: Some(root.clone()),
+            ..ShellConfig::default()
+        });
+        let root = root.canonicalize().unwrap();
+        assert_eq!(shell.root(), root);
+        assert_eq!(shell.working_dir(None).unwrap(), root);
+        assert_eq!(shell.working_dir(Some("src")).unwrap(), root.join("src"));
+        assert_eq!(shell.working_dir(Some("src/..")).unwrap(), root);
+        let absolute = root.join("src");
+        assert_eq!(
+            shell.working_dir(absolute.to_str()).unwrap(),
+            root.join("src")
+        );
+        //Directories outside the root or missing ones are refused
+        assert!(shell
+            .working_dir(Some("../other"))
+            .unwrap_err()
+            .contains("outside the project root"));
+        assert!(shell.working_dir(Some("/")).is_err());
+        assert!(shell
+            .working_dir(Some("missing"))
+            .unwrap_err()
+            .contains("invalid working directory"));
+    }
+
+    #[test]
+    fn refuses_bad_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        for root in [file, dir.path().join("missing")] {
+            let config = ShellConfig {
+                root: Some(root),
+                ..ShellConfig::default()
+            };
+            assert!(ShellEnvironment::new(&config).is_err());
+        }
+    }
+
+    #[test]
+    fn filters_environment_variables() {
+        let allowed = |shell: &ShellEnvironment, name: &str| shell.allows_var(&name.into());
+        let default = shell(ShellConfig::default());
+        assert!(allowed(&default, "PATH"));
+        assert!(allowed(&default, "HOME"));
+        assert!(!allowed(&default, "OLLAMA_API_KEY"));
+        assert!(!allowed(&default, "GITHUB_TOKEN"));
+        assert!(!allowed(&default, "AWS_SECRET_ACCESS_KEY"));
+        assert!(!allowed(&default, "PGPASSWORD"));
+
+        //An allow list passes only matching variables, and deny still wins
+        let strict = shell(ShellConfig {
+            env: EnvRules {
+                allow: vec!["PATH".into(), "CARGO_*".into()],
+                deny: vec!["CARGO_REGISTRY_TOKEN".into()],
+            },
+            ..ShellConfig::default()
+        });
+        assert!(allowed(&strict, "PATH"));
+        assert!(allowed(&strict, "CARGO_HOME"));
+        assert!(!allowed(&strict, "HOME"));
+        assert!(!allowed(&strict, "CARGO_REGISTRY_TOKEN"));
+
+        //Commands only see the variables that are passed on
+        let output = strict
+            .command("echo \"$PATH|$HOME\"", strict.root())
+            .output()
+            .unwrap();
+        let output = String::from_utf8_lossy(&output.stdout);
+        assert_eq!(output.trim(), format!("{}|", env::var("PATH").unwrap()));
+    }
+
+    #[test]
+    fn refuses_invalid_env_patterns() {
+        let config = ShellConfig {
+            env: EnvRules {
+                allow: vec![],
+                deny: vec!["[".into()],
+            },
+            ..ShellConfig::default()
+        };
+        assert!(ShellEnvironment::new(&config).is_err());
+    }
+}
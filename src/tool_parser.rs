@@ -0,0 +1,478 @@
+//! Tool calls written as plain text by models without tool support.
+//!
+//! Models without the `tools` capability can't use Ollama's function calling,
+//! so they are asked to write their tool calls into the response instead.
+//! After each of their responses the calls are extracted with the configured
+//! strategy and run like native tool calls:
+//!
+//! ```yaml
+//! tool_parsing:
+//!   strategy: parser          # parser (default), model or off
+//!   model: functiongemma      # helper model for the model strategy
+//! ```
+//!
+//! The built-in parser understands, in order of preference:
+//! - `<tool_call>{"name": ..., "arguments": {...}}</tool_call>` tags
+//! - `TOOL CALL` blocks as described in the system prompt
+//! - JSON objects with a `name` and `arguments` (or `parameters`), also when
+//!   wrapped in `{"function": ...}` or `{"tool_calls": [...]}`
+//!
+//! Only calls to known tools are extracted from tags and JSON, so unrelated
+//! JSON in a response isn't mistaken for a tool call. The model strategy sends
+//! the response to a tool capable helper model and uses the calls it makes.
+//! `/tools` in chat mode runs the extraction again by hand.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    LazyLock,
+};
+
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::ollama::{ToolCall, ToolCallFunction};
+
+/// Matches `<tool_call>...</tool_call>` tags
+static TOOL_CALL_TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<tool_call>(.*?)</tool_call>").unwrap());
+
+/// Matches the header line of a `TOOL CALL` block, capturing a tool name given on it
+static TOOL_CALL_HEADER: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^[\s#*>`-]*TOOL CALL[\s*`]*(?::\s*`?([A-Za-z0-9_.-]+)`?)?[\s*`]*$").unwrap()
+});
+
+/// Matches a `key: value` line inside a `TOOL CALL` block
+static BLOCK_FIELD: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[\s*-]*`?([A-Za-z0-9_]+)`?\**:\**\s*(.*)$").unwrap());
+
+/// How tool calls are extracted from responses of models without tool support
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolParsingStrategy {
+    /// Parse the calls out of the response text
+    #[default]
+    Parser,
+    /// Ask a tool capable helper model to make the calls
+    Model,
+    /// Don't extract tool calls
+    Off,
+}
+
+/// The `tool_parsing` config section
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ToolParsingConfig {
+    /// How tool calls are extracted
+    pub strategy: ToolParsingStrategy,
+    /// Helper model used by the model strategy
+    pub model: Option<String>,
+}
+
+/// Prompt telling models without tool support how to write tool calls
+static TEXT_TOOLS_PROMPT: &str = r#"
+**CALLING TOOLS:**
+You can't call tools directly. To call a tool, write a block starting with a `TOOL CALL` header line, followed by the tool name, the reason and one line per parameter. End the block with an empty line. For multi-line values leave the value empty and put the text in a fenced code block on the following lines. The tool results are sent back to you in the next message.
+
+TOOL CALL
+tool: read_file
+reason: Check how the config is loaded
+path: src/main.rs
+start_line: 10
+
+TOOL CALL
+tool: write_file
+reason: Add the notes file
+path: NOTES.md
+content:
+```
+# Notes
+Remember to update the changelog.
+```
+
+**AVAILABLE TOOLS:**
+"#;
+
+/// Builds the tool calling instructions for a model without tool support
+///
+/// # Arguments
+/// * `tools` - Tool definitions offered to the model
+///
+/// # Returns
+/// The calling convention followed by each tool's description and parameters
+pub fn text_tools_prompt(tools: &[Value]) -> String {
+    let mut prompt = TEXT_TOOLS_PROMPT.to_string();
+    for tool in tools {
+        let function = &tool["function"];
+        prompt.push_str(&format!(
+            "- `{}`: {}\n",
+            function["name"].as_str().unwrap_or_default(),
+            function["description"].as_str().unwrap_or_default()
+        ));
+        let required: Vec<&str> = function["parameters"]["required"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .collect();
+        let properties = function["parameters"]["properties"].as_object();
+        for (name, schema) in properties.into_iter().flatten() {
+            prompt.push_str(&format!(
+                "  - {} ({}{}): {}\n",
+                name,
+                schema["type"].as_str().unwrap_or("any"),
+                if required.contains(&name.as_str()) {
+                    ", required"
+                } else {
+                    ""
+                },
+                schema["description"].as_str().unwrap_or_default()
+            ));
+        }
+    }
+    prompt
+}
+
+/// Extracts the tool calls written into a response
+///
+/// # Arguments
+/// * `content` - Text of the response
+/// * `tools` - Tool definitions offered to the model
+///
+/// # Returns
+/// The tool calls found, using the first convention that yields any
+pub fn parse_tool_calls(content: &str, tools: &[Value]) -> Vec<ToolCall> {
+    let calls = parse_tags(content, tools);
+    if !calls.is_empty() {
+        return calls;
+    }
+    let calls = parse_blocks(content, tools);
+    if !calls.is_empty() {
+        return calls;
+    }
+    parse_json(content, tools)
+}
+
+/// Looks up the definition of a tool by name
+fn find_tool<'a>(tools: &'a [Value], name: &str) -> Option<&'a Value> {
+    tools.iter().find(|t| t["function"]["name"] == name)
+}
+
+/// Creates a tool call with a generated id
+fn new_call(name: &str, arguments: Value) -> ToolCall {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    ToolCall {
+        id: Some(format!("parsed_{}", id)),
+        function: ToolCallFunction {
+            name: name.to_string(),
+            description: None,
+            arguments,
+        },
+    }
+}
+
+/// Extracts the calls from `<tool_call>` tags
+fn parse_tags(content: &str, tools: &[Value]) -> Vec<ToolCall> {
+    TOOL_CALL_TAG
+        .captures_iter(content)
+        .filter_map(|caps| serde_json::from_str::<Value>(caps[1].trim()).ok())
+        .flat_map(|value| json_calls(&value, tools))
+        .collect()
+}
+
+/// Extracts the calls from JSON objects anywhere in the content
+fn parse_json(content: &str, tools: &[Value]) -> Vec<ToolCall> {
+    let mut calls = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = content[offset..].find(['{', '[']).map(|i| offset + i) {
+        let mut values = serde_json::Deserializer::from_str(&content[start..]).into_iter::<Value>();
+        match values.next() {
+            Some(Ok(value)) => {
+                let found = json_calls(&value, tools);
+                //Skip the whole value when it held calls, otherwise look inside it
+                offset = match found.is_empty() {
+                    true => start + 1,
+                    false => start + values.byte_offset(),
+                };
+                calls.extend(found);
+            }
+            _ => offset = start + 1,
+        }
+    }
+    calls
+}
+
+/// Reads the tool calls out of a JSON value
+///
+/// Accepts a call object (`name` with `arguments` or `parameters`), one
+/// wrapped in `function`, a `tool_calls` list or a list of any of these.
+/// Arguments given as a JSON string are decoded.
+fn json_calls(value: &Value, tools: &[Value]) -> Vec<ToolCall> {
+    match value {
+        Value::Array(items) => items.iter().flat_map(|v| json_calls(v, tools)).collect(),
+        Value::Object(object) => {
+            if let Some(list) = object.get("tool_calls") {
+                return json_calls(list, tools);
+            }
+            if let Some(function) = object.get("function").filter(|f| f.is_object()) {
+                return json_calls(function, tools);
+            }
+            let Some(name) = object.get("name").and_then(Value::as_str) else {
+                return Vec::new();
+            };
+            if find_tool(tools, name).is_none() {
+                return Vec::new();
+            }
+            let arguments = match object.get("arguments").or(object.get("parameters")) {
+                Some(Value::String(s)) => match serde_json::from_str(s) {
+                    Ok(arguments) => arguments,
+                    Err(_) => return Vec::new(),
+                },
+                Some(arguments) => arguments.clone(),
+                None => Value::Object(Map::new()),
+            };
+            vec![new_call(name, arguments)]
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Extracts the calls from `TOOL CALL` blocks
+///
+/// A block starts at a `TOOL CALL` header line, optionally naming the tool
+/// (`TOOL CALL: read_file`), and holds `key: value` lines up to the next empty
+/// line. A key with an empty value takes the fenced code block that follows.
+/// `tool` (or `name`) names the tool and `reason` is dropped unless the tool
+/// has a parameter of that name. Blocks naming unknown tools are kept so the
+/// model is told the tool doesn't exist.
+fn parse_blocks(content: &str, tools: &[Value]) -> Vec<ToolCall> {
+    let mut calls = Vec::new();
+    let mut lines = content.lines().peekable();
+    while let Some(line) = lines.next() {
+        let Some(header) = TOOL_CALL_HEADER.captures(line) else {
+            continue;
+        };
+        let mut name = header.get(1).map(|m| m.as_str().to_string());
+        let mut fields: Vec<(String, String)> = Vec::new();
+        while let Some(line) = lines.peek() {
+            if line.trim().is_empty() && !fields.is_empty() {
+                break;
+            }
+            if TOOL_CALL_HEADER.is_match(line) {
+                break;
+            }
+            let line = lines.next().unwrap_or_default();
+            //Fences around the whole block are ignored
+            if line.trim().is_empty() || line.trim_start().starts_with("```") {
+                continue;
+            }
+            let Some(field) = BLOCK_FIELD.captures(line) else {
+                //A line without a key continues the previous value
+                if let Some((_, value)) = fields.last_mut() {
+                    value.push('\n');
+                    value.push_str(line);
+                }
+                continue;
+            };
+            let key = field[1].to_string();
+            let mut value = field[2].trim().to_string();
+            if value.is_empty()
+                && lines
+                    .peek()
+                    .is_some_and(|l| l.trim_start().starts_with("```"))
+            {
+                value = read_fence(&mut lines);
+            }
+            fields.push((key, value));
+        }
+
+        if let Some(i) = fields
+            .iter()
+            .position(|(k, _)| name.is_none() && (k == "tool" || k == "name"))
+        {
+            name = Some(fields.remove(i).1.trim_matches('`').to_string());
+        }
+        let Some(name) = name else {
+            continue;
+        };
+        let schema = find_tool(tools, &name).map(|t| &t["function"]["parameters"]["properties"]);
+        let mut arguments = Map::new();
+        for (key, value) in fields {
+            let property = schema.map(|s| &s[&key]);
+            if key == "reason" && property.is_none_or(Value::is_null) {
+                continue;
+            }
+            arguments.insert(key, block_value(&value, property));
+        }
+        calls.push(new_call(&name, Value::Object(arguments)));
+    }
+    calls
+}
+
+/// Reads a fenced code block, the next line being its opening fence
+///
+/// # Returns
+/// The text between the fences
+fn read_fence<'a>(lines: &mut impl Iterator<Item = &'a str>) -> String {
+    let fence = lines.next().unwrap_or_default().trim_start();
+    let marker = &fence[..fence.len() - fence.trim_start_matches('`').len()];
+    let mut body: Vec<&str> = Vec::new();
+    for line in lines.by_ref() {
+        if line.trim() == marker {
+            break;
+        }
+        body.push(line);
+    }
+    let mut text = body.join("\n");
+    text.push('\n');
+    text
+}
+
+/// Converts a value from a `TOOL CALL` block to JSON
+///
+/// String parameters keep the text as written (without surrounding quotes),
+/// anything else is read as JSON when possible.
+///
+/// # Arguments
+/// * `value` - Text of the value
+/// * `property` - Schema of the parameter, if the tool is known
+fn block_value(value: &str, property: Option<&Value>) -> Value {
+    let is_string = property.is_some_and(|p| p["type"] == "string");
+    if is_string {
+        if let Ok(Value::String(s)) = serde_json::from_str(value) {
+            return Value::String(s);
+        }
+        return Value::String(value.to_string());
+    }
+    serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn tools() -> Vec<Value> {
+        let tool = |name: &str, properties: Value| json!({"type": "function", "function": {"name": name, "description": "", "parameters": {"type": "object", "properties": properties}}});
+        vec![
+            tool(
+                "read_file",
+                json!({"path": {"type": "string"}, "start_line": {"type": "integer"}}),
+            ),
+            tool(
+                "write_file",
+                json!({"path": {"type": "string"}, "content": {"type": "string"}}),
+            ),
+        ]
+    }
+
+    /// Names and arguments of the calls parsed from `content`
+    fn parse(content: &str) -> Vec<(String, Value)> {
+        parse_tool_calls(content, &tools())
+            .into_iter()
+            .map(|c| (c.function.name, c.function.arguments))
+            .collect()
+    }
+
+    fn read(path: &str) -> (String, Value) {
+        ("read_file".to_string(), json!({"path": path}))
+    }
+
+    #[test]
+    fn parses_tags() {
+        let content = r#"Let me look.
+<tool_call>{"name": "read_file", "arguments": {"path": "a.rs"}}</tool_call>
+<tool_call>
+{"name": "read_file", "arguments": {"path": "b.rs"}}
+</tool_call>"#;
+        assert_eq!(parse(content), vec![read("a.rs"), read("b.rs")]);
+    }
+
+    #[test]
+    fn parses_blocks() {
+        let content = "I'll read the file.\n\nTOOL CALL\ntool: read_file\nreason: Find the config\npath: src/main.rs\nstart_line: 10\n\n**TOOL CALL: read_file**\npath: b.rs\n";
+        assert_eq!(
+            parse(content),
+            vec![
+                (
+                    "read_file".to_string(),
+                    json!({"path": "src/main.rs", "start_line": 10})
+                ),
+                read("b.rs"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_blocks_with_fenced_values() {
+        let content = "TOOL CALL\ntool: write_file\npath: NOTES.md\ncontent:\n```\n# Notes\n\nkey: value\n```\n";
+        assert_eq!(
+            parse(content),
+            vec![(
+                "write_file".to_string(),
+                json!({"path": "NOTES.md", "content": "# Notes\n\nkey: value\n"})
+            )]
+        );
+    }
+
+    #[test]
+    fn keeps_blocks_naming_unknown_tools() {
+        let calls = parse("TOOL CALL\ntool: delete_everything\npath: /\n");
+        assert_eq!(
+            calls,
+            vec![("delete_everything".to_string(), json!({"path": "/"}))]
+        );
+    }
+
+    #[test]
+    fn parses_json() {
+        let content = r#"{"name": "read_file", "parameters": {"path": "a.rs"}}
+{"function": {"name": "read_file", "arguments": "{\"path\": \"b.rs\"}"}}
+{"tool_calls": [{"function": {"name": "read_file", "arguments": {"path": "c.rs"}}}]}
+[{"name": "read_file", "arguments": {"path": "d.rs"}}, {"name": "read_file", "arguments": {"path": "e.rs"}}]"#;
+        assert_eq!(
+            parse(content),
+            ["a.rs", "b.rs", "c.rs", "d.rs", "e.rs"].map(read).to_vec()
+        );
+    }
+
+    #[test]
+    fn parses_calls_in_code_fences() {
+        let json = "Reading it:\n```json\n{\"name\": \"read_file\", \"arguments\": {\"path\": \"a.rs\"}}\n```\n";
+        assert_eq!(parse(json), vec![read("a.rs")]);
+        let block = "```\nTOOL CALL\ntool: read_file\npath: a.rs\n```\n";
+        assert_eq!(parse(block), vec![read("a.rs")]);
+    }
+
+    #[test]
+    fn prefers_tags_over_json() {
+        let content = r#"<tool_call>{"name": "read_file", "arguments": {"path": "a.rs"}}</tool_call>
+{"name": "read_file", "arguments": {"path": "b.rs"}}"#;
+        assert_eq!(parse(content), vec![read("a.rs")]);
+    }
+
+    #[test]
+    fn ignores_malformed_and_unrelated_json() {
+        assert!(parse(r#"{"name": "read_file", "arguments": {"path": "a.rs""#).is_empty());
+        assert!(parse(r#"<tool_call>{"name": "read_file", "arguments": }</tool_call>"#).is_empty());
+        assert!(parse(r#"{"name": "read_file", "arguments": "{not json"}"#).is_empty());
+        assert!(parse(r#"{"name": "bob", "arguments": {"age": 3}}"#).is_empty());
+        assert!(parse("The config is {\"port\": 8080}.").is_empty());
+        assert!(parse("No tools needed here.").is_empty());
+    }
+
+    #[test]
+    fn parsed_calls_get_unique_ids() {
+        //Tool results are matched to calls, and the chat waits for the user, by these ids
+        let content = r#"[{"name": "read_file", "arguments": {"path": "a.rs"}}, {"name": "read_file", "arguments": {"path": "b.rs"}}]"#;
+        let calls = parse_tool_calls(content, &tools());
+        let ids: Vec<_> = calls.iter().map(|c| c.id.clone()).collect();
+        assert_eq!(ids.len(), 2);
+        assert!(ids
+            .iter()
+            .all(|id| id.as_ref().is_some_and(|id| id.starts_with("parsed_"))));
+        assert_ne!(ids[0], ids[1]);
+    }
+}
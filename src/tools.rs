@@ -8,137 +8,2021 @@
 //!
 //! - **execute_shell**: Execute shell commands with output limiting
 //! - **read_file**: Read file contents with optional line range
+//! - **search_files**: Regex search over file contents, respecting .gitignore
+//! - **find_files**: Find files by glob, newest first, respecting .gitignore
+//! - **list_directory**: Bounded tree view of a directory with file sizes
+//! - **start_process**, **read_process_output**, **send_process_input**,
+//!   **stop_process**: Run and manage long-running commands in the background
 //! - **write_file**: Write content with multiple modes (overwrite, append, insert, replace)
+//! - **edit_file**: Replace an exact, unique string in a file
+//! - **apply_patch**: Apply a unified diff across several files atomically
+//!
+//! ## Adding Tools
+//!
+//! Each tool is a type implementing the [`Tool`] trait. Registering it in a
+//! [`ToolRegistry`] exposes it to the model and routes matching tool calls
+//! to its preview and execute steps.
 //!
 //! ## Output Limiting
 //!
 //! Tool output can be limited using the `OutputLimit` configuration to prevent
 //! overwhelming the context window with large outputs.
 
-use serde::Deserialize;
-use serde_json::Value;
-use std::{collections::HashMap, process::Command};
-use strum::{Display, EnumString};
+use globset::GlobBuilder;
+use ignore::{
+    overrides::{Override, OverrideBuilder},
+    types::TypesBuilder,
+    WalkBuilder,
+};
+use regex::{Regex, RegexBuilder};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+use similar::{ChangeTag, TextDiff};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs,
+    io::{Read, Write},
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+use strum::{Display, EnumString};
+
+use crate::approval::ApprovalSubject;
+use crate::attach::{image_mime, load_image};
+use crate::interrupt;
+use crate::patch::{apply_changes, plan_patch};
+use crate::process::{peek_exit, ProcessManager, ProcessStatus};
+use crate::shell::ShellEnvironment;
+
+/// Configuration for limiting tool output size
+///
+/// Prevents overwhelming the context window with large command outputs
+/// by trimming based on size threshold and method.
+#[derive(Debug, Deserialize, Clone)]
+pub struct OutputLimit {
+    /// Maximum output size (in bytes or approximate lines). 0 means no limit.
+    pub max_size: usize,
+    /// Method to use for trimming (head, tail, or bytes)
+    pub method: TrimMethod,
+}
+
+/// Method for trimming oversized output
+///
+/// - **Head**: Keep the beginning of the output
+/// - **Tail**: Keep the end of the output
+/// - **Bytes**: Truncate at byte limit
+#[derive(Debug, Deserialize, Clone, Display, EnumString)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum TrimMethod {
+    /// Keep the first N lines/bytes
+    Head,
+    /// Keep the last N lines/bytes
+    Tail,
+    /// Truncate at byte limit
+    Bytes,
+}
+
+/// Default impl for Output Limit, 0 means no limit
+impl Default for OutputLimit {
+    fn default() -> Self {
+        Self {
+            max_size: 0,
+            method: TrimMethod::Head,
+        }
+    }
+}
+
+impl From<OutputLimit> for config::Value {
+    fn from(value: OutputLimit) -> Self {
+        let mut ret: HashMap<String, String> = HashMap::new();
+        ret.insert("max_size".to_string(), value.max_size.to_string());
+        ret.insert("method".to_string(), value.method.to_string());
+        ret.into()
+    }
+}
+
+/// Context handed to a tool when it executes
+///
+/// Carries the pieces of application configuration and state a tool may
+/// need, so that tools don't have to reach into `ApplicationConfig` directly.
+pub struct ToolContext<'a> {
+    /// Output size limits for tool execution
+    pub output_limit: &'a OutputLimit,
+    /// Where and how shell commands run
+    pub shell: &'a ShellEnvironment,
+    /// Background processes of the session
+    pub processes: &'a ProcessManager,
+    /// Whether the model can view images
+    pub vision: bool,
+    /// Base64 encoded images returned by the current tool call
+    pub images: &'a RefCell<Vec<String>>,
+}
+
+impl ToolContext<'_> {
+    /// Takes the images returned by the last tool call
+    ///
+    /// # Returns
+    /// The images for the tool result message, or None if there are none
+    pub fn take_images(&self) -> Option<Vec<String>> {
+        let images = self.images.take();
+        (!images.is_empty()).then_some(images)
+    }
+}
+
+/// A tool the model can call
+///
+/// Implementors describe their interface with a JSON schema, declare a typed
+/// argument struct that the raw tool call arguments are deserialized into,
+/// and provide a preview (shown before approval) and an execution step.
+///
+/// Any type implementing `Tool` can be registered in a [`ToolRegistry`].
+pub trait Tool {
+    /// Typed arguments deserialized from the tool call
+    type Args: DeserializeOwned;
+
+    /// Name of the tool as exposed to the model
+    fn name(&self) -> &str;
+
+    /// Human and model readable description of what the tool does
+    fn description(&self) -> &str;
+
+    /// JSON schema describing the tool's parameters
+    fn parameters(&self) -> Value;
+
+    /// Displays the requested call to the user before approval
+    ///
+    /// # Arguments
+    /// * `args` - The parsed tool call arguments
+    fn preview(&self, args: &Self::Args);
+
+    /// Checks that the call can succeed before it is previewed and approved
+    ///
+    /// # Arguments
+    /// * `args` - The parsed tool call arguments
+    ///
+    /// # Returns
+    /// An error message for the model if the call would fail, `Ok` by default
+    fn validate(&self, _args: &Self::Args) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Describes what the call acts on so approval rules can match it
+    ///
+    /// # Arguments
+    /// * `args` - The parsed tool call arguments
+    ///
+    /// # Returns
+    /// The approval subject, `ApprovalSubject::None` by default
+    fn approval_subject(&self, _args: &Self::Args) -> ApprovalSubject {
+        ApprovalSubject::None
+    }
+
+    /// Executes the tool call
+    ///
+    /// # Arguments
+    /// * `args` - The parsed tool call arguments
+    /// * `context` - Configuration and state available to the tool
+    ///
+    /// # Returns
+    /// The tool result to send back to the model
+    fn execute(&self, args: Self::Args, context: &ToolContext) -> String;
+}
+
+/// Object-safe form of [`Tool`] that works on raw JSON arguments
+///
+/// Implemented for every `Tool`, this is what the [`ToolRegistry`] stores.
+/// Malformed arguments and calls that fail validation are reported as an
+/// `Err` holding a message suitable for returning to the model as the tool
+/// result.
+pub trait DynTool {
+    /// Name of the tool as exposed to the model
+    fn name(&self) -> &str;
+
+    /// Full tool definition for Ollama's function calling API
+    fn definition(&self) -> Value;
+
+    /// Parses and validates the arguments and displays the requested call
+    fn preview(&self, arguments: &Value) -> Result<(), String>;
+
+    /// Parses the arguments and describes what the call acts on
+    fn approval_subject(&self, arguments: &Value) -> Result<ApprovalSubject, String>;
+
+    /// Parses the arguments and executes the tool call
+    fn execute(&self, arguments: &Value, context: &ToolContext) -> Result<String, String>;
+}
+
+impl<T: Tool> DynTool for T {
+    fn name(&self) -> &str {
+        Tool::name(self)
+    }
+
+    fn definition(&self) -> Value {
+        serde_json::json!({
+            "type": "function",
+            "function": {
+                "name": Tool::name(self),
+                "description": self.description(),
+                "parameters": self.parameters()
+            }
+        })
+    }
+
+    fn preview(&self, arguments: &Value) -> Result<(), String> {
+        let args = parse_arguments::<T::Args>(Tool::name(self), arguments)?;
+        self.validate(&args)?;
+        Tool::preview(self, &args);
+        Ok(())
+    }
+
+    fn approval_subject(&self, arguments: &Value) -> Result<ApprovalSubject, String> {
+        let args = parse_arguments::<T::Args>(Tool::name(self), arguments)?;
+        Ok(Tool::approval_subject(self, &args))
+    }
+
+    fn execute(&self, arguments: &Value, context: &ToolContext) -> Result<String, String> {
+        let args = parse_arguments::<T::Args>(Tool::name(self), arguments)?;
+        Ok(Tool::execute(self, args, context))
+    }
+}
+
+/// Deserializes raw tool call arguments into a typed argument struct
+///
+/// Some models send the arguments as a JSON encoded string rather than an
+/// object, so string values are decoded before deserializing.
+///
+/// # Arguments
+/// * `tool_name` - Name of the tool, used in the error message
+/// * `arguments` - Raw arguments from the tool call
+///
+/// # Returns
+/// The typed arguments, or an error message for the model
+fn parse_arguments<A: DeserializeOwned>(tool_name: &str, arguments: &Value) -> Result<A, String> {
+    let result = match arguments {
+        Value::String(s) => serde_json::from_str::<A>(s),
+        Value::Null => serde_json::from_value::<A>(Value::Object(Default::default())),
+        v => serde_json::from_value::<A>(v.clone()),
+    };
+    result.map_err(|e| format!("Error: invalid arguments for tool '{}': {}", tool_name, e))
+}
+
+/// Collection of tools available to the model
+///
+/// Supplies the tool definitions sent with each chat request and looks up
+/// the implementation for each tool call the model makes.
+#[derive(Default)]
+pub struct ToolRegistry {
+    tools: Vec<Box<dyn DynTool>>,
+}
+
+impl ToolRegistry {
+    /// Creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a registry containing the built-in tools
+    ///
+    /// # Returns
+    /// Registry with execute_shell, read_file, search_files, find_files,
+    /// list_directory, the background process tools, write_file, edit_file
+    /// and apply_patch registered
+    pub fn with_builtin_tools() -> Self {
+        let mut registry = Self::new();
+        registry.register(ShellTool);
+        registry.register(ReadFileTool);
+        registry.register(SearchFilesTool);
+        registry.register(FindFilesTool);
+        registry.register(ListDirectoryTool);
+        registry.register(StartProcessTool);
+        registry.register(ReadProcessOutputTool);
+        registry.register(SendProcessInputTool);
+        registry.register(StopProcessTool);
+        registry.register(WriteFileTool);
+        registry.register(EditFileTool);
+        registry.register(ApplyPatchTool);
+        registry
+    }
+
+    /// Registers a tool, replacing any existing tool with the same name
+    ///
+    /// # Arguments
+    /// * `tool` - The tool to register
+    pub fn register(&mut self, tool: impl DynTool + 'static) {
+        self.tools.retain(|t| t.name() != tool.name());
+        self.tools.push(Box::new(tool));
+    }
+
+    /// Looks up a tool by name
+    ///
+    /// # Arguments
+    /// * `name` - Name of the tool requested by the model
+    ///
+    /// # Returns
+    /// The tool if it is registered
+    pub fn get(&self, name: &str) -> Option<&dyn DynTool> {
+        self.tools
+            .iter()
+            .find(|t| t.name() == name)
+            .map(|t| t.as_ref())
+    }
+
+    /// Returns the tool definitions for all registered tools
+    ///
+    /// # Returns
+    /// JSON Values describing the tools for Ollama's function calling API
+    pub fn definitions(&self) -> Vec<Value> {
+        self.tools.iter().map(|t| t.definition()).collect()
+    }
+}
+
+/// Arguments for the execute_shell tool
+#[derive(Debug, Deserialize)]
+pub struct ShellArgs {
+    /// The shell command to execute
+    pub command: String,
+    /// The reason the agent needs to use this command
+    #[serde(default)]
+    pub reason: String,
+    /// Working directory relative to the project root
+    #[serde(default)]
+    pub cwd: Option<String>,
+    /// Seconds before the command is killed, overriding the configured default
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+/// Tool for executing shell commands
+pub struct ShellTool;
+
+impl Tool for ShellTool {
+    type Args = ShellArgs;
+
+    fn name(&self) -> &str {
+        "execute_shell"
+    }
+
+    fn description(&self) -> &str {
+        "Execute a shell command on the system. Returns the exit code, duration, stdout and stderr"
+    }
+
+    fn parameters(&self) -> Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "command": {
+                    "type": "string",
+                    "description": "The shell command to execute"
+                },
+                "reason": {
+                    "type": "string",
+                    "description": "The reason the agent needs to use this command"
+                },
+                "cwd": {
+                    "type": "string",
+                    "description": "Working directory relative to the project root (default: the project root)"
+                },
+                "timeout": {
+                    "type": "integer",
+                    "description": "Seconds before the command is killed (default from configuration)"
+                }
+            },
+            "required": ["command", "reason"]
+        })
+    }
+
+    fn preview(&self, args: &Self::Args) {
+        eprintln!(
+            "🛠️  Shell Command Requested!\n ● Command: {}\n ● Reason: {}",
+            args.command, args.reason
+        );
+        if let Some(cwd) = &args.cwd {
+            eprintln!(" ● Directory: {}", cwd);
+        }
+        if let Some(timeout) = args.timeout {
+            eprintln!(" ● Timeout: {}s", timeout);
+        }
+    }
+
+    fn approval_subject(&self, args: &Self::Args) -> ApprovalSubject {
+        ApprovalSubject::Command(args.command.clone())
+    }
+
+    fn execute(&self, args: Self::Args, context: &ToolContext) -> String {
+        execute_command(&args, context)
+    }
+}
+
+/// Arguments for the read_file tool
+#[derive(Debug, Deserialize)]
+pub struct ReadFileArgs {
+    /// Path to the file to read
+    pub path: String,
+    /// Starting line number (1-based)
+    pub start_line: Option<usize>,
+    /// Ending line number (1-based, inclusive)
+    pub end_line: Option<usize>,
+}
+
+/// Tool for reading file contents with optional line ranges
+pub struct ReadFileTool;
+
+impl Tool for ReadFileTool {
+    type Args = ReadFileArgs;
+
+    fn name(&self) -> &str {
+        "read_file"
+    }
+
+    fn description(&self) -> &str {
+        "Read lines from a file, or view an image if the model supports images"
+    }
+
+    fn parameters(&self) -> Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "path": {
+                    "type": "string",
+                    "description": "Path to the file to read"
+                },
+                "start_line": {
+                    "type": "integer",
+                    "description": "Starting line number (1-based, optional)"
+                },
+                "end_line": {
+                    "type": "integer",
+                    "description": "Ending line number (1-based, inclusive, optional)"
+                }
+            },
+            "required": ["path"]
+        })
+    }
+
+    fn preview(&self, args: &Self::Args) {
+        let path = &args.path;
+        eprintln!("🛠️  Read File Requested!");
+        match (args.start_line, args.end_line) {
+            (Some(start), Some(end)) => {
+                eprintln!(" ● Reading file: {}, from line {} to {}", path, start, end)
+            }
+            (Some(start), None) => {
+                eprintln!(" ● Reading file: {}, from line {} to end", path, start)
+            }
+            (None, Some(end)) => {
+                eprintln!(" ● Reading file: {}, from start to line {}", path, end)
+            }
+            (None, None) => eprintln!(" ● Reading file: {}", path),
+        }
+    }
+
+    fn approval_subject(&self, args: &Self::Args) -> ApprovalSubject {
+        ApprovalSubject::read(Some(&args.path))
+    }
+
+    fn execute(&self, args: Self::Args, context: &ToolContext) -> String {
+        let path = &args.path;
+        if image_mime(Path::new(path)).is_some() {
+            return read_image(path, context);
+        }
+        let result = read_file_lines(path, args.start_line, args.end_line);
+        let result = trim_output(&result, context.output_limit);
+        let byte_count = result.len();
+
+        // Count actual lines read
+        let line_count = result.lines().count();
+        let first_line = result
+            .lines()
+            .next()
+            .and_then(|line| line.split(':').next())
+            .and_then(|num| num.trim().parse::<usize>().ok())
+            .unwrap_or(1);
+        let last_line = first_line + line_count.saturating_sub(1);
+
+        if line_count > 0 {
+            eprintln!(
+                " ✓ Successfully read {} bytes, {} lines ({}-{}) from {}",
+                byte_count, line_count, first_line, last_line, path
+            );
+        } else {
+            eprintln!(" ✓ Successfully read {} bytes from {}", byte_count, path);
+        }
+
+        // Print what we're sending to the LLM
+        eprintln!("OUTPUT:");
+        eprintln!("{}", result);
+
+        result
+    }
+}
+
+/// Hands an image to the model for the read_file tool
+///
+/// # Arguments
+/// * `path` - Path of the image
+/// * `context` - Context receiving the image when the model can view it
+///
+/// # Returns
+/// Note that the image is attached, or an error message
+fn read_image(path: &str, context: &ToolContext) -> String {
+    if !context.vision {
+        let message = format!(
+            "Error: {} is an image and the current model can't view images",
+            path
+        );
+        eprintln!(" ❌ {}", message);
+        return message;
+    }
+    match load_image(path) {
+        Ok(image) => {
+            eprintln!(" ✓ Attached image {} for the model", path);
+            context.images.borrow_mut().push(image);
+            format!("Image {} is attached.", path)
+        }
+        Err(e) => {
+            eprintln!(" ❌ {}", e);
+            e
+        }
+    }
+}
+
+/// Default cap on the results returned by the search tools
+const DEFAULT_MAX_RESULTS: usize = 100;
+
+/// Arguments for the search_files tool
+#[derive(Debug, Deserialize)]
+pub struct SearchFilesArgs {
+    /// Regular expression to search for
+    pub pattern: String,
+    /// Directory (or file) to search, defaults to the current directory
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Glob the file names must match, eg. `*.rs`
+    #[serde(default)]
+    pub glob: Option<String>,
+    /// File type to search, eg. `rust` or `py`
+    #[serde(default)]
+    pub file_type: Option<String>,
+    /// Lines of context to show around each match
+    #[serde(default)]
+    pub context: usize,
+    /// Whether the search ignores case
+    #[serde(default)]
+    pub case_insensitive: bool,
+    /// Maximum number of matching lines to return
+    #[serde(default)]
+    pub max_results: Option<usize>,
+}
+
+/// Tool searching file contents with a regular expression
+///
+/// Walks the directory respecting `.gitignore` and skips binary files, so it
+/// replaces `grep -r` without needing shell approval.
+pub struct SearchFilesTool;
+
+impl Tool for SearchFilesTool {
+    type Args = SearchFilesArgs;
+
+    fn name(&self) -> &str {
+        "search_files"
+    }
+
+    fn description(&self) -> &str {
+        "Search file contents with a regular expression, respecting .gitignore. Returns matching lines as path:line: text"
+    }
+
+    fn parameters(&self) -> Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "pattern": {
+                    "type": "string",
+                    "description": "Regular expression to search for"
+                },
+                "path": {
+                    "type": "string",
+                    "description": "Directory or file to search (default: current directory)"
+                },
+                "glob": {
+                    "type": "string",
+                    "description": "Only search files matching this glob, eg. *.rs or src/**/*.ts"
+                },
+                "file_type": {
+                    "type": "string",
+                    "description": "Only search files of this type, eg. rust, py, js, md"
+                },
+                "context": {
+                    "type": "integer",
+                    "description": "Lines of context to show before and after each match (default 0)"
+                },
+                "case_insensitive": {
+                    "type": "boolean",
+                    "description": "Ignore case when matching (default false)"
+                },
+                "max_results": {
+                    "type": "integer",
+                    "description": "Maximum number of matching lines to return (default 100)"
+                }
+            },
+            "required": ["pattern"]
+        })
+    }
+
+    fn validate(&self, args: &Self::Args) -> Result<(), String> {
+        build_search_regex(args).map(|_| ())?;
+        walk_files(
+            args.path.as_deref(),
+            args.glob.as_deref(),
+            args.file_type.as_deref(),
+        )
+        .map(|_| ())
+    }
+
+    fn preview(&self, args: &Self::Args) {
+        eprintln!("🛠️  Search Files Requested!");
+        eprintln!(
+            " ● Searching for /{}/ in: {}{}{}",
+            args.pattern,
+            args.path.as_deref().unwrap_or("."),
+            args.glob
+                .as_ref()
+                .map(|g| format!(", files matching {}", g))
+                .unwrap_or_default(),
+            args.file_type
+                .as_ref()
+                .map(|t| format!(", {} files", t))
+                .unwrap_or_default()
+        );
+    }
+
+    fn approval_subject(&self, args: &Self::Args) -> ApprovalSubject {
+        ApprovalSubject::read(args.path.as_deref())
+    }
+
+    fn execute(&self, args: Self::Args, context: &ToolContext) -> String {
+        let regex = match build_search_regex(&args) {
+            Ok(regex) => regex,
+            Err(e) => return e,
+        };
+        let files = match walk_files(
+            args.path.as_deref(),
+            args.glob.as_deref(),
+            args.file_type.as_deref(),
+        ) {
+            Ok(files) => files,
+            Err(e) => return e,
+        };
+        let max_results = args.max_results.unwrap_or(DEFAULT_MAX_RESULTS).max(1);
+        let mut matches = 0;
+        let mut matched_files = 0;
+        let mut output: Vec<String> = Vec::new();
+
+        'files: for file in files {
+            let Some(content) = read_text_file(&file) else {
+                continue;
+            };
+            let lines: Vec<&str> = content.lines().collect();
+            //Last line printed for this file, to merge overlapping context
+            let mut printed: Option<usize> = None;
+            for (i, line) in lines.iter().enumerate() {
+                if !regex.is_match(line) {
+                    continue;
+                }
+                if matches == max_results {
+                    output.push(format!(
+                        "... [Results truncated at {} matches, narrow the search or raise max_results]",
+                        max_results
+                    ));
+                    break 'files;
+                }
+                if printed.is_none() {
+                    matched_files += 1;
+                }
+                let start = i.saturating_sub(args.context);
+                let start = printed.map_or(start, |p| start.max(p + 1));
+                if args.context > 0 && printed.is_some_and(|p| start > p + 1) {
+                    output.push("--".to_string());
+                }
+                let end = (i + args.context).min(lines.len() - 1);
+                for (j, text) in lines.iter().enumerate().take(end + 1).skip(start) {
+                    let separator = if j == i || regex.is_match(text) {
+                        ':'
+                    } else {
+                        '-'
+                    };
+                    output.push(format!(
+                        "{}{}{}{} {}",
+                        file.display(),
+                        separator,
+                        j + 1,
+                        separator,
+                        text
+                    ));
+                }
+                printed = Some(end.max(printed.unwrap_or_default()));
+                matches += 1;
+            }
+            if args.context > 0 && printed.is_some() {
+                output.push("--".to_string());
+            }
+        }
+
+        if matches == 0 {
+            eprintln!(" ✓ No matches");
+            return format!("No matches found for /{}/", args.pattern);
+        }
+        eprintln!(" ✓ Found {} matches in {} files", matches, matched_files);
+        trim_output(&output.join("\n"), context.output_limit)
+    }
+}
+
+/// Arguments for the find_files tool
+#[derive(Debug, Deserialize)]
+pub struct FindFilesArgs {
+    /// Glob pattern to match, eg. `*.rs` or `src/**/mod.rs`
+    pub pattern: String,
+    /// Directory to search, defaults to the current directory
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Maximum number of paths to return
+    #[serde(default)]
+    pub max_results: Option<usize>,
+}
+
+/// Tool finding files by glob pattern, most recently modified first
+///
+/// Respects `.gitignore`, so it replaces `find` without needing shell approval.
+pub struct FindFilesTool;
+
+impl Tool for FindFilesTool {
+    type Args = FindFilesArgs;
+
+    fn name(&self) -> &str {
+        "find_files"
+    }
+
+    fn description(&self) -> &str {
+        "Find files by glob pattern, respecting .gitignore. Returns paths sorted by modification time, newest first"
+    }
+
+    fn parameters(&self) -> Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "pattern": {
+                    "type": "string",
+                    "description": "Glob pattern. Without a / it matches file names anywhere (eg. *.rs), with a / it matches paths relative to the search directory (eg. src/**/*.rs)"
+                },
+                "path": {
+                    "type": "string",
+                    "description": "Directory to search (default: current directory)"
+                },
+                "max_results": {
+                    "type": "integer",
+                    "description": "Maximum number of paths to return (default 100)"
+                }
+            },
+            "required": ["pattern"]
+        })
+    }
+
+    fn validate(&self, args: &Self::Args) -> Result<(), String> {
+        walk_files(args.path.as_deref(), Some(&args.pattern), None).map(|_| ())
+    }
+
+    fn preview(&self, args: &Self::Args) {
+        eprintln!("🛠️  Find Files Requested!");
+        eprintln!(
+            " ● Finding {} in: {}",
+            args.pattern,
+            args.path.as_deref().unwrap_or(".")
+        );
+    }
+
+    fn approval_subject(&self, args: &Self::Args) -> ApprovalSubject {
+        ApprovalSubject::read(args.path.as_deref())
+    }
+
+    fn execute(&self, args: Self::Args, context: &ToolContext) -> String {
+        let files = match walk_files(args.path.as_deref(), Some(&args.pattern), None) {
+            Ok(files) => files,
+            Err(e) => return e,
+        };
+        if files.is_empty() {
+            eprintln!(" ✓ No files found");
+            return format!("No files found matching {}", args.pattern);
+        }
+        let mut files: Vec<(PathBuf, SystemTime)> = files
+            .into_iter()
+            .map(|f| {
+                let modified = fs::metadata(&f)
+                    .and_then(|m| m.modified())
+                    .unwrap_or(SystemTime::UNIX_EPOCH);
+                (f, modified)
+            })
+            .collect();
+        files.sort_by_key(|f| std::cmp::Reverse(f.1));
+
+        let max_results = args.max_results.unwrap_or(DEFAULT_MAX_RESULTS).max(1);
+        let total = files.len();
+        let mut output: Vec<String> = files
+            .iter()
+            .take(max_results)
+            .map(|(f, _)| f.display().to_string())
+            .collect();
+        if total > max_results {
+            output.push(format!(
+                "... [Results truncated: showing {} of {} files]",
+                max_results, total
+            ));
+        }
+        eprintln!(" ✓ Found {} files", total);
+        trim_output(&output.join("\n"), context.output_limit)
+    }
+}
+
+/// Default depth of the list_directory tree
+const DEFAULT_LIST_DEPTH: usize = 2;
+/// Default cap on the entries list_directory returns
+const DEFAULT_LIST_ENTRIES: usize = 200;
+
+/// Arguments for the list_directory tool
+#[derive(Debug, Deserialize)]
+pub struct ListDirectoryArgs {
+    /// Directory to list, defaults to the current directory
+    #[serde(default)]
+    pub path: Option<String>,
+    /// How many levels below the directory to show
+    #[serde(default)]
+    pub depth: Option<usize>,
+    /// Glob patterns to leave out in addition to the ignore files, eg. `*.lock`
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    /// Maximum number of entries to return
+    #[serde(default)]
+    pub max_entries: Option<usize>,
+}
+
+/// Tool showing a directory as an indented tree with file sizes
+///
+/// Respects `.gitignore` and caps depth and entries, so a model can survey a
+/// project without flooding the context with `ls -R`.
+pub struct ListDirectoryTool;
+
+impl Tool for ListDirectoryTool {
+    type Args = ListDirectoryArgs;
+
+    fn name(&self) -> &str {
+        "list_directory"
+    }
+
+    fn description(&self) -> &str {
+        "List a directory as a tree with file sizes, respecting .gitignore. Directories beyond the depth show their entry count"
+    }
+
+    fn parameters(&self) -> Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "path": {
+                    "type": "string",
+                    "description": "Directory to list (default: current directory)"
+                },
+                "depth": {
+                    "type": "integer",
+                    "description": "Levels of subdirectories to expand (default 2, 0 lists only the directory itself)"
+                },
+                "ignore": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "Extra glob patterns to leave out, eg. [\"*.lock\", \"fixtures\"]"
+                },
+                "max_entries": {
+                    "type": "integer",
+                    "description": "Maximum number of entries to return (default 200)"
+                }
+            }
+        })
+    }
+
+    fn validate(&self, args: &Self::Args) -> Result<(), String> {
+        let path = args.path.as_deref().unwrap_or(".");
+        if !Path::new(path).is_dir() {
+            return Err(format!("Error: {} is not a directory", path));
+        }
+        build_ignore_overrides(Path::new(path), &args.ignore).map(|_| ())
+    }
+
+    fn preview(&self, args: &Self::Args) {
+        eprintln!("🛠️  List Directory Requested!");
+        eprintln!(
+            " ● Listing {} to depth {}",
+            args.path.as_deref().unwrap_or("."),
+            args.depth.unwrap_or(DEFAULT_LIST_DEPTH)
+        );
+    }
+
+    fn approval_subject(&self, args: &Self::Args) -> ApprovalSubject {
+        ApprovalSubject::read(args.path.as_deref())
+    }
+
+    fn execute(&self, args: Self::Args, context: &ToolContext) -> String {
+        let root = Path::new(args.path.as_deref().unwrap_or("."));
+        let overrides = match build_ignore_overrides(root, &args.ignore) {
+            Ok(overrides) => overrides,
+            Err(e) => return e,
+        };
+        let depth = args.depth.unwrap_or(DEFAULT_LIST_DEPTH);
+        let max_entries = args.max_entries.unwrap_or(DEFAULT_LIST_ENTRIES).max(1);
+
+        let mut walker = file_walker(root);
+        walker
+            .overrides(overrides.clone())
+            .max_depth(Some(depth + 1))
+            .sort_by_file_name(|a, b| a.cmp(b));
+
+        let mut output = vec![format!("{}/", root.display())];
+        let mut entries = 0;
+        let mut truncated = false;
+        for entry in walker.build().filter_map(Result::ok) {
+            if entry.depth() == 0 {
+                continue;
+            }
+            if entries == max_entries {
+                truncated = true;
+                break;
+            }
+            entries += 1;
+            let indent = "  ".repeat(entry.depth());
+            let name = entry.file_name().to_string_lossy();
+            let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+            if !is_dir {
+                let size = entry.metadata().map(|m| m.len()).unwrap_or_default();
+                output.push(format!("{}{} ({})", indent, name, format_size(size)));
+            } else if entry.depth() > depth {
+                //Too deep to expand, summarise what's inside instead
+                let mut inner = file_walker(entry.path());
+                inner.overrides(overrides.clone()).max_depth(Some(1));
+                let count = inner.build().filter_map(Result::ok).count() - 1;
+                output.push(format!("{}{}/ ({} entries)", indent, name, count));
+            } else {
+                output.push(format!("{}{}/", indent, name));
+            }
+        }
+        if truncated {
+            output.push(format!(
+                "... [Listing truncated at {} entries, list a subdirectory or lower the depth]",
+                max_entries
+            ));
+        }
+
+        eprintln!(" ✓ Listed {} entries", entries);
+        trim_output(&output.join("\n"), context.output_limit)
+    }
+}
+
+/// Builds overrides leaving out paths matching any of the given globs
+fn build_ignore_overrides(root: &Path, patterns: &[String]) -> Result<Override, String> {
+    let mut overrides = OverrideBuilder::new(root);
+    for pattern in patterns {
+        overrides
+            .add(&format!("!{}", pattern))
+            .map_err(|e| format!("Error: invalid ignore pattern '{}': {}", pattern, e))?;
+    }
+    overrides
+        .build()
+        .map_err(|e| format!("Error: invalid ignore patterns: {}", e))
+}
+
+/// Compiles the regular expression for a search_files call
+fn build_search_regex(args: &SearchFilesArgs) -> Result<Regex, String> {
+    RegexBuilder::new(&args.pattern)
+        .case_insensitive(args.case_insensitive)
+        .build()
+        .map_err(|e| {
+            format!(
+                "Error: invalid regular expression '{}': {}",
+                args.pattern, e
+            )
+        })
+}
+
+/// Lists the files under a path, respecting ignore files (see [`file_walker`])
+///
+/// # Arguments
+/// * `path` - Directory or file to walk, defaults to the current directory
+/// * `glob` - Only include files matching this glob. Without a `/` it matches
+///   the file name, otherwise the path relative to `path`
+/// * `file_type` - Only include files of this type (ripgrep's type names)
+///
+/// # Returns
+/// The matching files in walk order, or an error message for the model
+pub fn walk_files(
+    path: Option<&str>,
+    glob: Option<&str>,
+    file_type: Option<&str>,
+) -> Result<Vec<PathBuf>, String> {
+    let root = Path::new(path.unwrap_or("."));
+    if !root.exists() {
+        return Err(format!("Error: path {} does not exist", root.display()));
+    }
+    let matcher = glob
+        .map(|g| {
+            GlobBuilder::new(g)
+                .literal_separator(true)
+                .build()
+                .map(|m| (m.compile_matcher(), g.contains('/')))
+                .map_err(|e| format!("Error: invalid glob '{}': {}", g, e))
+        })
+        .transpose()?;
+
+    let mut walker = file_walker(root);
+    if let Some(file_type) = file_type {
+        let mut types = TypesBuilder::new();
+        types.add_defaults();
+        types.select(file_type);
+        let types = types
+            .build()
+            .map_err(|e| format!("Error: unknown file type '{}': {}", file_type, e))?;
+        walker.types(types);
+    }
+
+    let files = walker
+        .build()
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type().is_some_and(|t| t.is_file()))
+        .map(|e| match path {
+            //Report paths relative to the current directory as given
+            None => e
+                .path()
+                .strip_prefix(root)
+                .unwrap_or(e.path())
+                .to_path_buf(),
+            Some(_) => e.into_path(),
+        })
+        .filter(|p| match &matcher {
+            None => true,
+            Some((m, true)) if path.is_some() => m.is_match(p.strip_prefix(root).unwrap_or(p)),
+            Some((m, true)) => m.is_match(p),
+            Some((m, false)) => p.file_name().is_some_and(|n| m.is_match(n)),
+        })
+        .collect();
+    Ok(files)
+}
+
+/// Creates a directory walker honouring ignore files
+///
+/// `.gitignore`, `.ignore` and git's exclude files apply even outside a git
+/// repository, hidden files are included unless ignored and `.git` is skipped.
+fn file_walker(root: &Path) -> WalkBuilder {
+    let mut walker = WalkBuilder::new(root);
+    walker
+        .hidden(false)
+        .require_git(false)
+        .filter_entry(|e| e.file_name() != ".git");
+    walker
+}
+
+/// Formats a file size for display, eg. `512 B` or `1.5 KB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Reads a file as text, returning None for binary or unreadable files
+///
+/// A file is treated as binary if it contains a NUL byte or isn't UTF-8.
+fn read_text_file(path: &Path) -> Option<String> {
+    let bytes = fs::read(path).ok()?;
+    if bytes.contains(&0) {
+        return None;
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Seconds the process tools wait for output at most
+const MAX_PROCESS_WAIT: f64 = 30.0;
+
+/// Arguments for the start_process tool
+#[derive(Debug, Deserialize)]
+pub struct StartProcessArgs {
+    /// The shell command to run in the background
+    pub command: String,
+    /// The reason the agent needs to run this command
+    #[serde(default)]
+    pub reason: String,
+    /// Working directory relative to the project root
+    #[serde(default)]
+    pub cwd: Option<String>,
+    /// Seconds to wait for initial output before returning
+    #[serde(default)]
+    pub wait: Option<f64>,
+}
+
+/// Tool starting a long-running command, such as a dev server, in the background
+pub struct StartProcessTool;
+
+impl Tool for StartProcessTool {
+    type Args = StartProcessArgs;
+
+    fn name(&self) -> &str {
+        "start_process"
+    }
+
+    fn description(&self) -> &str {
+        "Start a long-running shell command (dev server, watcher, ...) in the background. Returns a process id for read_process_output, send_process_input and stop_process"
+    }
+
+    fn parameters(&self) -> Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "command": {
+                    "type": "string",
+                    "description": "The shell command to run in the background"
+                },
+                "reason": {
+                    "type": "string",
+                    "description": "The reason the agent needs to run this command"
+                },
+                "cwd": {
+                    "type": "string",
+                    "description": "Working directory relative to the project root (default: the project root)"
+                },
+                "wait": {
+                    "type": "number",
+                    "description": "Seconds to wait for initial output before returning (default 1)"
+                }
+            },
+            "required": ["command", "reason"]
+        })
+    }
+
+    fn preview(&self, args: &Self::Args) {
+        eprintln!(
+            "🛠️  Background Process Requested!\n ● Command: {}\n ● Reason: {}",
+            args.command, args.reason
+        );
+        if let Some(cwd) = &args.cwd {
+            eprintln!(" ● Directory: {}", cwd);
+        }
+    }
+
+    fn approval_subject(&self, args: &Self::Args) -> ApprovalSubject {
+        ApprovalSubject::Command(args.command.clone())
+    }
+
+    fn execute(&self, args: Self::Args, context: &ToolContext) -> String {
+        let cwd = match context.shell.working_dir(args.cwd.as_deref()) {
+            Ok(cwd) => cwd,
+            Err(e) => return e,
+        };
+        let command = context.shell.command(&args.command, &cwd);
+        let (id, pid) = match context.processes.start(command, &args.command) {
+            Ok(started) => started,
+            Err(e) => return format!("Failed to start process: {}", e),
+        };
+        eprintln!(" ✓ Started process #{} (pid {})", id, pid);
+        wait_for_output(args.wait.unwrap_or(1.0));
+        match context.processes.read_output(id) {
+            Ok((status, output, dropped)) => format!(
+                "Started process #{}\n{}",
+                id,
+                format_process_output(id, status, &output, dropped, context.output_limit)
+            ),
+            Err(e) => e,
+        }
+    }
+}
+
+/// Arguments for the read_process_output tool
+#[derive(Debug, Deserialize)]
+pub struct ReadProcessOutputArgs {
+    /// Id of the background process
+    pub id: usize,
+    /// Seconds to wait for more output before reading
+    #[serde(default)]
+    pub wait: Option<f64>,
+}
+
+/// Tool reading the output a background process wrote since the last read
+pub struct ReadProcessOutputTool;
+
+impl Tool for ReadProcessOutputTool {
+    type Args = ReadProcessOutputArgs;
+
+    fn name(&self) -> &str {
+        "read_process_output"
+    }
+
+    fn description(&self) -> &str {
+        "Read the output a background process wrote since it was last read, and whether it is still running"
+    }
+
+    fn parameters(&self) -> Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "id": {
+                    "type": "integer",
+                    "description": "Id of the process returned by start_process"
+                },
+                "wait": {
+                    "type": "number",
+                    "description": "Seconds to wait for more output before reading (default 0)"
+                }
+            },
+            "required": ["id"]
+        })
+    }
+
+    fn preview(&self, args: &Self::Args) {
+        eprintln!("🛠️  Process Output Requested!\n ● Process: #{}", args.id);
+    }
+
+    fn approval_subject(&self, _args: &Self::Args) -> ApprovalSubject {
+        ApprovalSubject::ReadOnly
+    }
+
+    fn execute(&self, args: Self::Args, context: &ToolContext) -> String {
+        wait_for_output(args.wait.unwrap_or_default());
+        match context.processes.read_output(args.id) {
+            Ok((status, output, dropped)) => {
+                eprintln!(" ✓ Read {} bytes, process is {}", output.len(), status);
+                format_process_output(args.id, status, &output, dropped, context.output_limit)
+            }
+            Err(e) => e,
+        }
+    }
+}
+
+/// Arguments for the send_process_input tool
+#[derive(Debug, Deserialize)]
+pub struct SendProcessInputArgs {
+    /// Id of the background process
+    pub id: usize,
+    /// Text to write to the process's stdin
+    pub input: String,
+    /// Whether to add a newline after the input
+    #[serde(default = "default_true")]
+    pub newline: bool,
+}
+
+/// Serde default for flags that are on unless turned off
+fn default_true() -> bool {
+    true
+}
+
+/// Tool writing to the stdin of a background process
+pub struct SendProcessInputTool;
+
+impl Tool for SendProcessInputTool {
+    type Args = SendProcessInputArgs;
+
+    fn name(&self) -> &str {
+        "send_process_input"
+    }
+
+    fn description(&self) -> &str {
+        "Write text to the stdin of a background process started with start_process"
+    }
+
+    fn parameters(&self) -> Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "id": {
+                    "type": "integer",
+                    "description": "Id of the process returned by start_process"
+                },
+                "input": {
+                    "type": "string",
+                    "description": "Text to write to the process"
+                },
+                "newline": {
+                    "type": "boolean",
+                    "description": "Add a newline after the input (default true)"
+                }
+            },
+            "required": ["id", "input"]
+        })
+    }
+
+    fn preview(&self, args: &Self::Args) {
+        eprintln!(
+            "🛠️  Process Input Requested!\n ● Process: #{}\n ● Input: {}",
+            args.id, args.input
+        );
+    }
+
+    fn approval_subject(&self, _args: &Self::Args) -> ApprovalSubject {
+        ApprovalSubject::None
+    }
 
-/// Configuration for limiting tool output size
+    fn execute(&self, args: Self::Args, context: &ToolContext) -> String {
+        let mut input = args.input;
+        if args.newline {
+            input.push('\n');
+        }
+        match context.processes.send_input(args.id, &input) {
+            Ok(()) => {
+                eprintln!(" ✓ Sent {} bytes to process #{}", input.len(), args.id);
+                format!("Sent {} bytes to process #{}", input.len(), args.id)
+            }
+            Err(e) => e,
+        }
+    }
+}
+
+/// Arguments for the stop_process tool
+#[derive(Debug, Deserialize)]
+pub struct StopProcessArgs {
+    /// Id of the background process
+    pub id: usize,
+}
+
+/// Tool stopping a background process and everything it started
+pub struct StopProcessTool;
+
+impl Tool for StopProcessTool {
+    type Args = StopProcessArgs;
+
+    fn name(&self) -> &str {
+        "stop_process"
+    }
+
+    fn description(&self) -> &str {
+        "Stop a background process started with start_process, returning its remaining output"
+    }
+
+    fn parameters(&self) -> Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "id": {
+                    "type": "integer",
+                    "description": "Id of the process returned by start_process"
+                }
+            },
+            "required": ["id"]
+        })
+    }
+
+    fn preview(&self, args: &Self::Args) {
+        eprintln!("🛠️  Stop Process Requested!\n ● Process: #{}", args.id);
+    }
+
+    fn approval_subject(&self, _args: &Self::Args) -> ApprovalSubject {
+        ApprovalSubject::None
+    }
+
+    fn execute(&self, args: Self::Args, context: &ToolContext) -> String {
+        match context.processes.stop(args.id) {
+            Ok((status, output)) => {
+                eprintln!(" ✓ Stopped process #{}", args.id);
+                format_process_output(args.id, status, &output, 0, context.output_limit)
+            }
+            Err(e) => e,
+        }
+    }
+}
+
+/// Sleeps while a background process produces output, unless Ctrl-C is pressed
 ///
-/// Prevents overwhelming the context window with large command outputs
-/// by trimming based on size threshold and method.
-#[derive(Debug, Deserialize, Clone)]
-pub struct OutputLimit {
-    /// Maximum output size (in bytes or approximate lines). 0 means no limit.
-    pub max_size: usize,
-    /// Method to use for trimming (head, tail, or bytes)
-    pub method: TrimMethod,
+/// # Arguments
+/// * `seconds` - How long to wait, capped at `MAX_PROCESS_WAIT`
+fn wait_for_output(seconds: f64) {
+    let deadline = Instant::now() + Duration::from_secs_f64(seconds.clamp(0.0, MAX_PROCESS_WAIT));
+    while Instant::now() < deadline && !interrupt::is_interrupted() {
+        thread::sleep(Duration::from_millis(50));
+    }
 }
 
-/// Method for trimming oversized output
+/// Formats a background process's status and new output for the model
 ///
-/// - **Head**: Keep the beginning of the output
-/// - **Tail**: Keep the end of the output
-/// - **Bytes**: Truncate at byte limit
-#[derive(Debug, Deserialize, Clone, Display, EnumString)]
-#[serde(rename_all = "lowercase")]
-#[strum(serialize_all = "lowercase")]
-pub enum TrimMethod {
-    /// Keep the first N lines/bytes
-    Head,
-    /// Keep the last N lines/bytes
-    Tail,
-    /// Truncate at byte limit
-    Bytes,
+/// # Arguments
+/// * `id` - Id of the process
+/// * `status` - Running, or how the process exited
+/// * `output` - Output written since the last read
+/// * `dropped` - Bytes lost because the buffer overflowed before they were read
+/// * `limit` - Output limit applied to the output
+fn format_process_output(
+    id: usize,
+    status: ProcessStatus,
+    output: &str,
+    dropped: u64,
+    limit: &OutputLimit,
+) -> String {
+    let mut result = format!("Process #{}: {}\n", id, status);
+    if dropped > 0 {
+        result.push_str(&format!(
+            "[{} bytes of output were dropped before they could be read]\n",
+            dropped
+        ));
+    }
+    let output = trim_output(output.trim_end(), limit);
+    match output.is_empty() {
+        true => result.push_str("<output></output>"),
+        false => result.push_str(&format!("<output>\n{}\n</output>", output)),
+    }
+    result
 }
 
-/// Default impl for Output Limit, 0 means no limit
-impl Default for OutputLimit {
-    fn default() -> Self {
-        Self {
-            max_size: 0,
-            method: TrimMethod::Head,
+/// Arguments for the write_file tool
+#[derive(Debug, Deserialize)]
+pub struct WriteFileArgs {
+    /// Path to the file to write
+    pub path: String,
+    /// Content to write to the file
+    pub content: String,
+    /// Write mode: overwrite (default), append, insert or replace
+    pub mode: Option<String>,
+    /// Starting line number for insert/replace operations (1-based)
+    pub start_line: Option<usize>,
+    /// End line number for replace operations (1-based, inclusive)
+    pub end_line: Option<usize>,
+}
+
+/// Tool for writing file contents with multiple modes
+pub struct WriteFileTool;
+
+impl Tool for WriteFileTool {
+    type Args = WriteFileArgs;
+
+    fn name(&self) -> &str {
+        "write_file"
+    }
+
+    fn description(&self) -> &str {
+        "Write content to a file"
+    }
+
+    fn parameters(&self) -> Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "path": {
+                    "type": "string",
+                    "description": "Path to the file to write"
+                },
+                "content": {
+                    "type": "string",
+                    "description": "Content to write to the file"
+                },
+                "mode": {
+                    "type": "string",
+                    "enum": ["overwrite", "append", "insert", "replace"],
+                    "description": "Write mode: overwrite (default), append, insert at line, or replace line range"
+                },
+                "start_line": {
+                    "type": "integer",
+                    "description": "Starting line number for insert/replace operations (1-based)"
+                },
+                "end_line": {
+                    "type": "integer",
+                    "description": "End line number for replace operations (1-based, inclusive)"
+                }
+            },
+            "required": ["path", "content"]
+        })
+    }
+
+    fn preview(&self, args: &Self::Args) {
+        let path = &args.path;
+        let mode = args.mode.as_deref();
+        eprintln!("🛠️  Write File Requested!");
+        match (mode.unwrap_or("overwrite"), args.start_line, args.end_line) {
+            ("replace", Some(start), Some(end)) => {
+                eprintln!(" ● Replacing lines {}-{} in: {}", start, end, path)
+            }
+            ("replace", Some(start), None) => {
+                eprintln!(" ● Replacing from line {} in: {}", start, path)
+            }
+            ("insert", Some(line), _) => {
+                eprintln!(" ● Inserting at line {} in: {}", line, path)
+            }
+            ("append", _, _) => eprintln!(" ● Appending to: {}", path),
+            _ => eprintln!(" ● Writing to: {}", path),
         }
+        eprintln!();
+
+        show_write_diff(path, &args.content, mode, args.start_line, args.end_line);
+    }
+
+    fn approval_subject(&self, args: &Self::Args) -> ApprovalSubject {
+        ApprovalSubject::WritePaths(vec![args.path.clone()])
+    }
+
+    fn execute(&self, args: Self::Args, _context: &ToolContext) -> String {
+        write_file_content(
+            &args.path,
+            &args.content,
+            args.mode.as_deref(),
+            args.start_line,
+            args.end_line,
+        )
     }
 }
 
-impl From<OutputLimit> for config::Value {
-    fn from(value: OutputLimit) -> Self {
-        let mut ret: HashMap<String, String> = HashMap::new();
-        ret.insert("max_size".to_string(), value.max_size.to_string());
-        ret.insert("method".to_string(), value.method.to_string());
-        ret.into()
+/// Arguments for the edit_file tool
+#[derive(Debug, Deserialize)]
+pub struct EditFileArgs {
+    /// Path to the file to edit
+    pub path: String,
+    /// Exact text to replace
+    pub old_string: String,
+    /// Text to replace it with
+    pub new_string: String,
+    /// Replace every occurrence instead of requiring a unique match
+    #[serde(default)]
+    pub replace_all: bool,
+}
+
+/// Tool replacing an exact string in a file
+///
+/// Unlike write_file's line based replace mode, the edit is anchored on the
+/// text itself, so it stays correct after earlier edits shift line numbers.
+pub struct EditFileTool;
+
+impl Tool for EditFileTool {
+    type Args = EditFileArgs;
+
+    fn name(&self) -> &str {
+        "edit_file"
+    }
+
+    fn description(&self) -> &str {
+        "Replace an exact string in a file. old_string must match the file exactly (including whitespace and indentation) and be unique unless replace_all is set"
+    }
+
+    fn parameters(&self) -> Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "path": {
+                    "type": "string",
+                    "description": "Path to the file to edit"
+                },
+                "old_string": {
+                    "type": "string",
+                    "description": "Exact text to replace, include enough surrounding lines to make it unique"
+                },
+                "new_string": {
+                    "type": "string",
+                    "description": "Text to replace old_string with"
+                },
+                "replace_all": {
+                    "type": "boolean",
+                    "description": "Replace every occurrence of old_string (default false)"
+                }
+            },
+            "required": ["path", "old_string", "new_string"]
+        })
+    }
+
+    fn validate(&self, args: &Self::Args) -> Result<(), String> {
+        edit_content(args).map(|_| ())
+    }
+
+    fn preview(&self, args: &Self::Args) {
+        eprintln!("🛠️  Edit File Requested!");
+        if args.replace_all {
+            eprintln!(" ● Replacing all occurrences in: {}", args.path);
+        } else {
+            eprintln!(" ● Editing: {}", args.path);
+        }
+        eprintln!();
+
+        if let Ok((content, _)) = edit_content(args) {
+            show_write_diff(&args.path, &content, Some("overwrite"), None, None);
+        }
+    }
+
+    fn approval_subject(&self, args: &Self::Args) -> ApprovalSubject {
+        ApprovalSubject::WritePaths(vec![args.path.clone()])
+    }
+
+    fn execute(&self, args: Self::Args, _context: &ToolContext) -> String {
+        let (content, count) = match edit_content(&args) {
+            Ok(edit) => edit,
+            Err(e) => return e,
+        };
+        match std::fs::write(&args.path, content) {
+            Ok(_) => format!(
+                "Replaced {} occurrence{} in {}",
+                count,
+                if count == 1 { "" } else { "s" },
+                args.path
+            ),
+            Err(e) => format!("Error writing file: {}", e),
+        }
     }
 }
 
-/// Creates the tool definition for execute_shell operations
+/// Computes the contents of a file after an edit_file call
 ///
-/// Returns a JSON structure describing the execute_shell tool's interface,
-/// including parameters for command and reason.
+/// # Arguments
+/// * `args` - The edit to apply
 ///
 /// # Returns
-/// JSON Value describing the tool for Ollama's function calling API
-pub fn create_shell_tool() -> Value {
-    serde_json::json!({
-        "type": "function",
-        "function": {
-            "name": "execute_shell",
-            "description": "Execute a shell command on the system",
-            "parameters": {
-                "type": "object",
-                "properties": {
-                    "command": {
-                        "type": "string",
-                        "description": "The shell command to execute"
-                    },
-                    "reason": {
-                        "type": "string",
-                        "description": "The reason the agent needs to use this command"
-                    }
-                },
-                "required": ["command", "reason"]
+/// The edited contents and the number of replacements, or an error message
+/// for the model if the file can't be read or old_string is empty, missing
+/// or (without replace_all) not unique
+pub fn edit_content(args: &EditFileArgs) -> Result<(String, usize), String> {
+    if args.old_string.is_empty() {
+        return Err(
+            "Error: old_string must not be empty. Use write_file to create a file".to_string(),
+        );
+    }
+    if args.old_string == args.new_string {
+        return Err(
+            "Error: old_string and new_string are identical, nothing to change".to_string(),
+        );
+    }
+    let content = std::fs::read_to_string(&args.path)
+        .map_err(|e| format!("Error reading file {}: {}", args.path, e))?;
+    let count = content.matches(args.old_string.as_str()).count();
+    match count {
+        0 => Err(format!(
+            "Error: old_string was not found in {}. It must match the file exactly, including whitespace and indentation. Use read_file to check the current contents",
+            args.path
+        )),
+        1 => Ok((content.replacen(&args.old_string, &args.new_string, 1), 1)),
+        n if args.replace_all => Ok((content.replace(&args.old_string, &args.new_string), n)),
+        n => Err(format!(
+            "Error: old_string appears {} times in {}. Include more surrounding lines to make it unique, or set replace_all to replace every occurrence",
+            n, args.path
+        )),
+    }
+}
+
+/// Arguments for the apply_patch tool
+#[derive(Debug, Deserialize)]
+pub struct ApplyPatchArgs {
+    /// Unified diff covering one or more files
+    pub patch: String,
+}
+
+/// Tool applying a unified diff across multiple files in one step
+///
+/// The whole patch is validated before it is previewed, shown in a single
+/// approval and applied atomically. See the [`crate::patch`] module.
+pub struct ApplyPatchTool;
+
+impl Tool for ApplyPatchTool {
+    type Args = ApplyPatchArgs;
+
+    fn name(&self) -> &str {
+        "apply_patch"
+    }
+
+    fn description(&self) -> &str {
+        "Apply a unified diff that can add, delete, update and move several files at once. Either the whole patch applies or nothing changes"
+    }
+
+    fn parameters(&self) -> Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "patch": {
+                    "type": "string",
+                    "description": "Unified diff (like `git diff` output) with ---/+++ headers per file and @@ hunks with 3 lines of context. Use --- /dev/null to add a file, +++ /dev/null to delete one and different old/new paths to move one"
+                }
+            },
+            "required": ["patch"]
+        })
+    }
+
+    fn validate(&self, args: &Self::Args) -> Result<(), String> {
+        plan_patch(&args.patch).map(|_| ())
+    }
+
+    fn preview(&self, args: &Self::Args) {
+        eprintln!("🛠️  Apply Patch Requested!");
+        let Ok(plan) = plan_patch(&args.patch) else {
+            return;
+        };
+        for line in &plan.summary {
+            eprintln!(" ● {}", line);
+        }
+        for note in &plan.notes {
+            eprintln!(" ⚠ {}", note);
+        }
+        for change in &plan.changes {
+            match (&change.content, &change.moved_from) {
+                //Moved away, the destination shows the changes
+                (None, _)
+                    if plan
+                        .changes
+                        .iter()
+                        .any(|c| c.moved_from.as_ref() == Some(&change.path)) =>
+                {
+                    continue
+                }
+                (None, _) => eprintln!("\n── {} (deleted)", change.path),
+                (Some(_), Some(from)) => eprintln!("\n── {} → {}", from, change.path),
+                (Some(_), None) => eprintln!("\n── {}", change.path),
             }
+            eprintln!();
+            let source = change.moved_from.as_deref().unwrap_or(&change.path);
+            show_write_diff(
+                source,
+                change.content.as_deref().unwrap_or_default(),
+                Some("overwrite"),
+                None,
+                None,
+            );
         }
-    })
+    }
+
+    fn approval_subject(&self, args: &Self::Args) -> ApprovalSubject {
+        ApprovalSubject::WritePaths(
+            plan_patch(&args.patch)
+                .map(|p| p.paths())
+                .unwrap_or_default(),
+        )
+    }
+
+    fn execute(&self, args: Self::Args, _context: &ToolContext) -> String {
+        let plan = match plan_patch(&args.patch) {
+            Ok(plan) => plan,
+            Err(e) => return e,
+        };
+        if let Err(e) = apply_changes(&plan) {
+            return e;
+        }
+        let mut result = format!("Patch applied successfully:\n{}", plan.summary.join("\n"));
+        if !plan.notes.is_empty() {
+            result += &format!("\nNotes:\n{}", plan.notes.join("\n"));
+        }
+        result
+    }
 }
 
 /// Executes a shell command and returns the output
 ///
-/// Runs the command through the shell and captures stdout/stderr.
-/// Output can be trimmed based on the provided output limit configuration.
+/// Runs the command through the shell in the configured environment (see
+/// [`ShellEnvironment`]). Its stdout and stderr are shown in the terminal as
+/// they arrive and both are returned to the model, each trimmed by the output
+/// limit, along with the exit code and duration. The command runs in its own
+/// process group, which is killed if Ctrl-C is pressed or the timeout expires
+/// while it runs.
 ///
 /// # Arguments
-/// * `command` - Shell command to execute
-/// * `output_limit` - Configuration for limiting output size
+/// * `args` - Command, working directory and timeout from the tool call
+/// * `context` - Output limit and shell environment
 ///
 /// # Returns
-/// Command output (stdout on success, stderr on failure) or error message
-pub fn execute_command(command: &str, output_limit: &OutputLimit) -> String {
-    println!("EXECUTING {}", command);
-    let output = match Command::new("sh").arg("-c").arg(command).output() {
-        Ok(output) => output,
+/// The rendered [`CommandResult`], or an error message if the command couldn't run
+pub fn execute_command(args: &ShellArgs, context: &ToolContext) -> String {
+    let cwd = match context.shell.working_dir(args.cwd.as_deref()) {
+        Ok(cwd) => cwd,
+        Err(e) => {
+            eprintln!("EXECUTION ERROR: {}", e);
+            return e;
+        }
+    };
+    let timeout = context.shell.timeout(args.timeout);
+    eprintln!("EXECUTING {}", args.command);
+    let result = match run_interruptible(&mut context.shell.command(&args.command, &cwd), timeout) {
+        Ok(result) => result,
         Err(e) => {
             let error_msg = format!("Failed to execute command: {}", e);
-            println!("EXECUTION ERROR: {}", error_msg);
+            eprintln!("EXECUTION ERROR: {}", error_msg);
             return error_msg;
         }
     };
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let stderr = String::from_utf8_lossy(&output.stderr);
+    let status = result.status_line();
+    match (result.end, result.exit_code) {
+        (CommandEnd::Exited, Some(0)) => eprintln!(" ✓ {}", status),
+        _ => eprintln!(" ❌ {}", status),
+    }
+    result.render(context.output_limit)
+}
 
-    let result = if !output.status.success() {
-        let error_msg = format!(
-            "Command failed with exit code {}: {}",
-            output.status.code().unwrap_or(-1),
-            stderr
-        );
-        println!("COMMAND FAILED\n{}", error_msg);
-        error_msg
-    } else {
-        let result = trim_output(&stdout, output_limit);
-        println!("OUTPUT:\n{}", result);
-        result
+/// How a command run by [`run_interruptible`] ended
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandEnd {
+    /// The command exited by itself
+    Exited,
+    /// Ctrl-C was pressed and the command was killed
+    Interrupted,
+    /// The timeout expired and the command was killed
+    TimedOut(Duration),
+}
+
+/// Outcome of a shell command, with everything it printed
+#[derive(Debug, Clone)]
+pub struct CommandResult {
+    /// How the command ended
+    pub end: CommandEnd,
+    /// Exit code, None if the command was killed by a signal
+    pub exit_code: Option<i32>,
+    /// How long the command ran
+    pub duration: Duration,
+    /// Everything written to stdout
+    pub stdout: String,
+    /// Everything written to stderr
+    pub stderr: String,
+}
+
+impl CommandResult {
+    /// Describes how the command ended on one line
+    pub fn status_line(&self) -> String {
+        let code = match (self.end, self.exit_code) {
+            (CommandEnd::Interrupted, _) => "killed (interrupted by the user)".to_string(),
+            (CommandEnd::TimedOut(limit), _) => {
+                format!("killed (timed out after {}s)", limit.as_secs())
+            }
+            (CommandEnd::Exited, Some(code)) => code.to_string(),
+            (CommandEnd::Exited, None) => "killed by a signal".to_string(),
+        };
+        format!("Exit code: {} in {:.2}s", code, self.duration.as_secs_f64())
+    }
+
+    /// Formats the result for the model
+    ///
+    /// stdout and stderr are trimmed independently, so a noisy stdout can't
+    /// push compiler errors on stderr out of the result.
+    ///
+    /// # Arguments
+    /// * `limit` - Output limit applied to each stream
+    ///
+    /// # Returns
+    /// The status line followed by `<stdout>` and `<stderr>` blocks
+    pub fn render(&self, limit: &OutputLimit) -> String {
+        let stream = |name: &str, text: &str| {
+            let text = trim_output(text.trim_end(), limit);
+            match text.is_empty() {
+                true => format!("<{}></{}>", name, name),
+                false => format!("<{}>\n{}\n</{}>", name, text, name),
+            }
+        };
+        format!(
+            "{}\n{}\n{}",
+            self.status_line(),
+            stream("stdout", &self.stdout),
+            stream("stderr", &self.stderr)
+        )
+    }
+}
+
+/// How long the output of a finished command is read at most
+const PIPE_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Runs a command to completion unless Ctrl-C is pressed or it times out
+///
+/// The child is started in its own process group with piped stdout/stderr,
+/// which are echoed to the terminal as they arrive. While it runs the
+/// interrupt flag is polled, and if it is raised or the timeout expires the
+/// whole process group is killed so that grandchildren (eg. `cargo` spawning
+/// `rustc`) don't keep running. Output up to that point is still returned.
+/// The group is also killed when the command exits by itself, so a job it
+/// left in the background (`npm run dev &`) can't outlive the call; use
+/// `start_process` for those.
+///
+/// # Arguments
+/// * `command` - The command to run
+/// * `timeout` - How long the command may run, None for no limit
+///
+/// # Returns
+/// How the command ended and what it printed
+///
+/// # Errors
+/// Returns error if the command cannot be started
+fn run_interruptible(
+    command: &mut Command,
+    timeout: Option<Duration>,
+) -> std::io::Result<CommandResult> {
+    let started = Instant::now();
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()?;
+
+    //Drain the pipes on their own threads so a chatty command can't fill them and block
+    let stdout = PipeEcho::spawn(child.stdout.take());
+    let stderr = PipeEcho::spawn(child.stderr.take());
+
+    //Wait for the command to end, then for the readers to hit EOF. A process
+    //that left the group (eg. with setsid) can still hold the pipes open, so
+    //the readers get PIPE_DRAIN_TIMEOUT before their output is taken as is.
+    let mut ended: Option<(ExitStatus, CommandEnd, Instant)> = None;
+    let (status, end) = loop {
+        match ended {
+            Some((status, end, at)) => {
+                let drained = stdout.is_finished() && stderr.is_finished();
+                if drained || at.elapsed() >= PIPE_DRAIN_TIMEOUT {
+                    break (status, end);
+                }
+            }
+            None => {
+                let end = if peek_exit(child.id()).is_some() {
+                    //Jobs the command put in the background would keep the pipes open forever
+                    Some((kill_process_group(&mut child), CommandEnd::Exited))
+                } else if interrupt::is_interrupted() {
+                    Some((kill_process_group(&mut child), CommandEnd::Interrupted))
+                } else {
+                    timeout
+                        .filter(|t| started.elapsed() >= *t)
+                        .map(|limit| (kill_process_group(&mut child), CommandEnd::TimedOut(limit)))
+                };
+                ended = end.map(|(status, end)| (status, end, Instant::now()));
+            }
+        }
+        thread::sleep(Duration::from_millis(50));
     };
 
-    result
+    Ok(CommandResult {
+        end,
+        exit_code: status.code(),
+        duration: started.elapsed(),
+        stdout: stdout.take(),
+        stderr: stderr.take(),
+    })
+}
+
+/// A child's pipe read to the end on its own thread and echoed to stderr
+struct PipeEcho {
+    /// The reading thread
+    thread: thread::JoinHandle<()>,
+    /// Everything read so far
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl PipeEcho {
+    /// Starts reading a pipe
+    ///
+    /// # Arguments
+    /// * `pipe` - The child's stdout or stderr
+    fn spawn(pipe: Option<impl Read + Send + 'static>) -> Self {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let buf = Arc::clone(&output);
+        let thread = thread::spawn(move || {
+            let Some(mut pipe) = pipe else {
+                return;
+            };
+            let mut chunk = [0; 4096];
+            loop {
+                match pipe.read(&mut chunk) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        std::io::stderr().write_all(&chunk[..n]).unwrap_or_default();
+                        buf.lock().unwrap().extend_from_slice(&chunk[..n]);
+                    }
+                }
+            }
+        });
+        Self { thread, output }
+    }
+
+    /// Whether the pipe was read to the end
+    fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Everything read so far, the thread keeps running if the pipe is still open
+    fn take(&self) -> String {
+        let output = std::mem::take(&mut *self.output.lock().unwrap());
+        String::from_utf8_lossy(&output).into_owned()
+    }
+}
+
+/// Kills a child started with its own process group, and all of its descendants
+///
+/// The group may outlive its leader, so this is also used after the child
+/// exited, in which case its status is returned as is. The child is only
+/// reaped after the group is killed, as its process group id could be
+/// reused once it is.
+///
+/// # Arguments
+/// * `child` - The child process (the leader of its process group)
+///
+/// # Returns
+/// The exit status of the killed child
+fn kill_process_group(child: &mut Child) -> ExitStatus {
+    // SAFETY: kill has no memory safety requirements, a negative pid targets the process group
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    child.wait().unwrap_or_default()
 }
 
 /// Trims output to fit within the specified size limit.
@@ -153,7 +2037,7 @@ pub fn execute_command(command: &str, output_limit: &OutputLimit) -> String {
 /// # Returns
 /// The trimmed output with a truncation notice if applicable,
 /// or the original output if within limits
-fn trim_output(output: &str, limit: &OutputLimit) -> String {
+pub fn trim_output(output: &str, limit: &OutputLimit) -> String {
     //Trim is disabled
     if limit.max_size == 0 {
         return output.to_string();
@@ -191,7 +2075,9 @@ fn trim_output(output: &str, limit: &OutputLimit) -> String {
         }
         TrimMethod::Bytes => {
             if output.len() > limit.max_size {
-                let mut result = output[..limit.max_size].to_string();
+                //Cut before a character the limit falls into
+                let end = output.floor_char_boundary(limit.max_size);
+                let mut result = output[..end].to_string();
                 result.push_str(&format!(
                     "\n... [Output truncated at {} bytes]",
                     limit.max_size
@@ -204,85 +2090,6 @@ fn trim_output(output: &str, limit: &OutputLimit) -> String {
     }
 }
 
-/// Creates the tool definition for read_file operations
-///
-/// Returns a JSON structure describing the read_file tool's interface,
-/// including parameters for path and optional line range.
-///
-/// # Returns
-/// JSON Value describing the tool for Ollama's function calling API
-pub fn create_read_file_tool() -> Value {
-    serde_json::json!({
-        "type": "function",
-        "function": {
-            "name": "read_file",
-            "description": "Read lines from a file",
-            "parameters": {
-                "type": "object",
-                "properties": {
-                    "path": {
-                        "type": "string",
-                        "description": "Path to the file to read"
-                    },
-                    "start_line": {
-                        "type": "integer",
-                        "description": "Starting line number (1-based, optional)"
-                    },
-                    "end_line": {
-                        "type": "integer",
-                        "description": "Ending line number (1-based, inclusive, optional)"
-                    }
-                },
-                "required": ["path"]
-            }
-        }
-    })
-}
-
-/// Creates the tool definition for write_file operations
-///
-/// Returns a JSON structure describing the write_file tool's interface,
-/// including parameters for path, content, mode, and line ranges.
-///
-/// # Returns
-/// JSON Value describing the tool for Ollama's function calling API
-pub fn create_write_file_tool() -> Value {
-    serde_json::json!({
-        "type": "function",
-        "function": {
-            "name": "write_file",
-            "description": "Write content to a file",
-            "parameters": {
-                "type": "object",
-                "properties": {
-                    "path": {
-                        "type": "string",
-                        "description": "Path to the file to write"
-                    },
-                    "content": {
-                        "type": "string",
-                        "description": "Content to write to the file"
-                    },
-                    "mode": {
-                        "type": "string",
-                        "enum": ["overwrite", "append", "insert", "replace"],
-                        "description": "Write mode: overwrite (default), append, insert at line, or replace line range"
-                    },
-                    "start_line": {
-                        "type": "integer",
-                        "description": "Starting line number for insert/replace operations (1-based)"
-                    },
-                    "end_line": {
-                        "type": "integer",
-                        "description": "End line number for replace operations (1-based, inclusive)"
-                    }
-                },
-                "required": ["path", "content"]
-            }
-        }
-    })
-}
-
 /// Reads lines from a file with optional line range
 ///
 /// Reads file contents and returns them with line numbers prefixed.
@@ -357,18 +2164,38 @@ pub fn show_write_diff(
 
     match mode.unwrap_or("overwrite") {
         "overwrite" => {
-            let new_lines: Vec<&str> = content.lines().collect();
-            for (i, line) in existing_lines.iter().enumerate() {
-                println!("\x1b[41m- {:3}     : {}\x1b[0m", i + 1, line);
-            }
-            for (i, line) in new_lines.iter().enumerate() {
-                println!("\x1b[42m+ {:3}     : {}\x1b[0m", i + 1, line);
+            //Only show the changed hunks, with 3 lines of context around each
+            let diff = TextDiff::from_lines(existing.as_str(), content);
+            for (i, group) in diff.grouped_ops(3).iter().enumerate() {
+                if i > 0 {
+                    eprintln!(" ⋮ ");
+                }
+                for change in group.iter().flat_map(|op| diff.iter_changes(op)) {
+                    let line = change.value().trim_end_matches(['\n', '\r']);
+                    match change.tag() {
+                        ChangeTag::Delete => eprintln!(
+                            "\x1b[41m- {:3}     : {}\x1b[0m",
+                            change.old_index().unwrap_or_default() + 1,
+                            line
+                        ),
+                        ChangeTag::Insert => eprintln!(
+                            "\x1b[42m+ {:3}     : {}\x1b[0m",
+                            change.new_index().unwrap_or_default() + 1,
+                            line
+                        ),
+                        ChangeTag::Equal => eprintln!(
+                            "  {:3}     : {}",
+                            change.new_index().unwrap_or_default() + 1,
+                            line
+                        ),
+                    }
+                }
             }
         }
         "append" => {
             let start_line_num = existing_lines.len() + 1;
             for line in content.lines() {
-                println!("\x1b[42m+ {:3}     : {}\x1b[0m", start_line_num, line);
+                eprintln!("\x1b[42m+ {:3}     : {}\x1b[0m", start_line_num, line);
             }
         }
         "insert" => {
@@ -379,13 +2206,13 @@ pub fn show_write_diff(
             let context_start = insert_idx.saturating_sub(3);
             for i in context_start..insert_idx {
                 if i < existing_lines.len() {
-                    println!("  {:3}     : {}", i + 1, existing_lines[i]);
+                    eprintln!("  {:3}     : {}", i + 1, existing_lines[i]);
                 }
             }
 
             // Show inserted lines
             for line in content.lines() {
-                println!("\x1b[42m+ {:3}     : {}\x1b[0m", insert_at, line);
+                eprintln!("\x1b[42m+ {:3}     : {}\x1b[0m", insert_at, line);
             }
 
             // Show context after (up to 3 lines, starting from where we're inserting)
@@ -396,7 +2223,7 @@ pub fn show_write_diff(
                 .take(context_end)
                 .skip(insert_idx)
             {
-                println!("  {:3}     : {}", i + 1, existing_lines[i]);
+                eprintln!("  {:3}     : {}", i + 1, existing_lines[i]);
             }
         }
         "replace" => {
@@ -409,20 +2236,20 @@ pub fn show_write_diff(
             let context_start = start_idx.saturating_sub(3);
             for i in context_start..start_idx {
                 if i < existing_lines.len() {
-                    println!("  {:3}     : {}", i + 1, existing_lines[i]);
+                    eprintln!("  {:3}     : {}", i + 1, existing_lines[i]);
                 }
             }
 
             // Show removed lines
             for i in start_idx..end_idx {
                 if i < existing_lines.len() {
-                    println!("\x1b[41m- {:3}     : {}\x1b[0m", i + 1, existing_lines[i]);
+                    eprintln!("\x1b[41m- {:3}     : {}\x1b[0m", i + 1, existing_lines[i]);
                 }
             }
 
             // Show added lines
             for (i, line) in content.lines().enumerate() {
-                println!("\x1b[42m+ {:3}     : {}\x1b[0m", start + i, line);
+                eprintln!("\x1b[42m+ {:3}     : {}\x1b[0m", start + i, line);
             }
 
             // Show context after
@@ -433,14 +2260,14 @@ pub fn show_write_diff(
                 .take(context_end)
                 .skip(end_idx)
             {
-                println!("  {:3}     : {}", i + 1, line);
+                eprintln!("  {:3}     : {}", i + 1, line);
             }
         }
         _ => {}
     }
 
-    println!();
-    println!(" ⋮ ");
+    eprintln!();
+    eprintln!(" ⋮ ");
 }
 
 /// Writes content to a file with various modes
@@ -525,3 +2352,108 @@ pub fn write_file_content(
         _ => "Invalid mode".to_string(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trims_bytes_at_char_boundary() {
+        let limit = OutputLimit {
+            max_size: 4,
+            method: TrimMethod::Bytes,
+        };
+        //The limit falls into the first "ä" (bytes 3 and 4)
+        let trimmed = trim_output("abcää", &limit);
+        assert!(trimmed.starts_with("abc\n"), "{:?}", trimmed);
+        assert_eq!(trim_output("äö", &limit), "äö");
+    }
+
+    #[test]
+    fn background_jobs_dont_keep_the_command_running() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "sleep 1000 & echo started"]);
+        let started = Instant::now();
+        let result = run_interruptible(&mut command, Some(Duration::from_secs(30))).unwrap();
+        assert_eq!(result.end, CommandEnd::Exited);
+        assert_eq!(result.exit_code, Some(0));
+        assert_eq!(result.stdout, "started\n");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn pipes_held_outside_the_group_dont_block() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "setsid sleep 30 & echo started"]);
+        let started = Instant::now();
+        let result = run_interruptible(&mut command, Some(Duration::from_secs(30))).unwrap();
+        assert_eq!(result.end, CommandEnd::Exited);
+        assert_eq!(result.stdout, "started\n");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    /// Runs edit_content on a file holding `content`
+    fn edit(
+        content: &str,
+        old_string: &str,
+        new_string: &str,
+        replace_all: bool,
+    ) -> Result<(String, usize), String> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.rs");
+        std::fs::write(&path, content).unwrap();
+        edit_content(&EditFileArgs {
+            path: path.display().to_string(),
+            old_string: old_string.to_string(),
+            new_string: new_string.to_string(),
+            replace_all,
+        })
+    }
+
+    #[test]
+    fn edits_unique_matches() {
+        let content = "fn a() {\n    1\n}\nfn b() {\n    2\n}\n";
+        assert_eq!(
+            edit(content, "    2\n", "    3\n", false),
+            Ok(("fn a() {\n    1\n}\nfn b() {\n    3\n}\n".to_string(), 1))
+        );
+        //A unique match is replaced with or without replace_all
+        assert_eq!(
+            edit(content, "fn a", "fn c", true),
+            Ok(("fn c() {\n    1\n}\nfn b() {\n    2\n}\n".to_string(), 1))
+        );
+    }
+
+    #[test]
+    fn refuses_missing_matches() {
+        let error = edit("let x = 1;\n", "let  x = 1;", "let x = 2;", false).unwrap_err();
+        assert!(error.contains("was not found"), "{}", error);
+        let error = edit("let x = 1;\n", "", "let x = 2;", false).unwrap_err();
+        assert!(error.contains("must not be empty"), "{}", error);
+        let error = edit("let x = 1;\n", "x", "x", false).unwrap_err();
+        assert!(error.contains("identical"), "{}", error);
+        let error = edit_content(&EditFileArgs {
+            path: "/nonexistent/file.rs".into(),
+            old_string: "a".into(),
+            new_string: "b".into(),
+            replace_all: false,
+        })
+        .unwrap_err();
+        assert!(error.starts_with("Error reading file"), "{}", error);
+    }
+
+    #[test]
+    fn refuses_ambiguous_matches_unless_replacing_all() {
+        let content = "x += 1;\ny += 1;\nx += 1;\n";
+        let error = edit(content, "x += 1;", "x += 2;", false).unwrap_err();
+        assert!(error.contains("appears 2 times"), "{}", error);
+        assert_eq!(
+            edit(content, "x += 1;", "x += 2;", true),
+            Ok(("x += 2;\ny += 1;\nx += 2;\n".to_string(), 2))
+        );
+        assert_eq!(
+            edit(content, "+= 1", "-= 1", true),
+            Ok(("x -= 1;\ny -= 1;\nx -= 1;\n".to_string(), 3))
+        );
+    }
+}